@@ -0,0 +1,239 @@
+//! This module contains a line based admin server for inspecting and managing the running routes.
+//!
+//! Every command is a single line, answered by zero or more `key=value` lines followed by `OK`, or by a single
+//! `ERR <reason>` line. Send `help` for the list of commands.
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
+use crate::error::{self, Error};
+use crate::routes::{RouteHandle, RouteRegistry};
+use crate::supervisor::ReloadRequest;
+
+/// Wait before accepting again after an error not caused by a single connection, such as running out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const HELP: &str = "\
+routes                       List the routes.
+clients [ROUTE]              List the connected output clients, of every route or of one route.
+kick ROUTE ID                Disconnect an output client.
+write ROUTE ID on|off        Allow or deny an output client writing back to the input.
+pause ROUTE                  Stop retransmitting the input (it is still read and discarded).
+resume ROUTE                 Resume retransmitting the input.
+reload                       Reload the configuration file, restarting only the routes that changed.
+quit                         Close the admin connection.
+";
+
+/// AdminServer
+///
+/// Listens for admin connections on the given address, usually a loopback one as the commands are not authenticated.
+///
+/// ```rust,no_run
+/// # use port_redirector::admin::AdminServer;
+/// # use port_redirector::routes::RouteRegistry;
+/// # async fn example() -> port_redirector::error::Result<()> {
+/// let routes = RouteRegistry::new();
+///
+/// let mut admin_server = AdminServer::new("127.0.0.1:9000", routes.clone()).await?;
+/// tokio::spawn( async move { admin_server.run_loop().await; });
+/// # Ok(())
+/// # }
+/// ```
+pub struct AdminServer {
+    server: TcpListener,
+    routes: Arc<RouteRegistry>,
+    reload: Option<mpsc::Sender<ReloadRequest>>,
+}
+
+impl AdminServer {
+    pub async fn new(endpoint: &str, routes: Arc<RouteRegistry>) -> error::Result<AdminServer> {
+        let server = TcpListener::bind(endpoint).await.map_err(|e| Error::connection(endpoint, e))?;
+        info!(%endpoint, "Starting admin server");
+        Ok(AdminServer { server, routes, reload: None })
+    }
+
+    /// Enable the `reload` command, which sends its requests on this channel and answers with the outcome.
+    pub fn set_reload(&mut self, reload: mpsc::Sender<ReloadRequest>) {
+        self.reload = Some(reload);
+    }
+
+    /// The main run loop, every admin connection is handled on its own task.
+    ///
+    /// Errors affecting a single incoming connection are logged and skipped. After any other error accepting
+    /// connections, such as running out of file descriptors, the loop waits `ACCEPT_RETRY_DELAY` before trying again.
+    pub async fn run_loop(&mut self) {
+        loop {
+            let (socket, socket_address) = match self.server.accept().await {
+                Ok(val) => val,
+                Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted) => {
+                    warn!(error = %e, "Error accepting admin connection");
+                    continue;
+                },
+                Err(e) => {
+                    warn!(error = %e, "Error accepting admin connections, retrying");
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            info!(peer = %socket_address, "Accepted admin connection");
+            let routes = self.routes.clone();
+            let reload = self.reload.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(socket, &routes, reload.as_ref()).await {
+                    debug!(peer = %socket_address, error = %e, "Admin connection failed");
+                }
+                info!(peer = %socket_address, "Admin connection closed");
+            });
+        }
+    }
+}
+
+async fn handle_connection(socket: TcpStream, routes: &RouteRegistry, reload: Option<&mpsc::Sender<ReloadRequest>>) -> io::Result<()> {
+    let (rd, mut tx) = socket.into_split();
+    let mut lines = BufReader::new(rd).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "quit" {
+            break;
+        }
+        let response = match line {
+            "reload" => request_reload(reload).await,
+            _ => execute(line, routes),
+        };
+        tx.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Ask for a reload and wait for its outcome, which can take a while if routes have to drain their clients.
+async fn request_reload(reload: Option<&mpsc::Sender<ReloadRequest>>) -> String {
+    let Some(reload) = reload else {
+        return "ERR reload needs a configuration file\n".to_string();
+    };
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if reload.send(reply_tx).await.is_err() {
+        return "ERR reload unavailable\n".to_string();
+    }
+    match reply_rx.await {
+        Ok(Ok(summary)) => {
+            info!("Admin reloaded the configuration");
+            format!("{}OK\n", summary)
+        },
+        Ok(Err(e)) => format!("ERR {}\n", e),
+        Err(_) => "ERR reload unavailable\n".to_string(),
+    }
+}
+
+/// Run a single admin command and return the full response, including the trailing `OK` or `ERR` line.
+pub fn execute(command: &str, routes: &RouteRegistry) -> String {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    match run_command(&args, routes) {
+        Ok(mut out) => {
+            out.push_str("OK\n");
+            out
+        },
+        Err(reason) => format!("ERR {}\n", reason),
+    }
+}
+
+fn run_command(args: &[&str], routes: &RouteRegistry) -> Result<String, String> {
+    let mut out = String::new();
+    match args {
+        ["help"] => out.push_str(HELP),
+        ["routes"] => {
+            for route in routes.list() {
+                write_route(&mut out, &route);
+            }
+        },
+        ["clients"] => {
+            for route in routes.list() {
+                write_clients(&mut out, &route);
+            }
+        },
+        ["clients", name] => write_clients(&mut out, &find_route(routes, name)?),
+        ["kick", name, id] => {
+            let route = find_route(routes, name)?;
+            if !route.server_control.kick(parse_id(id)?) {
+                return Err(format!("no client {} on route {}", id, name));
+            }
+            info!(route = %name, client = id, "Admin kicked output client");
+        },
+        ["write", name, id, mode] => {
+            let route = find_route(routes, name)?;
+            let allowed = match *mode {
+                "on" => true,
+                "off" => false,
+                _ => return Err(format!("expected on or off, got {}", mode)),
+            };
+            if !route.server_control.set_write_allowed(parse_id(id)?, allowed) {
+                return Err(format!("no client {} on route {}", id, name));
+            }
+            info!(route = %name, client = id, allowed, "Admin changed output client write permission");
+        },
+        ["pause", name] => {
+            find_route(routes, name)?.input_control.pause();
+            info!(route = %name, "Admin paused input");
+        },
+        ["resume", name] => {
+            find_route(routes, name)?.input_control.resume();
+            info!(route = %name, "Admin resumed input");
+        },
+        _ => return Err(format!("unknown command '{}', try help", args.join(" "))),
+    }
+    Ok(out)
+}
+
+fn find_route(routes: &RouteRegistry, name: &str) -> Result<RouteHandle, String> {
+    routes.get(name).ok_or_else(|| format!("no route named {}", name))
+}
+
+fn parse_id(id: &str) -> Result<u64, String> {
+    id.parse::<u64>().map_err(|_| format!("invalid client id {}", id))
+}
+
+fn write_route(out: &mut String, route: &RouteHandle) {
+    let input = route.input_stats.snapshot();
+    let output = route.server_stats.snapshot();
+    writeln!(
+        out,
+        "route={} state={} bytes_in={} messages_in={} dropped={} clients={} bytes_out={}",
+        route.name,
+        if route.input_control.is_paused() { "paused" } else { "running" },
+        input.bytes_in,
+        input.messages_in,
+        input.dropped_messages,
+        output.connected_clients,
+        output.bytes_out
+    ).unwrap();
+}
+
+fn write_clients(out: &mut String, route: &RouteHandle) {
+    for client in route.server_stats.snapshot().clients {
+        let write = match route.server_control.write_allowed(client.id) {
+            Some(false) => "off",
+            _ => "on",
+        };
+        writeln!(
+            out,
+            "route={} id={} peer={} bytes_out={} messages_out={} bytes_in={} pending={} pending_bytes={} lagged={} dropped={} write={} connected_secs={}",
+            route.name,
+            client.id,
+            client.peer,
+            client.bytes_out,
+            client.messages_out,
+            client.bytes_in,
+            client.pending_writes,
+            client.pending_bytes,
+            client.lagged_messages,
+            client.dropped_messages,
+            write,
+            client.uptime.as_secs()
+        ).unwrap();
+    }
+}
@@ -0,0 +1,646 @@
+//! This module describes the routes to run, built from the command line or read from a configuration file.
+//!
+//! A configuration file holds one route per line as `key=value` pairs, the keys being the names of the route
+//! arguments of the command line tool (`name`, `type`, `endpoint`, `port`, `baudrate`, `output_port`, ...). Values
+//! containing spaces can be double quoted, and lines starting with `#` are comments.
+//!
+//! ```text
+//! # GPS on a serial port, shared on port 8001
+//! name=gps type=serial endpoint=/dev/ttyUSB0 baudrate=115200 output_port=8001 lag_policy=marker
+//! name=lidar type=udp port=5001 output_port=8002 gap_marker="[{skipped} skipped]\n"
+//! # Local process output, shared with the members of the sensors group
+//! name=imu type=unix endpoint=/run/imu.sock output_path=/run/imu-shared.sock socket_mode=660 socket_group=sensors
+//! # RTK corrections from a caster, uploading the position of the base
+//! name=rtcm type=ntrip endpoint=rover:secret@caster.example.com:2101/BASE1 gga="$GPGGA,..." output_port=8003
+//! ```
+use std::collections::HashMap;
+use std::str::FromStr;
+use tokio::time::Duration;
+use crate::error::{Error, Result};
+use crate::input_stream::{Framing, Input, InputSocket, NoSubscriberPolicy};
+use crate::mqtt::MqttSettings;
+use crate::ntrip::{NtripCaster, NtripSettings, NtripVersion};
+use crate::responses::ResponseRouting;
+use crate::rtcm::RtcmFilter;
+use crate::retransmit_server::{LagPolicy, SlowClientAction, SlowClientPolicy};
+#[cfg(unix)]
+use crate::unix_socket::SocketPermissions;
+
+/// The settings describing a route, in the order they are documented.
+pub const ROUTE_KEYS: [&str; 42] = [
+    "name",
+    "type",
+    "endpoint",
+    "port",
+    "baudrate",
+    "ntrip_version",
+    "gga",
+    "gga_interval",
+    "framing",
+    "rtcm_messages",
+    "output_port",
+    "output_path",
+    "output_pty",
+    "output_mqtt",
+    "mqtt_topic",
+    "mqtt_qos",
+    "mqtt_retain",
+    "mqtt_client_id",
+    "mqtt_keep_alive",
+    "mqtt_reconnect",
+    "mqtt_command_topic",
+    "socket_mode",
+    "socket_owner",
+    "socket_group",
+    "output_protocol",
+    "modbus_timeout",
+    "ntrip_mountpoint",
+    "ntrip_credentials",
+    "response_routing",
+    "response_terminator",
+    "response_timeout",
+    "channel_capacity",
+    "lag_policy",
+    "gap_marker",
+    "slow_client_policy",
+    "max_pending_messages",
+    "max_pending_bytes",
+    "write_timeout",
+    "max_write_timeouts",
+    "drain_timeout",
+    "no_subscriber_policy",
+    "history_size",
+];
+
+const DEFAULTS: [(&str, &str); 23] = [
+    ("baudrate", "9600"),
+    ("ntrip_version", "2"),
+    ("gga_interval", "10"),
+    ("framing", "raw"),
+    ("mqtt_qos", "0"),
+    ("mqtt_retain", "false"),
+    ("mqtt_keep_alive", "30"),
+    ("mqtt_reconnect", "1000"),
+    ("output_protocol", "raw"),
+    ("modbus_timeout", "1000"),
+    ("response_routing", "broadcast"),
+    ("response_terminator", "\\n"),
+    ("response_timeout", "1000"),
+    ("channel_capacity", "4096"),
+    ("lag_policy", "skip"),
+    ("slow_client_policy", "disconnect"),
+    ("max_pending_messages", "100"),
+    ("max_pending_bytes", "1048576"),
+    ("write_timeout", "5000"),
+    ("max_write_timeouts", "10"),
+    ("drain_timeout", "2000"),
+    ("no_subscriber_policy", "discard"),
+    ("history_size", "256"),
+];
+
+const DEFAULT_GAP_MARKER: &str = "[{skipped} messages skipped]\n";
+
+/// The default value of a route setting, if it has one.
+pub fn default_value(key: &str) -> Option<&'static str> {
+    DEFAULTS.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+/// The input of a route, before it is opened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputConfig {
+    Tcp { ip: String, port: u16 },
+    TcpServer { port: u16 },
+    Udp { port: u16 },
+    Serial { port_name: String, baudrate: u32 },
+    #[cfg(unix)]
+    Unix { path: String },
+    #[cfg(unix)]
+    UnixServer { path: String, permissions: SocketPermissions },
+    #[cfg(unix)]
+    Pty { link: String, permissions: SocketPermissions },
+    Ntrip(NtripSettings),
+}
+
+impl InputConfig {
+    /// The unconnected input socket, to be opened with `InputSocket::connect`.
+    pub fn socket(&self) -> InputSocket {
+        match self {
+            InputConfig::Tcp { ip, port } => InputSocket::TcpSocket { ip: ip.clone(), port: Some(*port), rd: None, tx: None, stats: Default::default() },
+            InputConfig::TcpServer { port } => InputSocket::TcpServer { port: *port, server: None, stream: None, stats: Default::default() },
+            InputConfig::Udp { port } => InputSocket::UdpSocket { port: *port, rd: None, stats: Default::default() },
+            InputConfig::Serial { port_name, baudrate } => InputSocket::Serial { port_name: port_name.clone(), baudrate: Some(*baudrate), rd: None, tx: None, stats: Default::default() },
+            #[cfg(unix)]
+            InputConfig::Unix { path } => InputSocket::UnixSocket { path: path.clone(), rd: None, tx: None, stats: Default::default() },
+            #[cfg(unix)]
+            InputConfig::UnixServer { path, permissions } => InputSocket::UnixServer { path: path.clone(), permissions: permissions.clone(), server: None, stream: None, stats: Default::default() },
+            #[cfg(unix)]
+            InputConfig::Pty { link, permissions } => InputSocket::Pty { link: link.clone(), permissions: permissions.clone(), device: None, stats: Default::default() },
+            InputConfig::Ntrip(settings) => InputSocket::Ntrip { settings: settings.clone(), stream: None, stats: Default::default() },
+        }
+    }
+
+    /// Whether the input has line settings that RFC 2217 clients can change: a serial port or a PTY.
+    pub fn has_line_settings(&self) -> bool {
+        match self {
+            InputConfig::Serial { .. } => true,
+            #[cfg(unix)]
+            InputConfig::Pty { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Where the output clients of a route connect.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutputConfig {
+    /// TCP server on this port, set by `output_port`.
+    Tcp { port: u16 },
+    /// Unix stream socket at this path, set by `output_path`.
+    #[cfg(unix)]
+    Unix { path: String, permissions: SocketPermissions },
+    /// PTY published at this symlink path, set by `output_pty`.
+    #[cfg(unix)]
+    Pty { link: String, permissions: SocketPermissions },
+    /// MQTT broker the input is published to, set by `output_mqtt` and the `mqtt_` settings.
+    Mqtt(MqttSettings),
+}
+
+/// What the output clients of a route speak.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputProtocol {
+    /// The input data as is, in both directions.
+    #[default]
+    Raw,
+    /// RFC 2217 Telnet, letting the clients change the line settings of a serial or PTY input.
+    Rfc2217,
+    /// Modbus TCP, converted to Modbus RTU for the devices on the input bus.
+    Modbus,
+    /// NTRIP, the input being served as a mountpoint to the rovers.
+    Ntrip,
+    /// The JSON protocol of gpsd, reporting the NMEA sentences of the input.
+    Gpsd,
+}
+
+impl OutputConfig {
+    /// Short description used in the default route name: the port, or `unix:` and the path.
+    pub fn describe(&self) -> String {
+        match self {
+            OutputConfig::Tcp { port } => port.to_string(),
+            #[cfg(unix)]
+            OutputConfig::Unix { path, .. } => format!("unix:{}", path),
+            #[cfg(unix)]
+            OutputConfig::Pty { link, .. } => format!("pty:{}", link),
+            OutputConfig::Mqtt(settings) => format!("mqtt:{}", settings.describe()),
+        }
+    }
+}
+
+/// Everything needed to start a route.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteConfig {
+    pub name: String,
+    pub input: InputConfig,
+    pub framing: Framing,
+    /// The RTCM 3 message types sent to the output, if not all of them.
+    pub rtcm_filter: Option<RtcmFilter>,
+    pub output: OutputConfig,
+    pub output_protocol: OutputProtocol,
+    /// How long a Modbus device is given to answer a request, with the `Modbus` output protocol.
+    pub modbus_timeout: Duration,
+    /// The mountpoint served with the `Ntrip` output protocol.
+    pub ntrip_caster: Option<NtripCaster>,
+    /// How the responses to the commands of the clients are routed to them, if they are not broadcast.
+    pub response_routing: Option<ResponseRouting>,
+    pub channel_capacity: usize,
+    pub lag_policy: LagPolicy,
+    pub slow_client_policy: SlowClientPolicy,
+    pub no_subscriber_policy: NoSubscriberPolicy,
+    pub drain_timeout: Duration,
+}
+
+impl RouteConfig {
+    /// Build a route from its `key=value` settings, see `ROUTE_KEYS`. Missing settings take their default value, and
+    /// a missing name is derived from the input and output, e.g. `udp:5001->8001`.
+    ///
+    /// The socket permission settings apply to the Unix socket files and PTY devices created by the route, for a
+    /// `unixs` or `pty` input, an `output_path` or an `output_pty`.
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<RouteConfig> {
+        if let Some(key) = settings.keys().find(|key| !ROUTE_KEYS.contains(&key.as_str())) {
+            return Err(Error::Config(format!("Unknown route setting: {}", key)));
+        }
+        let get = |key: &str| -> Result<&str> {
+            settings.get(key).map(String::as_str).or_else(|| default_value(key))
+                .ok_or_else(|| Error::Config(format!("Missing route setting: {}", key)))
+        };
+
+        let input = match get("type")?.to_ascii_lowercase().as_str() {
+            "tcp" => InputConfig::Tcp { ip: get("endpoint")?.to_string(), port: parse("port", get("port")?)? },
+            "tcps" => InputConfig::TcpServer { port: parse("port", get("port")?)? },
+            "udp" => InputConfig::Udp { port: parse("port", get("port")?)? },
+            "serial" => InputConfig::Serial { port_name: get("endpoint")?.to_string(), baudrate: parse("baudrate", get("baudrate")?)? },
+            #[cfg(unix)]
+            "unix" => InputConfig::Unix { path: get("endpoint")?.to_string() },
+            #[cfg(unix)]
+            "unixs" => InputConfig::UnixServer { path: get("endpoint")?.to_string(), permissions: socket_permissions(settings)? },
+            #[cfg(unix)]
+            "pty" => InputConfig::Pty { link: get("endpoint")?.to_string(), permissions: socket_permissions(settings)? },
+            "ntrip" => InputConfig::Ntrip(NtripSettings {
+                version: match get("ntrip_version")? {
+                    "1" => NtripVersion::V1,
+                    "2" => NtripVersion::V2,
+                    other => return Err(Error::Config(format!("Invalid NTRIP version: {}", other))),
+                },
+                gga: settings.get("gga").cloned(),
+                gga_interval: Duration::from_secs(parse("gga_interval", get("gga_interval")?)?),
+                ..NtripSettings::from_endpoint(get("endpoint")?)?
+            }),
+            other => return Err(Error::Config(format!("Invalid parameter socket type name: {}", other))),
+        };
+        if let InputConfig::Ntrip(ntrip) = &input {
+            if ntrip.gga_interval.is_zero() {
+                return Err(Error::Config("gga_interval must be at least 1 second".to_string()));
+            }
+        }
+        let framing = match get("framing")?.to_ascii_lowercase().as_str() {
+            "raw" => Framing::Raw,
+            "lines" => Framing::Lines,
+            "rtcm3" => Framing::Rtcm3,
+            other => return Err(Error::Config(format!("Invalid framing: {}", other))),
+        };
+        let rtcm_filter = match settings.get("rtcm_messages") {
+            Some(_) if framing != Framing::Rtcm3 => return Err(Error::Config("rtcm_messages requires the rtcm3 framing".to_string())),
+            Some(messages) => Some(rtcm_filter(messages)?),
+            None => None,
+        };
+        let outputs: Vec<&str> = ["output_port", "output_path", "output_pty", "output_mqtt"].iter().copied().filter(|key| settings.contains_key::<str>(key)).collect();
+        if outputs.len() > 1 {
+            return Err(Error::Config(format!("Only one of {} can be set", outputs.join(", "))));
+        }
+        let mut output = match outputs.first().copied() {
+            #[cfg(unix)]
+            Some("output_path") => OutputConfig::Unix { path: get("output_path")?.to_string(), permissions: socket_permissions(settings)? },
+            #[cfg(unix)]
+            Some("output_pty") => OutputConfig::Pty { link: get("output_pty")?.to_string(), permissions: socket_permissions(settings)? },
+            #[cfg(not(unix))]
+            Some("output_path" | "output_pty") => return Err(Error::Config("Unix socket and PTY outputs are not supported on this platform".to_string())),
+            Some("output_mqtt") => OutputConfig::Mqtt(MqttSettings {
+                qos: parse("mqtt_qos", get("mqtt_qos")?)?,
+                retain: parse("mqtt_retain", get("mqtt_retain")?)?,
+                keep_alive: Duration::from_secs(parse("mqtt_keep_alive", get("mqtt_keep_alive")?)?),
+                reconnect_delay: Duration::from_millis(parse("mqtt_reconnect", get("mqtt_reconnect")?)?),
+                command_topic: settings.get("mqtt_command_topic").cloned(),
+                ..MqttSettings::from_broker(get("output_mqtt")?, get("mqtt_topic")?)?
+            }),
+            _ => OutputConfig::Tcp { port: parse("output_port", get("output_port")?)? },
+        };
+        if let OutputConfig::Mqtt(mqtt) = &output {
+            if mqtt.keep_alive.is_zero() {
+                return Err(Error::Config("mqtt_keep_alive must be at least 1 second".to_string()));
+            }
+            if settings.get("output_protocol").is_some_and(|protocol| !protocol.eq_ignore_ascii_case("raw")) {
+                return Err(Error::Config("An MQTT output cannot be used with an output protocol".to_string()));
+            }
+        }
+        let protocol_name = get("output_protocol")?.to_ascii_lowercase();
+        let output_protocol = match protocol_name.as_str() {
+            "raw" => OutputProtocol::Raw,
+            "rfc2217" if !input.has_line_settings() => {
+                return Err(Error::Config("The rfc2217 output protocol requires a serial or PTY input".to_string()));
+            },
+            "rfc2217" => OutputProtocol::Rfc2217,
+            "modbus" => OutputProtocol::Modbus,
+            "ntrip" => OutputProtocol::Ntrip,
+            "gpsd" => OutputProtocol::Gpsd,
+            other => return Err(Error::Config(format!("Invalid output protocol: {}", other))),
+        };
+        #[cfg(unix)]
+        if matches!(output, OutputConfig::Pty { .. }) && output_protocol != OutputProtocol::Raw {
+            return Err(Error::Config(format!("The {} output protocol cannot be used with a PTY output", protocol_name)));
+        }
+
+        let ntrip_caster = match output_protocol {
+            OutputProtocol::Ntrip => Some(NtripCaster {
+                mountpoint: get("ntrip_mountpoint")?.trim_start_matches('/').to_string(),
+                credentials: match settings.get("ntrip_credentials") {
+                    Some(credentials) => match credentials.split_once(':') {
+                        Some((user, password)) => Some((user.to_string(), password.to_string())),
+                        None => return Err(Error::Config("NTRIP credentials must be given as user:password".to_string())),
+                    },
+                    None => None,
+                },
+            }),
+            _ => None,
+        };
+
+        let response_routing = match get("response_routing")?.to_ascii_lowercase().as_str() {
+            "broadcast" => None,
+            "requester" if matches!(output, OutputConfig::Mqtt(_)) => {
+                return Err(Error::Config("Response routing cannot be used with an MQTT output".to_string()));
+            },
+            "requester" if matches!(output_protocol, OutputProtocol::Modbus | OutputProtocol::Gpsd) => {
+                return Err(Error::Config("Response routing cannot be used with the modbus and gpsd output protocols".to_string()));
+            },
+            #[cfg(unix)]
+            "requester" if matches!(output, OutputConfig::Pty { .. }) => {
+                return Err(Error::Config("Response routing cannot be used with a PTY output".to_string()));
+            },
+            "requester" => Some(ResponseRouting {
+                terminator: unescape(get("response_terminator")?).into_bytes(),
+                timeout: Duration::from_millis(parse("response_timeout", get("response_timeout")?)?),
+            }),
+            other => return Err(Error::Config(format!("Invalid response routing: {}", other))),
+        };
+
+        let lag_policy = match get("lag_policy")?.to_ascii_lowercase().as_str() {
+            "skip" => LagPolicy::Skip,
+            "disconnect" => LagPolicy::Disconnect,
+            "marker" => LagPolicy::GapMarker(match settings.get("gap_marker") {
+                Some(marker) => unescape(marker),
+                None => DEFAULT_GAP_MARKER.to_string(),
+            }),
+            other => return Err(Error::Config(format!("Invalid lag policy: {}", other))),
+        };
+        let slow_client_policy = SlowClientPolicy {
+            action: match get("slow_client_policy")?.to_ascii_lowercase().as_str() {
+                "disconnect" => SlowClientAction::Disconnect,
+                "drop-oldest" => SlowClientAction::DropOldest,
+                "drop-newest" => SlowClientAction::DropNewest,
+                "coalesce" => SlowClientAction::Coalesce,
+                other => return Err(Error::Config(format!("Invalid slow client policy: {}", other))),
+            },
+            max_pending_messages: parse("max_pending_messages", get("max_pending_messages")?)?,
+            max_pending_bytes: parse("max_pending_bytes", get("max_pending_bytes")?)?,
+            write_timeout: Duration::from_millis(parse("write_timeout", get("write_timeout")?)?),
+            max_write_timeouts: parse("max_write_timeouts", get("max_write_timeouts")?)?,
+        };
+        let no_subscriber_policy = match get("no_subscriber_policy")?.to_ascii_lowercase().as_str() {
+            "discard" => NoSubscriberPolicy::Discard,
+            "buffer" => NoSubscriberPolicy::Buffer(parse("history_size", get("history_size")?)?),
+            "block" => NoSubscriberPolicy::Block,
+            other => return Err(Error::Config(format!("Invalid no-subscriber policy: {}", other))),
+        };
+        // The response router reads the input whether clients are connected or not.
+        if no_subscriber_policy != NoSubscriberPolicy::Discard && response_routing.is_some() {
+            return Err(Error::Config("The no-subscriber policy cannot be changed with response routing".to_string()));
+        }
+        // Likewise, the gpsd feed parses every sentence to answer the clients that poll.
+        if no_subscriber_policy != NoSubscriberPolicy::Discard && output_protocol == OutputProtocol::Gpsd {
+            return Err(Error::Config("The no-subscriber policy cannot be changed with the gpsd output protocol".to_string()));
+        }
+
+        let name = match settings.get("name") {
+            Some(name) => name.clone(),
+            None => format!("{}->{}", input.socket().describe(), output.describe()),
+        };
+        if let OutputConfig::Mqtt(mqtt) = &mut output {
+            mqtt.client_id = match settings.get("mqtt_client_id") {
+                Some(client_id) => client_id.clone(),
+                None => format!("port_redirector-{}", name),
+            };
+            mqtt.validate()?;
+        }
+
+        Ok(RouteConfig {
+            name,
+            input,
+            framing,
+            rtcm_filter,
+            output,
+            output_protocol,
+            modbus_timeout: Duration::from_millis(parse("modbus_timeout", get("modbus_timeout")?)?),
+            ntrip_caster,
+            response_routing,
+            channel_capacity: parse("channel_capacity", get("channel_capacity")?)?,
+            lag_policy,
+            slow_client_policy,
+            no_subscriber_policy,
+            drain_timeout: Duration::from_millis(parse("drain_timeout", get("drain_timeout")?)?),
+        })
+    }
+
+    /// Whether going from this configuration to `other` requires restarting the route. Only the no-subscriber policy
+    /// can be changed on a running route.
+    pub fn needs_restart(&self, other: &RouteConfig) -> bool {
+        let mut other = other.clone();
+        other.no_subscriber_policy = self.no_subscriber_policy;
+        *self != other
+    }
+}
+
+/// Parse a configuration file, returning its routes in order.
+///
+/// This will return an error naming the line of the first invalid route, or if two routes have the same name.
+pub fn parse_routes(text: &str) -> Result<Vec<RouteConfig>> {
+    let mut routes: Vec<RouteConfig> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let route = split_settings(line)
+            .and_then(|settings| RouteConfig::from_settings(&settings))
+            .map_err(|e| Error::Config(format!("line {}: {}", index + 1, e)))?;
+        if routes.iter().any(|r| r.name == route.name) {
+            return Err(Error::Config(format!("line {}: duplicate route name {}", index + 1, route.name)));
+        }
+        routes.push(route);
+    }
+    Ok(routes)
+}
+
+/// Split a line into its `key=value` pairs, honouring double quoted values.
+fn split_settings(line: &str) -> Result<HashMap<String, String>> {
+    let mut settings = HashMap::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(settings);
+        }
+
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && !c.is_whitespace())).collect();
+        if chars.next() != Some('=') {
+            return Err(Error::Config(format!("expected key=value, got {}", key)));
+        }
+        let value: String = if chars.next_if_eq(&'"').is_some() {
+            let value = std::iter::from_fn(|| chars.next_if(|c| *c != '"')).collect();
+            if chars.next() != Some('"') {
+                return Err(Error::Config(format!("unterminated quote in {}", key)));
+            }
+            value
+        } else {
+            std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect()
+        };
+
+        if settings.insert(key.clone(), value).is_some() {
+            return Err(Error::Config(format!("{} is set twice", key)));
+        }
+    }
+}
+
+/// The socket file permissions set by `socket_mode` (octal), `socket_owner` and `socket_group`.
+#[cfg(unix)]
+fn socket_permissions(settings: &HashMap<String, String>) -> Result<SocketPermissions> {
+    let mode = match settings.get("socket_mode") {
+        Some(mode) => Some(u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .map_err(|e| Error::Config(format!("Invalid socket_mode '{}': {}", mode, e)))?),
+        None => None,
+    };
+    Ok(SocketPermissions {
+        mode,
+        owner: settings.get("socket_owner").cloned(),
+        group: settings.get("socket_group").cloned(),
+    })
+}
+
+/// A list of RTCM 3 message types and ranges of them, such as `1005,1074-1077`.
+fn rtcm_filter(messages: &str) -> Result<RtcmFilter> {
+    let message_types = messages.split(',').map(str::trim).map(|item| match item.split_once('-') {
+        Some((first, last)) => Ok(parse("rtcm_messages", first.trim())?..=parse("rtcm_messages", last.trim())?),
+        None => parse("rtcm_messages", item).map(|message_type| message_type..=message_type),
+    }).collect::<Result<Vec<_>>>()?;
+    Ok(RtcmFilter { message_types })
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T> where T::Err: std::fmt::Display {
+    value.parse::<T>().map_err(|e| Error::Config(format!("Invalid {} '{}': {}", key, value, e)))
+}
+
+/// Replace the `\n`, `\r`, `\t` and `\\` escapes typed on the command line or in a configuration file.
+pub fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            },
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_settings_honours_quotes() {
+        let settings = split_settings(r#"name=gps  gap_marker="[{skipped} skipped]\n" output_port=8001"#).unwrap();
+        assert_eq!(settings.len(), 3);
+        assert_eq!(settings["name"], "gps");
+        assert_eq!(settings["gap_marker"], r"[{skipped} skipped]\n");
+        assert_eq!(settings["output_port"], "8001");
+    }
+
+    #[test]
+    fn split_settings_rejects_malformed_lines() {
+        assert!(split_settings("name").is_err());
+        assert!(split_settings(r#"gap_marker="open"#).is_err());
+        assert!(split_settings("name=a name=b").is_err());
+    }
+
+    #[test]
+    fn parse_routes_skips_comments_and_blank_lines() {
+        let routes = parse_routes("# comment\n\nname=gps type=udp port=5001 output_port=8001\n").unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].name, "gps");
+        assert_eq!(routes[0].input, InputConfig::Udp { port: 5001 });
+        assert_eq!(routes[0].output, OutputConfig::Tcp { port: 8001 });
+    }
+
+    #[test]
+    fn parse_routes_rejects_duplicate_names() {
+        let text = "name=gps type=udp port=5001 output_port=8001\nname=gps type=udp port=5002 output_port=8002\n";
+        let error = parse_routes(text).unwrap_err().to_string();
+        assert!(error.contains("line 2"), "{}", error);
+        assert!(error.contains("duplicate route name gps"), "{}", error);
+    }
+
+    #[test]
+    fn parse_routes_rejects_unknown_keys() {
+        let error = parse_routes("name=gps type=udp port=5001 output_port=8001 colour=blue").unwrap_err().to_string();
+        assert!(error.contains("line 1"), "{}", error);
+        assert!(error.contains("Unknown route setting: colour"), "{}", error);
+    }
+
+    #[test]
+    fn parse_routes_rejects_conflicting_outputs() {
+        let error = parse_routes("name=gps type=udp port=5001 output_port=8001 output_mqtt=localhost mqtt_topic=gps")
+            .unwrap_err().to_string();
+        assert!(error.contains("Only one of output_port, output_mqtt can be set"), "{}", error);
+    }
+
+    #[test]
+    fn gga_interval_must_not_be_zero() {
+        let route = "name=rtk type=ntrip endpoint=caster.local/BASE1 output_port=8003";
+        assert!(parse_routes(&format!("{} gga_interval=1", route)).is_ok());
+        let error = parse_routes(&format!("{} gga_interval=0", route)).unwrap_err().to_string();
+        assert!(error.contains("gga_interval must be at least 1 second"), "{}", error);
+    }
+
+    #[test]
+    fn mqtt_strings_must_fit_in_a_packet() {
+        let route = "name=gps type=udp port=5001 output_mqtt=localhost mqtt_topic=gps";
+        assert!(parse_routes(route).is_ok());
+        let error = parse_routes(&format!("{} mqtt_client_id={}", route, "a".repeat(65536))).unwrap_err().to_string();
+        assert!(error.contains("MQTT client identifier longer than 65535 bytes"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_output_only_carries_raw_data() {
+        let route = "name=gps type=serial endpoint=/dev/ttyUSB0 output_pty=/tmp/gps0";
+        assert!(parse_routes(route).is_ok());
+        for protocol in ["rfc2217", "modbus", "gpsd"] {
+            let error = parse_routes(&format!("{} output_protocol={}", route, protocol)).unwrap_err().to_string();
+            assert!(error.contains(&format!("The {} output protocol cannot be used with a PTY output", protocol)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn response_routing_keeps_the_default_no_subscriber_policy() {
+        let route = "name=probe type=udp port=5001 output_port=8001 response_routing=requester";
+        assert!(parse_routes(route).is_ok());
+        assert!(parse_routes(&format!("{} no_subscriber_policy=discard", route)).is_ok());
+        for policy in ["buffer", "block"] {
+            let error = parse_routes(&format!("{} no_subscriber_policy={}", route, policy)).unwrap_err().to_string();
+            assert!(error.contains("The no-subscriber policy cannot be changed with response routing"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn gpsd_keeps_the_default_no_subscriber_policy() {
+        let route = "name=gps type=serial endpoint=/dev/ttyUSB0 output_port=2947 output_protocol=gpsd";
+        assert!(parse_routes(route).is_ok());
+        let error = parse_routes(&format!("{} no_subscriber_policy=buffer", route)).unwrap_err().to_string();
+        assert!(error.contains("The no-subscriber policy cannot be changed with the gpsd output protocol"), "{}", error);
+    }
+
+    #[test]
+    fn no_subscriber_policy_change_is_applied_live() {
+        let routes = parse_routes("name=gps type=udp port=5001 output_port=8001").unwrap();
+        let updated = parse_routes("name=gps type=udp port=5001 output_port=8001 no_subscriber_policy=block").unwrap();
+        assert_ne!(routes[0], updated[0]);
+        assert!(!routes[0].needs_restart(&updated[0]));
+    }
+
+    #[test]
+    fn port_change_restarts_the_route() {
+        let routes = parse_routes("name=gps type=udp port=5001 output_port=8001").unwrap();
+        let updated = parse_routes("name=gps type=udp port=5001 output_port=8002").unwrap();
+        assert!(routes[0].needs_restart(&updated[0]));
+        let updated = parse_routes("name=gps type=udp port=5002 output_port=8001").unwrap();
+        assert!(routes[0].needs_restart(&updated[0]));
+    }
+}
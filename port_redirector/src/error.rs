@@ -0,0 +1,54 @@
+//! This module contains the error type returned throughout the crate.
+use std::fmt;
+use std::io;
+
+/// Errors returned when setting up or running a route.
+#[derive(Debug)]
+pub enum Error {
+    /// An argument or setting is missing or invalid.
+    Config(String),
+    /// A connection could not be opened, or a port could not be bound.
+    Connection {
+        /// The address, port or device that was being opened.
+        endpoint: String,
+        source: io::Error,
+    },
+    /// Reading or writing an established connection failed.
+    Io(io::Error),
+    /// A channel between two tasks of a route was closed, usually because the other task stopped.
+    Channel(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn connection(endpoint: impl Into<String>, source: io::Error) -> Error {
+        Error::Connection { endpoint: endpoint.into(), source }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(reason) => write!(f, "{}", reason),
+            Error::Connection { endpoint, source } => write!(f, "Unable to open {}: {}", endpoint, source),
+            Error::Io(source) => write!(f, "I/O error: {}", source),
+            Error::Channel(reason) => write!(f, "Channel closed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection { source, .. } | Error::Io(source) => Some(source),
+            Error::Config(_) | Error::Channel(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
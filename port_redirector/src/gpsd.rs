@@ -0,0 +1,718 @@
+//! This module implements the JSON protocol of gpsd, so gpsd clients can use a GNSS receiver sending NMEA 0183.
+//!
+//! The NMEA sentences read from the input are parsed into the position, heading and satellites of the receiver, which
+//! are reported to the clients as gpsd `TPV`, `ATT` and `SKY` objects: a `TPV` after every GGA or RMC sentence, an
+//! `ATT` after every HDT sentence and a `SKY` once a group of GSV sentences is complete. As with gpsd, a client only
+//! receives the reports after enabling them with `?WATCH={"enable":true,"json":true};`, and can ask for the raw
+//! sentences with `"nmea":true`. `?VERSION;`, `?DEVICES;` and `?POLL;` are answered too.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// The port gpsd listens on.
+pub const DEFAULT_PORT: u16 = 2947;
+
+/// Version of the gpsd protocol implemented.
+const PROTO_MAJOR: u32 = 3;
+const PROTO_MINOR: u32 = 14;
+
+/// Reports the slowest client can fall behind before it misses some.
+const REPORT_CAPACITY: usize = 256;
+
+/// Longest NMEA sentence accepted, the standard allowing 82 characters.
+const MAX_SENTENCE_LENGTH: usize = 1024;
+
+/// Longest request accepted from a client.
+pub const MAX_REQUEST_LENGTH: usize = 8192;
+
+const KNOTS_TO_MPS: f64 = 1852.0 / 3600.0;
+
+/// A report for the clients, with its line ending.
+#[derive(Clone, Debug)]
+pub enum Report {
+    /// A JSON object, sent to the clients watching with `"json":true`.
+    Json(Bytes),
+    /// A sentence read from the input, sent to the clients watching with `"nmea":true`.
+    Nmea(Bytes),
+}
+
+/// Builder of the JSON objects sent to the clients, with their members in the order they are added.
+struct Object(String);
+
+impl Object {
+    fn new(class: &str) -> Object {
+        Object::empty().str("class", class)
+    }
+
+    fn empty() -> Object {
+        Object(String::from("{"))
+    }
+
+    fn str(self, key: &str, value: &str) -> Object {
+        self.raw(key, format!("\"{}\"", escape(value)))
+    }
+
+    /// A number, a boolean or a JSON value written by the caller.
+    fn raw(mut self, key: &str, value: impl std::fmt::Display) -> Object {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        write!(self.0, "\"{}\":{}", key, value).unwrap();
+        self
+    }
+
+    /// A member left out when the value is not known.
+    fn opt(self, key: &str, value: Option<impl std::fmt::Display>) -> Object {
+        match value {
+            Some(value) => self.raw(key, value),
+            None => self,
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// A sentence read from the input, split into its fields.
+struct Sentence<'a> {
+    /// The talker identifier, such as `GP` for GPS or `GN` for a combined solution.
+    talker: &'a str,
+    /// The sentence formatter, such as `GGA`.
+    kind: &'a str,
+    /// The fields after the address.
+    fields: Vec<&'a str>,
+}
+
+impl Sentence<'_> {
+    /// Split a line into a sentence, or return `None` if it is not one or its checksum is invalid. The checksum is
+    /// optional, as some devices leave it out.
+    fn parse(line: &str) -> Option<Sentence<'_>> {
+        let body = line.trim_end().strip_prefix('$')?;
+        let body = match body.rsplit_once('*') {
+            Some((body, checksum)) => {
+                let expected = u8::from_str_radix(checksum, 16).ok()?;
+                if body.bytes().fold(0, |sum, byte| sum ^ byte) != expected {
+                    debug!(sentence = line.trim_end(), "Discarding NMEA sentence with an invalid checksum");
+                    return None;
+                }
+                body
+            },
+            None => body,
+        };
+        let mut fields = body.split(',');
+        let address = fields.next()?;
+        if address.len() != 5 || !address.is_ascii() {
+            return None;
+        }
+        Some(Sentence { talker: &address[..2], kind: &address[2..], fields: fields.collect() })
+    }
+
+    fn field(&self, index: usize) -> &str {
+        self.fields.get(index).copied().unwrap_or("")
+    }
+
+    fn number<T: std::str::FromStr>(&self, index: usize) -> Option<T> {
+        self.field(index).parse().ok()
+    }
+
+    /// A latitude or longitude given as degrees and minutes, followed by its hemisphere.
+    fn coordinate(&self, index: usize) -> Option<f64> {
+        let value = self.field(index);
+        let point = value.find('.').unwrap_or(value.len());
+        if point < 2 || !value.is_ascii() {
+            return None;
+        }
+        let degrees: f64 = value[..point - 2].parse().ok()?;
+        let minutes: f64 = value[point - 2..].parse().ok()?;
+        let coordinate = degrees + minutes / 60.0;
+        match self.field(index + 1) {
+            "N" | "E" => Some(coordinate),
+            "S" | "W" => Some(-coordinate),
+            _ => None,
+        }
+    }
+
+    /// A time of day given as `hhmmss.ss`, in seconds.
+    fn time(&self, index: usize) -> Option<f64> {
+        let value = self.field(index);
+        if value.len() < 6 || !value.is_ascii() {
+            return None;
+        }
+        let hours: u32 = value[..2].parse().ok()?;
+        let minutes: u32 = value[2..4].parse().ok()?;
+        let seconds: f64 = value[4..].parse().ok()?;
+        Some(f64::from(hours * 3600 + minutes * 60) + seconds)
+    }
+}
+
+/// A GNSS constellation, telling apart satellites of different systems that have the same number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum System {
+    Gps,
+    Glonass,
+    Galileo,
+    Beidou,
+    Qzss,
+    Navic,
+}
+
+impl System {
+    /// The system of a talker, `None` for `GN` which combines several systems.
+    fn of_talker(talker: &str) -> Option<System> {
+        match talker {
+            "GP" => Some(System::Gps),
+            "GL" => Some(System::Glonass),
+            "GA" => Some(System::Galileo),
+            "GB" | "BD" => Some(System::Beidou),
+            "GQ" | "QZ" => Some(System::Qzss),
+            "GI" => Some(System::Navic),
+            _ => None,
+        }
+    }
+
+    /// The system ID field added to GSA by NMEA 4.10.
+    fn of_id(id: &str) -> Option<System> {
+        match id {
+            "1" => Some(System::Gps),
+            "2" => Some(System::Glonass),
+            "3" => Some(System::Galileo),
+            "4" => Some(System::Beidou),
+            "5" => Some(System::Qzss),
+            "6" => Some(System::Navic),
+            _ => None,
+        }
+    }
+}
+
+/// Identify a satellite by its system and its number within the system. Without a system, as in `GN` sentences before
+/// NMEA 4.10, the number tells it: 65 to 96 for GLONASS, 193 to 202 for QZSS, and in the extended numbering of u-blox
+/// receivers 301 to 336 for Galileo and 401 to 437 for BeiDou. The other numbers are GPS and its SBAS.
+fn satellite_id(system: Option<System>, prn: u16) -> (System, u16) {
+    match (system, prn) {
+        (_, 301..=336) => (System::Galileo, prn - 300),
+        (_, 401..=437) => (System::Beidou, prn - 400),
+        (Some(system), _) => (system, prn),
+        (None, 65..=96) => (System::Glonass, prn),
+        (None, 193..=202) => (System::Qzss, prn),
+        (None, _) => (System::Gps, prn),
+    }
+}
+
+/// A satellite in view.
+#[derive(Clone, Debug)]
+struct Satellite {
+    prn: u16,
+    elevation: Option<f64>,
+    azimuth: Option<f64>,
+    snr: Option<f64>,
+}
+
+/// What is known of the receiver, updated by every sentence read from the input.
+#[derive(Debug, Default)]
+struct Receiver {
+    /// Year, month and day, from RMC.
+    date: Option<(u32, u32, u32)>,
+    /// Time of day of the last position, in seconds.
+    time: Option<f64>,
+    /// The GGA quality indicator.
+    quality: Option<u32>,
+    /// The fix type of GSA, 1 for none, 2 for 2D and 3 for 3D.
+    fix_type: Option<u32>,
+    /// Whether the last GGA or RMC sentence reported a valid position.
+    valid: Option<bool>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    alt_msl: Option<f64>,
+    geoid_sep: Option<f64>,
+    /// Speed over ground, in meters per second.
+    speed: Option<f64>,
+    track: Option<f64>,
+    heading: Option<f64>,
+    hdop: Option<f64>,
+    vdop: Option<f64>,
+    pdop: Option<f64>,
+    /// Satellites used in the solution, by system, as listed by the last GSA sentence of each system.
+    used: HashMap<System, Vec<u16>>,
+    /// Satellites in view, by talker.
+    in_view: BTreeMap<String, Vec<Satellite>>,
+    /// GSV groups being received, by talker.
+    partial_view: HashMap<String, Vec<Satellite>>,
+}
+
+impl Receiver {
+    /// Update the state with a sentence, returning the report it completes, if any.
+    fn update(&mut self, sentence: &Sentence, device: &str) -> Option<String> {
+        match sentence.kind {
+            "GGA" => {
+                self.time = sentence.time(0).or(self.time);
+                self.lat = sentence.coordinate(1);
+                self.lon = sentence.coordinate(3);
+                self.quality = sentence.number(5);
+                self.valid = self.quality.map(|quality| quality != 0);
+                self.hdop = sentence.number(7).or(self.hdop);
+                self.alt_msl = sentence.number(8);
+                self.geoid_sep = sentence.number(10);
+                Some(self.tpv(device))
+            },
+            "RMC" => {
+                self.time = sentence.time(0).or(self.time);
+                self.valid = Some(sentence.field(1) == "A");
+                self.lat = sentence.coordinate(2);
+                self.lon = sentence.coordinate(4);
+                self.speed = sentence.number::<f64>(6).map(|knots| knots * KNOTS_TO_MPS);
+                self.track = sentence.number(7);
+                let date = sentence.field(8);
+                if date.len() == 6 && date.bytes().all(|byte| byte.is_ascii_digit()) {
+                    let part = |start: usize| date[start..start + 2].parse::<u32>().unwrap_or(0);
+                    // Two digit years, GNSS time starting in 1980.
+                    let century = if part(4) < 80 { 2000 } else { 1900 };
+                    self.date = Some((century + part(4), part(2), part(0)));
+                }
+                Some(self.tpv(device))
+            },
+            "VTG" => {
+                self.track = sentence.number(0);
+                self.speed = sentence.number::<f64>(4).map(|knots| knots * KNOTS_TO_MPS);
+                None
+            },
+            "HDT" => {
+                self.heading = sentence.number(0);
+                self.heading?;
+                Some(Object::new("ATT").str("device", device).opt("time", self.timestamp()).opt("heading", self.heading).finish())
+            },
+            "GSA" => {
+                self.fix_type = sentence.number(1);
+                // A GSA sentence lists the satellites of a single system, several being sent for a combined solution.
+                let system = System::of_id(sentence.field(17)).or_else(|| System::of_talker(sentence.talker));
+                let used: Vec<(System, u16)> = (2..14).filter_map(|index| sentence.number(index)).map(|prn| satellite_id(system, prn)).collect();
+                if let Some(system) = used.first().map(|(system, _)| *system).or(system) {
+                    self.used.insert(system, used.into_iter().map(|(_, prn)| prn).collect());
+                }
+                self.pdop = sentence.number(14);
+                self.hdop = sentence.number(15);
+                self.vdop = sentence.number(16);
+                None
+            },
+            "GSV" => {
+                let total: u32 = sentence.number(0)?;
+                let number: u32 = sentence.number(1)?;
+                let partial = self.partial_view.entry(sentence.talker.to_string()).or_default();
+                if number == 1 {
+                    partial.clear();
+                }
+                for group in sentence.fields.get(3..).unwrap_or_default().chunks(4).filter(|group| group.len() == 4) {
+                    if let Ok(prn) = group[0].parse() {
+                        partial.push(Satellite { prn, elevation: group[1].parse().ok(), azimuth: group[2].parse().ok(), snr: group[3].parse().ok() });
+                    }
+                }
+                if number < total {
+                    return None;
+                }
+                let complete = self.partial_view.remove(sentence.talker).unwrap_or_default();
+                self.in_view.insert(sentence.talker.to_string(), complete);
+                Some(self.sky(device))
+            },
+            _ => None,
+        }
+    }
+
+    /// The gpsd fix mode: 1 for no fix, 2 for 2D and 3 for 3D.
+    fn mode(&self) -> u32 {
+        if self.valid == Some(false) || self.lat.is_none() {
+            return 1;
+        }
+        self.fix_type.unwrap_or(if self.alt_msl.is_some() { 3 } else { 2 })
+    }
+
+    /// The gpsd fix status, telling differential and RTK solutions apart.
+    fn status(&self) -> Option<u32> {
+        match self.quality? {
+            1 => Some(1),
+            2 => Some(2),
+            4 => Some(3),
+            5 => Some(4),
+            6 => Some(5),
+            _ => None,
+        }
+    }
+
+    /// The time of the last position, once the date is known, in ISO 8601.
+    fn timestamp(&self) -> Option<String> {
+        let (year, month, day) = self.date?;
+        let time = self.time?;
+        let whole = time as u32;
+        let millis = ((time - f64::from(whole)) * 1000.0).round() as u32;
+        Some(format!("\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z\"", year, month, day, whole / 3600, whole / 60 % 60, whole % 60, millis.min(999)))
+    }
+
+    fn tpv(&self, device: &str) -> String {
+        let mode = self.mode();
+        let fix = |value: Option<f64>| value.filter(|_| mode > 1);
+        Object::new("TPV")
+            .str("device", device)
+            .raw("mode", mode)
+            .opt("status", self.status().filter(|_| mode > 1))
+            .opt("time", self.timestamp())
+            .opt("lat", fix(self.lat).map(|lat| format!("{:.9}", lat)))
+            .opt("lon", fix(self.lon).map(|lon| format!("{:.9}", lon)))
+            .opt("alt", fix(self.alt_msl).filter(|_| mode == 3))
+            .opt("altMSL", fix(self.alt_msl).filter(|_| mode == 3))
+            .opt("altHAE", fix(self.alt_msl).filter(|_| mode == 3).zip(self.geoid_sep).map(|(alt, sep)| alt + sep))
+            .opt("geoidSep", self.geoid_sep)
+            .opt("track", fix(self.track))
+            .opt("speed", fix(self.speed))
+            .finish()
+    }
+
+    fn sky(&self, device: &str) -> String {
+        let is_used = |talker: &str, satellite: &Satellite| {
+            let (system, prn) = satellite_id(System::of_talker(talker), satellite.prn);
+            self.used.get(&system).is_some_and(|used| used.contains(&prn))
+        };
+        let mut satellites = String::from("[");
+        let mut count = 0;
+        let mut used_in_view = 0;
+        for (talker, satellite) in self.in_view.iter().flat_map(|(talker, satellites)| satellites.iter().map(move |satellite| (talker, satellite))) {
+            if count > 0 {
+                satellites.push(',');
+            }
+            count += 1;
+            let used = is_used(talker, satellite);
+            used_in_view += usize::from(used);
+            let entry = Object::empty()
+                .raw("PRN", satellite.prn)
+                .opt("el", satellite.elevation)
+                .opt("az", satellite.azimuth)
+                .opt("ss", satellite.snr)
+                .raw("used", used)
+                .finish();
+            satellites.push_str(&entry);
+        }
+        satellites.push(']');
+        Object::new("SKY")
+            .str("device", device)
+            .opt("time", self.timestamp())
+            .opt("hdop", self.hdop)
+            .opt("vdop", self.vdop)
+            .opt("pdop", self.pdop)
+            .raw("nSat", count)
+            .raw("uSat", used_in_view)
+            .raw("satellites", satellites)
+            .finish()
+    }
+}
+
+/// The last reports, answered to `?POLL;`.
+#[derive(Debug, Default)]
+struct Latest {
+    tpv: Option<String>,
+    sky: Option<String>,
+}
+
+/// Handle used by the client tasks to receive the reports of a `GpsdFeed` and answer requests.
+#[derive(Clone, Debug)]
+pub struct GpsdHandle {
+    device: String,
+    reports: broadcast::Sender<Report>,
+    latest: Arc<Mutex<Latest>>,
+}
+
+impl GpsdHandle {
+    /// Receive the reports made from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Report> {
+        self.reports.subscribe()
+    }
+
+    /// The `VERSION` object, sent to every client when it connects.
+    pub fn version(&self) -> String {
+        let release = env!("CARGO_PKG_VERSION");
+        Object::new("VERSION").str("release", release).str("rev", release).raw("proto_major", PROTO_MAJOR).raw("proto_minor", PROTO_MINOR).finish()
+    }
+
+    /// The `DEVICES` object, listing the input as the only device.
+    pub fn devices(&self) -> String {
+        let device = Object::new("DEVICE").str("path", &self.device).raw("activated", 1).raw("flags", 1).str("driver", "NMEA0183").finish();
+        Object::new("DEVICES").raw("devices", format!("[{}]", device)).finish()
+    }
+
+    /// The `POLL` object, with the last `TPV` and `SKY` reports.
+    fn poll(&self) -> String {
+        let latest = self.latest.lock().unwrap();
+        let list = |report: &Option<String>| format!("[{}]", report.as_deref().unwrap_or(""));
+        Object::new("POLL").raw("active", 1).raw("tpv", list(&latest.tpv)).raw("sky", list(&latest.sky)).finish()
+    }
+}
+
+/// Parses the NMEA sentences read from the input into reports for the clients.
+pub struct GpsdFeed {
+    device: String,
+    rx_from_input: broadcast::Receiver<Bytes>,
+    reports_tx: broadcast::Sender<Report>,
+    latest: Arc<Mutex<Latest>>,
+    receiver: Receiver,
+    line: BytesMut,
+}
+
+impl GpsdFeed {
+    /// Create a feed reporting the input of `broadcast_from_input_tx` as the device `device`. The feed subscribes to
+    /// the input right away, so the input always has a subscriber and its no-subscriber policy never applies.
+    pub fn new(device: &str, broadcast_from_input_tx: &broadcast::Sender<Bytes>) -> GpsdFeed {
+        let (reports_tx, _) = broadcast::channel(REPORT_CAPACITY);
+        GpsdFeed {
+            device: device.to_string(),
+            rx_from_input: broadcast_from_input_tx.subscribe(),
+            reports_tx,
+            latest: Default::default(),
+            receiver: Default::default(),
+            line: BytesMut::new(),
+        }
+    }
+
+    /// Returns the handle used by the clients.
+    pub fn handle(&self) -> GpsdHandle {
+        GpsdHandle { device: self.device.clone(), reports: self.reports_tx.clone(), latest: self.latest.clone() }
+    }
+
+    /// The main run loop, until `shutdown` is cancelled or the input stops.
+    pub async fn run_loop(mut self, shutdown: CancellationToken) {
+        loop {
+            let data = tokio::select! {
+                result = self.rx_from_input.recv() => match result {
+                    Ok(data) => data,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "gpsd feed lagged behind the input");
+                        self.line.clear();
+                        continue;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = shutdown.cancelled() => break,
+            };
+            self.line.extend_from_slice(&data);
+            while let Some(end) = self.line.iter().position(|&byte| byte == b'\n') {
+                let sentence = self.line.split_to(end + 1).freeze();
+                self.report(sentence);
+            }
+            if self.line.len() > MAX_SENTENCE_LENGTH {
+                debug!(bytes = self.line.len(), "Discarding input data without line endings");
+                self.line.advance(self.line.len());
+            }
+        }
+    }
+
+    /// Send the reports made from a line read from the input.
+    fn report(&mut self, line: Bytes) {
+        let Ok(text) = std::str::from_utf8(&line) else { return };
+        let Some(sentence) = Sentence::parse(text) else { return };
+        // No subscribed client is not an error.
+        let _ = self.reports_tx.send(Report::Nmea(Bytes::from(format!("{}\r\n", text.trim_end()))));
+        let Some(report) = self.receiver.update(&sentence, &self.device) else { return };
+        {
+            let mut latest = self.latest.lock().unwrap();
+            match sentence.kind {
+                "GGA" | "RMC" => latest.tpv = Some(report.clone()),
+                "GSV" => latest.sky = Some(report.clone()),
+                _ => {},
+            }
+        }
+        let _ = self.reports_tx.send(Report::Json(Bytes::from(report + "\r\n")));
+    }
+}
+
+/// The reports a client asked for with `?WATCH`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Watch {
+    pub enable: bool,
+    pub json: bool,
+    pub nmea: bool,
+}
+
+impl Watch {
+    /// Whether the client receives `report`.
+    pub fn wants(&self, report: &Report) -> bool {
+        self.enable && match report {
+            Report::Json(_) => self.json,
+            Report::Nmea(_) => self.nmea,
+        }
+    }
+
+    fn object(&self) -> String {
+        Object::new("WATCH").raw("enable", self.enable).raw("json", self.json).raw("nmea", self.nmea).raw("raw", 0).raw("scaled", false).raw("timing", false).raw("split24", false).raw("pps", false).finish()
+    }
+
+    /// Apply the members of a `?WATCH` argument.
+    fn update(&mut self, argument: &str) {
+        let enable = member(argument, "enable");
+        let json = member(argument, "json");
+        let nmea = member(argument, "nmea");
+        self.enable = enable.unwrap_or(true);
+        self.json = json.unwrap_or(self.json);
+        self.nmea = nmea.unwrap_or(self.nmea);
+        // As gpsd does, watching without saying what enables JSON.
+        if self.enable && json.is_none() && nmea.is_none() && !self.nmea {
+            self.json = true;
+        }
+    }
+}
+
+/// The boolean member `key` of a JSON object, if present.
+fn member(object: &str, key: &str) -> Option<bool> {
+    let quoted = format!("\"{}\"", key);
+    let rest = object[object.find(&quoted)? + quoted.len()..].trim_start().strip_prefix(':')?.trim_start();
+    if rest.starts_with("true") {
+        Some(true)
+    } else if rest.starts_with("false") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Take the next complete request out of the data received from a client, or return `None` if more data is needed.
+/// Requests end with `;` or a line ending.
+pub fn next_request(buf: &mut BytesMut) -> Option<String> {
+    loop {
+        let end = buf.iter().position(|&byte| matches!(byte, b';' | b'\n'))?;
+        let request = buf.split_to(end + 1);
+        let request = String::from_utf8_lossy(&request[..end]).trim().to_string();
+        if !request.is_empty() {
+            return Some(request);
+        }
+    }
+}
+
+/// Answer a request of a client, updating what it watches. Every object of the answer ends with a line ending.
+pub fn answer(request: &str, watch: &mut Watch, gpsd: &GpsdHandle) -> String {
+    let (name, argument) = request.split_once('=').unwrap_or((request, ""));
+    match name {
+        "?WATCH" => {
+            if !argument.is_empty() {
+                watch.update(argument);
+            }
+            format!("{}\r\n{}\r\n", gpsd.devices(), watch.object())
+        },
+        "?VERSION" => format!("{}\r\n", gpsd.version()),
+        "?DEVICES" => format!("{}\r\n", gpsd.devices()),
+        "?POLL" => format!("{}\r\n", gpsd.poll()),
+        _ => {
+            let message = format!("Unrecognized request '{}'", name.trim_start_matches('?'));
+            format!("{}\r\n", Object::new("ERROR").str("message", &message).finish())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed sentences to a receiver, adding their checksum, and return the last report.
+    fn feed(receiver: &mut Receiver, sentences: &[&str]) -> Option<String> {
+        let mut report = None;
+        for body in sentences {
+            let checksum = body.bytes().fold(0, |checksum, byte| checksum ^ byte);
+            let line = format!("${}*{:02X}\r\n", body, checksum);
+            let sentence = Sentence::parse(&line).expect("valid sentence");
+            report = receiver.update(&sentence, "test");
+        }
+        report
+    }
+
+    const GGA: &str = "GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,";
+
+    #[test]
+    fn gga_fix_overrides_an_invalid_rmc() {
+        let mut receiver = Receiver::default();
+        let report = feed(&mut receiver, &["GPRMC,123519,V,,,,,,,230394,,", GGA]).unwrap();
+        assert!(report.contains("\"mode\":3"), "{}", report);
+        let report = feed(&mut receiver, &["GPGGA,123520,,,,,0,00,,,M,,M,,"]).unwrap();
+        assert!(report.contains("\"mode\":1"), "{}", report);
+    }
+
+    #[test]
+    fn used_satellites_are_kept_per_system() {
+        let mut receiver = Receiver::default();
+        // NMEA 4.0 combined solution: one GNGSA per system, without a system ID.
+        feed(&mut receiver, &["GNGSA,A,3,05,12,,,,,,,,,,,1.5,0.9,1.2", "GNGSA,A,3,68,70,,,,,,,,,,,1.5,0.9,1.2"]);
+        let report = feed(&mut receiver, &[
+            "GPGSV,1,1,02,05,40,083,46,12,17,308,41",
+            "GLGSV,1,1,02,68,30,050,38,69,10,100,30",
+            "GAGSV,1,1,01,05,60,200,40",
+        ]).unwrap();
+        assert!(report.contains("\"nSat\":5"), "{}", report);
+        assert!(report.contains("\"uSat\":3"), "{}", report);
+        // Galileo 5 has the number of a used GPS satellite, but is not used itself.
+        assert!(report.contains("{\"PRN\":5,\"el\":60,\"az\":200,\"ss\":40,\"used\":false}"), "{}", report);
+
+        // NMEA 4.10 system IDs, here with the u-blox extended numbering of Galileo.
+        feed(&mut receiver, &["GNGSA,A,3,305,,,,,,,,,,,,1.5,0.9,1.2,3"]);
+        let report = feed(&mut receiver, &["GAGSV,1,1,01,05,60,200,40"]).unwrap();
+        assert!(report.contains("\"uSat\":4"), "{}", report);
+    }
+
+    fn handle() -> GpsdHandle {
+        let (input_tx, _) = broadcast::channel(4);
+        GpsdFeed::new("serial:/dev/ttyUSB0", &input_tx).handle()
+    }
+
+    #[test]
+    fn watch_enables_and_disables_reports() {
+        let gpsd = handle();
+        let json = Report::Json(Bytes::from_static(b"{}\r\n"));
+        let nmea = Report::Nmea(Bytes::from_static(b"$GPGGA\r\n"));
+        let mut watch = Watch::default();
+
+        let answer = answer(r#"?WATCH={"enable":true,"json":true}"#, &mut watch, &gpsd);
+        assert!(answer.starts_with(r#"{"class":"DEVICES","devices":[{"class":"DEVICE","path":"serial:/dev/ttyUSB0""#), "{}", answer);
+        assert!(answer.contains(r#"{"class":"WATCH","enable":true,"json":true,"nmea":false,"#), "{}", answer);
+        assert!(watch.wants(&json) && !watch.wants(&nmea));
+
+        super::answer(r#"?WATCH={"nmea": true, "json": false}"#, &mut watch, &gpsd);
+        assert_eq!(watch, Watch { enable: true, json: false, nmea: true });
+        assert!(!watch.wants(&json) && watch.wants(&nmea));
+
+        super::answer(r#"?WATCH={"enable":false}"#, &mut watch, &gpsd);
+        assert!(!watch.wants(&json) && !watch.wants(&nmea));
+    }
+
+    #[test]
+    fn watch_without_members_enables_json() {
+        let gpsd = handle();
+        let mut watch = Watch::default();
+        answer("?WATCH={}", &mut watch, &gpsd);
+        assert_eq!(watch, Watch { enable: true, json: true, nmea: false });
+
+        // Without an argument, the watch is only reported.
+        let mut watch = Watch::default();
+        let answer = answer("?WATCH", &mut watch, &gpsd);
+        assert!(answer.contains(r#"{"class":"WATCH","enable":false,"json":false,"#), "{}", answer);
+        assert_eq!(watch, Watch::default());
+    }
+
+    #[test]
+    fn unknown_requests_are_answered_with_an_error() {
+        let answer = answer("?SPEED", &mut Watch::default(), &handle());
+        assert_eq!(answer, "{\"class\":\"ERROR\",\"message\":\"Unrecognized request 'SPEED'\"}\r\n");
+    }
+
+    #[test]
+    fn requests_are_taken_once_complete() {
+        let mut buf = BytesMut::from(&b"?VERSION;\n?WATCH={\"enable\":"[..]);
+        assert_eq!(next_request(&mut buf).as_deref(), Some("?VERSION"));
+        assert_eq!(next_request(&mut buf), None);
+        buf.extend_from_slice(b"true};?POLL\r\n");
+        assert_eq!(next_request(&mut buf).as_deref(), Some("?WATCH={\"enable\":true}"));
+        assert_eq!(next_request(&mut buf).as_deref(), Some("?POLL"));
+        assert_eq!(next_request(&mut buf), None);
+        assert!(buf.is_empty());
+    }
+}
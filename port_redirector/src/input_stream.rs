@@ -1,28 +1,499 @@
-//! This module contains the InputStream structure which is used to handle incoming data streams, either TCP, UDP or serial connections.
+//! This module contains the InputStream structure which is used to handle incoming data streams, either TCP, UDP, serial, Unix socket, PTY or NTRIP connections.
 
-use tokio::io::{self, AsyncWriteExt, AsyncReadExt};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::{TcpStream, UdpSocket, TcpListener};
-use tokio_serial::{SerialPortBuilder, SerialPortBuilderExt, SerialStream, SerialPort};
-use tokio::sync::{mpsc, broadcast};
-use tokio::time::{sleep, Duration};
-use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_serial::{DataBits, FlowControl, Parity, SerialPortBuilder, SerialPortBuilderExt, SerialStream, SerialPort, StopBits};
+use tokio::sync::{mpsc, broadcast, Notify};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_util::sync::CancellationToken;
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, info, warn};
+use crate::error::{Error, Result};
+use crate::ntrip::{NtripSettings, NtripStream};
+use crate::rtcm::{self, Framed};
+use crate::stats::InputStats;
+#[cfg(unix)]
+use crate::pty::{PtyDevice, PtyEvent};
+#[cfg(unix)]
+use crate::unix_socket::{SocketPermissions, UnixSocketListener};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// What the input does with the data it reads while no output client is subscribed to the broadcast channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoSubscriberPolicy {
+    /// Discard the data immediately so the input keeps draining at line rate.
+    #[default]
+    Discard,
+    /// Keep the most recent chunks, up to the given number, in a history ring and send them ahead of the next chunk
+    /// once a client subscribes. Older chunks are discarded.
+    Buffer(usize),
+    /// Stop reading the input until a client subscribes.
+    Block,
+}
+
+/// How the data read from the input is split into the chunks handed to the output clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Every read is a chunk.
+    #[default]
+    Raw,
+    /// Every line is a chunk, with its line ending, whatever the reads it took. A line longer than a read buffer is
+    /// split.
+    Lines,
+    /// Every RTCM 3 frame is a chunk, whatever the reads it took. The data that is not RTCM 3 and the frames with an
+    /// invalid CRC are discarded, see `rtcm::split_frame`.
+    Rtcm3,
+}
+
+/// Serial line settings. In a change request, the settings left to `None` are not changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LineSettings {
+    pub baudrate: Option<u32>,
+    pub data_bits: Option<DataBits>,
+    pub parity: Option<Parity>,
+    pub stop_bits: Option<StopBits>,
+    pub flow_control: Option<FlowControl>,
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    /// Whether a break condition is held on the line.
+    pub break_signal: Option<bool>,
+}
+
+impl LineSettings {
+    /// Overwrite these settings with the ones set in `other`.
+    pub fn merge(&mut self, other: &LineSettings) {
+        self.baudrate = other.baudrate.or(self.baudrate);
+        self.data_bits = other.data_bits.or(self.data_bits);
+        self.parity = other.parity.or(self.parity);
+        self.stop_bits = other.stop_bits.or(self.stop_bits);
+        self.flow_control = other.flow_control.or(self.flow_control);
+        self.dtr = other.dtr.or(self.dtr);
+        self.rts = other.rts.or(self.rts);
+        self.break_signal = other.break_signal.or(self.break_signal);
+    }
+}
+
+/// Line settings waiting to be applied by the input, and the ones it last reported.
+#[derive(Debug, Default)]
+struct LineState {
+    requested: LineSettings,
+    current: LineSettings,
+}
+
+/// Handle for pausing and resuming a running input.
+///
+/// While paused, the input keeps being read so the sender and the driver buffers do not back up, but the data is
+/// discarded instead of being retransmitted. Data from the output clients is still written to the input.
+///
+/// It also carries requests to change the line settings of a serial input, see `Input::set_line_settings`.
+#[derive(Clone, Debug, Default)]
+pub struct InputControl {
+    paused: Arc<AtomicBool>,
+    no_subscriber_policy: Arc<Mutex<NoSubscriberPolicy>>,
+    framing: Arc<Mutex<Framing>>,
+    line: Arc<Mutex<LineState>>,
+    line_requested: Arc<Notify>,
+    line_applied: Arc<Notify>,
+}
+
+impl InputControl {
+    pub fn new() -> InputControl {
+        InputControl::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Set what the input does while no output client is subscribed. The default is `NoSubscriberPolicy::Discard`.
+    pub fn set_no_subscriber_policy(&self, policy: NoSubscriberPolicy) {
+        *self.no_subscriber_policy.lock().unwrap() = policy;
+    }
+
+    pub fn no_subscriber_policy(&self) -> NoSubscriberPolicy {
+        *self.no_subscriber_policy.lock().unwrap()
+    }
+
+    /// Set how the input data is split into chunks. The default is `Framing::Raw`.
+    pub fn set_framing(&self, framing: Framing) {
+        *self.framing.lock().unwrap() = framing;
+    }
+
+    pub fn framing(&self) -> Framing {
+        *self.framing.lock().unwrap()
+    }
+
+    /// Ask the running input to change its line settings. Requests made before the input gets to them are merged.
+    pub fn request_line_settings(&self, settings: LineSettings) {
+        self.line.lock().unwrap().requested.merge(&settings);
+        self.line_requested.notify_one();
+    }
+
+    /// Ask the running input to change its line settings and wait for it to try, at most `limit`. Returns the line
+    /// settings in use afterwards, where the requested settings the input refused keep their previous value.
+    pub async fn apply_line_settings(&self, settings: LineSettings, limit: Duration) -> LineSettings {
+        let applied = self.line_applied.notified();
+        self.request_line_settings(settings);
+        let _ = timeout(limit, applied).await;
+        self.line_settings()
+    }
+
+    /// The line settings last reported by the input, all `None` for inputs without a serial line.
+    pub fn line_settings(&self) -> LineSettings {
+        self.line.lock().unwrap().current
+    }
+
+    /// Wait for a line settings change request, and take it.
+    async fn line_request(&self) -> LineSettings {
+        loop {
+            self.line_requested.notified().await;
+            let requested = std::mem::take(&mut self.line.lock().unwrap().requested);
+            if requested != LineSettings::default() {
+                return requested;
+            }
+        }
+    }
+
+    fn update_line_settings(&self, settings: &LineSettings) {
+        self.line.lock().unwrap().current.merge(settings);
+    }
+}
+
+/// Space reserved for every read, large enough for any UDP datagram.
+const READ_CAPACITY: usize = 64 * 1024;
+
+/// State of the input while no output client is subscribed.
+#[derive(Default)]
+struct Unsubscribed {
+    /// Whether the last chunk found no subscribers.
+    active: bool,
+    /// Chunks discarded since the last subscriber left.
+    discarded: u64,
+    /// Chunks held back by `NoSubscriberPolicy::Buffer`.
+    history: VecDeque<Bytes>,
+    /// Chunks held back by `NoSubscriberPolicy::Block`. The input is not read while there are any.
+    blocked: VecDeque<Bytes>,
+}
+
+/// How often the input checks for a subscriber while blocked.
+const BLOCK_RETRY: Duration = Duration::from_millis(10);
+
+/// Send a chunk read from the input on the broadcast channel, applying the no-subscriber policy if nobody listens.
+/// The chunks blocked by `NoSubscriberPolicy::Block` keep their order.
+fn forward(tx_channel: &broadcast::Sender<Bytes>, data: Bytes, control: &InputControl, stats: &InputStats, state: &mut Unsubscribed) {
+    if !state.blocked.is_empty() {
+        state.blocked.push_back(data);
+        return;
+    }
+    if let Some(data) = send(tx_channel, data, control, stats, state) {
+        state.blocked.push_back(data);
+    }
+}
+
+/// Send the chunks blocked by `NoSubscriberPolicy::Block`, until one finds no subscriber again.
+fn resume_blocked(tx_channel: &broadcast::Sender<Bytes>, control: &InputControl, stats: &InputStats, state: &mut Unsubscribed) {
+    while let Some(data) = state.blocked.pop_front() {
+        if let Some(data) = send(tx_channel, data, control, stats, state) {
+            state.blocked.push_front(data);
+            return;
+        }
+    }
+}
+
+/// Send a chunk on the broadcast channel, or apply the no-subscriber policy to it. Returns the chunk if the policy is
+/// `NoSubscriberPolicy::Block`.
+fn send(tx_channel: &broadcast::Sender<Bytes>, data: Bytes, control: &InputControl, stats: &InputStats, state: &mut Unsubscribed) -> Option<Bytes> {
+    if tx_channel.receiver_count() > 0 {
+        if state.active {
+            info!(discarded = state.discarded, replayed = state.history.len(), "Output client subscribed, resuming retransmission");
+            state.active = false;
+            state.discarded = 0;
+        }
+        for chunk in state.history.drain(..) {
+            // A send error means the subscriber left again, the chunk is lost like any other.
+            let _ = tx_channel.send(chunk);
+        }
+    }
+
+    let data = match tx_channel.send(data) {
+        Ok(_) => return None,
+        Err(broadcast::error::SendError(returned)) => returned,
+    };
+
+    let policy = control.no_subscriber_policy();
+    if !state.active {
+        state.active = true;
+        info!(?policy, "No output client subscribed");
+        if policy == NoSubscriberPolicy::Block {
+            stats.record_backpressure();
+        }
+    }
+
+    match policy {
+        NoSubscriberPolicy::Discard => {
+            stats.record_dropped();
+            state.discarded += 1;
+            debug!(bytes = data.len(), "Discarded input, no output client subscribed");
+            None
+        },
+        NoSubscriberPolicy::Buffer(capacity) => {
+            if capacity == 0 || state.history.len() >= capacity {
+                state.history.pop_front();
+                stats.record_dropped();
+                state.discarded += 1;
+            }
+            if capacity > 0 {
+                state.history.push_back(data);
+            }
+            None
+        },
+        NoSubscriberPolicy::Block => Some(data),
+    }
+}
+
+
+/// A transport the input side of a route reads from and writes back to.
+///
+/// `InputSocket` implements it for the built-in transports. Applications can implement it for their own and hand them
+/// to `run_input`, the `RetransmitServer` only sees the channels and works with any of them.
+///
+/// ```rust,no_run
+/// # use port_redirector::input_stream::{run_input, Input, InputControl};
+/// # use port_redirector::stats::InputStats;
+/// # use bytes::BytesMut;
+/// # use tokio_util::sync::CancellationToken;
+/// # use std::io;
+/// struct Replay { data: Vec<u8>, stats: InputStats }
+///
+/// impl Input for Replay {
+///     async fn connect(self) -> port_redirector::error::Result<Replay> {
+///         self.stats.record_connection();
+///         Ok(self)
+///     }
+///
+///     async fn read(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+///         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+///         buf.extend_from_slice(&self.data);
+///         Ok(self.data.len())
+///     }
+///
+///     async fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+///         Ok(0)
+///     }
+///
+///     fn describe(&self) -> String {
+///         "replay".to_string()
+///     }
+///
+///     fn stats(&self) -> InputStats {
+///         self.stats.clone()
+///     }
+/// }
+///
+/// # async fn example() -> port_redirector::error::Result<()> {
+/// let (input_tx, _) = tokio::sync::broadcast::channel(4096);
+/// let (_, rx_to_input) = tokio::sync::mpsc::channel(1024);
+/// let mut input = Replay { data: b"hello\n".to_vec(), stats: InputStats::new() }.connect().await?;
+/// tokio::spawn( async move { run_input(&mut input, input_tx, rx_to_input, InputControl::new(), CancellationToken::new()).await });
+/// # Ok(())
+/// # }
+/// ```
+pub trait Input: Send {
+    /// Open the connection described by `self`, returning the connected input.
+    fn connect(self) -> impl Future<Output = Result<Self>> + Send where Self: Sized;
+
+    /// Read into the spare capacity of `buf`, returning the number of bytes read. Ok(0) means nothing was read this
+    /// time, for example because the connection changed, and the read is simply retried. An error stops `run_input`,
+    /// so a closed connection must be reported as one.
+    fn read(&mut self, buf: &mut BytesMut) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Write data from the output clients to the remote end, returning the number of bytes written.
+    fn write(&mut self, buf: &[u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Close the connection cleanly when the route shuts down. Nothing is done by default.
+    fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Apply the line settings set in `settings`, leaving the others unchanged, and return the settings now in use.
+    /// Settings that cannot be read back may be left to `None`. Inputs without a serial line return an
+    /// `ErrorKind::Unsupported` error, the default.
+    fn set_line_settings(&mut self, _settings: &LineSettings) -> io::Result<LineSettings> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "This input has no line settings."))
+    }
+
+    /// Short description of the input, such as `tcp:192.168.0.1:8080`, used in logs and default route names.
+    fn describe(&self) -> String;
+
+    /// The statistics handle of the input. `run_input` records the data flowing through it, the input itself only
+    /// records its connections with `InputStats::record_connection`.
+    fn stats(&self) -> InputStats;
+}
+
+/// The main run loop of any input.
+///
+/// Data read from the input is sent on the broadcast channel and data recieved on the MPSC channel is written back to
+/// the input. The statistics returned by `Input::stats` are updated as data flows through, and `control` can be used
+/// to pause the input or change its line settings while it runs.
+///
+/// Chunks are read into a shared buffer and handed out as `Bytes` slices of it, so the fan-out to the clients does
+/// not copy the data.
+///
+/// Returns an error when reading or writing the input fails, or when the channel from the output clients is closed
+/// because the server retransmitting the input stopped.
+///
+/// Once `shutdown` is cancelled the input stops reading, writes the data the output clients already sent back, and
+/// is closed with `Input::close`.
+pub async fn run_input<I: Input>(input: &mut I, tx_channel: broadcast::Sender<Bytes>, mut rx_channel: mpsc::Receiver<Bytes>, control: InputControl, shutdown: CancellationToken) -> Result<()> {
+    let stats = input.stats();
+    let mut unsubscribed = Unsubscribed::default();
+    // With RTCM 3 framing, whether the input lost the frame boundaries after a CRC failure.
+    let mut resyncing = false;
+
+    let mut buf = BytesMut::new();
+    debug!(input = %input.describe(), "Input run loop started");
+    if let Ok(current) = input.set_line_settings(&LineSettings::default()) {
+        control.update_line_settings(&current);
+    }
+
+    loop {
+        // Reclaims the buffer once every chunk handed out from it is dropped, otherwise allocates a new one.
+        buf.reserve(READ_CAPACITY);
+
+        tokio::select!{
+            val = rx_channel.recv() => {
+                let Some(val) = val else {
+                    if shutdown.is_cancelled() {
+                        // The server stopped first.
+                        break;
+                    }
+                    return Err(Error::Channel("output clients to input".to_string()));
+                };
+                let written = input.write(&val).await?;
+                stats.record_write_back(written);
+            },
+
+            // Reading stops while chunks are blocked, the other requests are still served.
+            n = input.read(&mut buf), if unsubscribed.blocked.is_empty() => {
+                let n = n?;
+                if n == 0 {
+                    continue;
+                }
+                stats.record_input(n);
+                // With framing, a partial frame or line stays in the buffer until the next read completes it.
+                match control.framing() {
+                    Framing::Raw => {},
+                    Framing::Lines => {
+                        while let Some(end) = buf.iter().position(|&byte| byte == b'\n') {
+                            let line = buf.split_to(end + 1).freeze();
+                            if !control.is_paused() {
+                                forward(&tx_channel, line, &control, &stats, &mut unsubscribed);
+                            }
+                        }
+                        if buf.len() < READ_CAPACITY {
+                            continue;
+                        }
+                    },
+                    Framing::Rtcm3 => {
+                        while let Some(framed) = rtcm::split_frame(&mut buf) {
+                            match framed {
+                                Framed::Frame(frame) => {
+                                    resyncing = false;
+                                    if !control.is_paused() {
+                                        forward(&tx_channel, frame, &control, &stats, &mut unsubscribed);
+                                    }
+                                },
+                                // The preambles found inside a corrupted frame, or in data that is not RTCM 3, would
+                                // each fail again.
+                                Framed::CrcFailure(length) if resyncing => {
+                                    debug!(bytes = length, "Discarding stray RTCM 3 preamble while resynchronising");
+                                },
+                                Framed::CrcFailure(length) => {
+                                    resyncing = true;
+                                    let total = stats.record_crc_failure();
+                                    warn!(bytes = length, total, "Discarding RTCM 3 frame with an invalid CRC");
+                                },
+                            }
+                        }
+                        continue;
+                    },
+                }
+                let data = buf.split().freeze();
+                if control.is_paused() {
+                    continue;
+                }
+
+                forward(&tx_channel, data, &control, &stats, &mut unsubscribed);
+            },
+
+            _ = sleep(BLOCK_RETRY), if !unsubscribed.blocked.is_empty() => {
+                resume_blocked(&tx_channel, &control, &stats, &mut unsubscribed);
+            },
+
+            settings = control.line_request() => {
+                match input.set_line_settings(&settings) {
+                    Ok(current) => {
+                        info!(?settings, "Changed input line settings");
+                        control.update_line_settings(&current);
+                    },
+                    Err(e) => warn!(error = %e, ?settings, "Unable to change input line settings"),
+                }
+                control.line_applied.notify_waiters();
+            },
+
+            _ = shutdown.cancelled() => break,
+        };
+    }
+
+    info!(input = %input.describe(), "Shutting down input");
+    for _ in unsubscribed.blocked.drain(..) {
+        stats.record_dropped();
+    }
+    while let Ok(val) = rx_channel.try_recv() {
+        let written = input.write(&val).await?;
+        stats.record_write_back(written);
+    }
+    input.close().await?;
+    Ok(())
+}
 
 
 /// This enum represents the different input sockets supported by the input connection.
+///
+/// Every variant carries an `InputStats` handle, normally created with `Default::default()`.
 pub enum InputSocket {
     /// The TCP socket requires an ip address and a port. This can either be sent together: 
     /// ```rust
-    /// InputSocket::TcpSocket {ip: "192.168.0.1:8080"};
+    /// # use port_redirector::input_stream::InputSocket;
+    /// InputSocket::TcpSocket {ip: "192.168.0.1:8080".to_string(), port: None, rd: None, tx: None, stats: Default::default()};
     /// ```
     /// or
     /// ```rust
-    /// InputSocket::TcpSocket {ip: "192.168.0.1", port: Some(8080)}; 
+    /// # use port_redirector::input_stream::InputSocket;
+    /// InputSocket::TcpSocket {ip: "192.168.0.1".to_string(), port: Some(8080), rd: None, tx: None, stats: Default::default()};
     /// ```
     TcpSocket {
         ip: String,
         port: Option<u16>,
         rd: Option<io::ReadHalf<TcpStream>>,
-        tx: Option<io::WriteHalf<TcpStream>>
+        tx: Option<io::WriteHalf<TcpStream>>,
+        stats: InputStats
     },
     /// TCP server that listens for a single connection, and only that one connection.
     ///
@@ -30,24 +501,85 @@ pub enum InputSocket {
         port: u16,
         server: Option<TcpListener>,
         stream: Option<TcpStream>,
+        stats: InputStats
     },
     /// As UDP is stateless, you only need to send a port value.
     /// ```rust
-    /// InputSocket::UdpSocket(port: 8080);
+    /// # use port_redirector::input_stream::InputSocket;
+    /// InputSocket::UdpSocket {port: 8080, rd: None, stats: Default::default()};
     /// ```
     UdpSocket {
         port: u16,
-        rd: Option<UdpSocket>
+        rd: Option<UdpSocket>,
+        stats: InputStats
     },
     /// The serial port can be initialized with or without a baudrate. Default is 9600 if a option is not specified.
     /// ```rust
-    /// InputSocket::Serial (port_name="COM6", baudrate= Some(115200));
+    /// # use port_redirector::input_stream::InputSocket;
+    /// InputSocket::Serial {port_name: "COM6".to_string(), baudrate: Some(115200), rd: None, tx: None, stats: Default::default()};
     /// ```
     Serial {
         port_name: String,
         baudrate: Option<u32>,
         rd: Option<io::ReadHalf<SerialStream>>,
-        tx: Option<io::WriteHalf<SerialStream>>
+        tx: Option<io::WriteHalf<SerialStream>>,
+        stats: InputStats
+    },
+    /// Unix stream socket connected to the given path.
+    /// ```rust
+    /// # use port_redirector::input_stream::InputSocket;
+    /// InputSocket::UnixSocket {path: "/run/sensor.sock".to_string(), rd: None, tx: None, stats: Default::default()};
+    /// ```
+    #[cfg(unix)]
+    UnixSocket {
+        path: String,
+        rd: Option<io::ReadHalf<UnixStream>>,
+        tx: Option<io::WriteHalf<UnixStream>>,
+        stats: InputStats
+    },
+    /// Unix stream socket listening on the given path for a single connection at a time, like `TcpServer`. The socket
+    /// file is created with the given permissions and removed when the input is closed.
+    /// ```rust
+    /// # use port_redirector::input_stream::InputSocket;
+    /// InputSocket::UnixServer {path: "/run/sensor.sock".to_string(), permissions: Default::default(), server: None, stream: None, stats: Default::default()};
+    /// ```
+    #[cfg(unix)]
+    UnixServer {
+        path: String,
+        permissions: SocketPermissions,
+        server: Option<UnixSocketListener>,
+        stream: Option<UnixStream>,
+        stats: InputStats
+    },
+    /// Virtual serial port for a program that writes to a serial device. A PTY is created with the given permissions
+    /// and published as a symlink at the given path, and the data the program writes to it is read. When the program
+    /// closes it, the PTY is replaced for the next program. The symlink is removed when the input is closed.
+    /// ```rust
+    /// # use port_redirector::input_stream::InputSocket;
+    /// InputSocket::Pty {link: "/tmp/sim0".to_string(), permissions: Default::default(), device: None, stats: Default::default()};
+    /// ```
+    #[cfg(unix)]
+    Pty {
+        link: String,
+        permissions: SocketPermissions,
+        device: Option<PtyDevice>,
+        stats: InputStats
+    },
+    /// NTRIP client receiving a GNSS correction stream from a caster mountpoint. Data written to the input, such as the
+    /// GGA sentences of a rover, is sent to the caster.
+    /// ```rust
+    /// # use port_redirector::input_stream::InputSocket;
+    /// # use port_redirector::ntrip::NtripSettings;
+    /// # fn example() -> port_redirector::error::Result<()> {
+    /// let settings = NtripSettings::from_endpoint("rover:secret@caster.example.com:2101/BASE1")?;
+    /// InputSocket::Ntrip {settings, stream: None, stats: Default::default()};
+    /// # Ok(())
+    /// # }
+    /// ```
+    Ntrip {
+        settings: NtripSettings,
+        stream: Option<NtripStream>,
+        stats: InputStats
     }
 }
 
@@ -55,155 +587,269 @@ pub enum InputSocket {
 impl InputSocket {
     /// Create a new input connection given the SocketType and connects to it.
     ///
-    /// ```rust
-    /// let socket = InputSocket::connect( InputSocket::TcpSocket {ip: "192.168.0.1", port: Some(8080)} )?;
+    /// ```rust,no_run
+    /// # use port_redirector::input_stream::InputSocket;
+    /// # async fn example() -> port_redirector::error::Result<()> {
+    /// let socket = InputSocket::connect( InputSocket::TcpSocket {ip: "192.168.0.1".to_string(), port: Some(8080), rd: None, tx: None, stats: Default::default()} ).await?;
+    /// # Ok(())
+    /// # }
     /// ```
     ///
     /// This will return an error if the connection cannot be made.
-    pub async fn connect (port_type: InputSocket) -> io::Result<InputSocket> {
+    pub async fn connect (port_type: InputSocket) -> Result<InputSocket> {
 
         match port_type {
-            InputSocket::TcpSocket {ip, port, ..} => {
+            InputSocket::TcpSocket {ip, port, stats, ..} => {
                 let endpoint = match port {
                     Some(val) => ip.clone() + ":" + &val.to_string(),
                     None => ip.clone()
                 };
                 
-                let socket = TcpStream::connect(&endpoint).await?;
+                let socket = TcpStream::connect(&endpoint).await.map_err(|e| Error::connection(&endpoint, e))?;
                 let (rd, tx) = io::split(socket);
-                let socket = InputSocket::TcpSocket{ip: ip, port: port, rd:  Some(rd), tx: Some(tx)};
+                stats.record_connection();
+                let socket = InputSocket::TcpSocket{ip, port, rd:  Some(rd), tx: Some(tx), stats};
 
-                println!("Open TCP listener on {}.", endpoint);
+                info!(%endpoint, "Open TCP listener");
                 Ok(socket)
             },
-            InputSocket::TcpServer {port, ..} => {
+            InputSocket::TcpServer {port, stats, ..} => {
                 let endpoint = "0.0.0.0:".to_owned() + &port.to_string();
 
-                let server = TcpListener::bind(&endpoint).await?;
-                let socket = InputSocket::TcpServer{port: port, server: Some(server), stream: None};
-                println!("Input TCP server lisenting on port {}", port);
+                let server = TcpListener::bind(&endpoint).await.map_err(|e| Error::connection(&endpoint, e))?;
+                let socket = InputSocket::TcpServer{port, server: Some(server), stream: None, stats};
+                info!(port, "Input TCP server listening");
 
                 Ok(socket)
             }
-            InputSocket::UdpSocket {port, ..} => {
-                let sock = UdpSocket::bind("0.0.0.0:".to_owned() + &port.to_string()).await?;
-                let socket = InputSocket::UdpSocket{port: port, rd:  Some(sock)};
-                println!("Open UDP listener on port {}.", port);
+            InputSocket::UdpSocket {port, stats, ..} => {
+                let endpoint = "0.0.0.0:".to_owned() + &port.to_string();
+                let sock = UdpSocket::bind(&endpoint).await.map_err(|e| Error::connection(&endpoint, e))?;
+                stats.record_connection();
+                let socket = InputSocket::UdpSocket{port, rd:  Some(sock), stats};
+                info!(port, "Open UDP listener");
                 Ok(socket)
             },
-            InputSocket::Serial {port_name, baudrate, ..} => {
-                let baudrate = match baudrate {
-                    Some(val) => val,
-                    None => 9600
-                };
+            InputSocket::Serial {port_name, baudrate, stats, ..} => {
+                let baudrate = baudrate.unwrap_or(9600);
 
                 let sp_build: SerialPortBuilder = tokio_serial::new(port_name.clone(), baudrate);
-                let mut serial_str = match sp_build.open_native_async() {
-                    Err(e) => {
-                        eprintln!("Error opening {} {}", port_name, e);
-                        panic!();
-                    },
-                    Ok(str) => str
-                };
+                let mut serial_str = sp_build.open_native_async()
+                    .map_err(|e| Error::connection(&port_name, io::Error::from(e)))?;
                 
                 let dtr_ok = serial_str.write_data_terminal_ready(true).is_ok();
 
                 if dtr_ok {
-                    println!("DTR Set");
+                    debug!(port = %port_name, "DTR set");
                 } else {
-                    println!("Error setting DTR (ignored)");
+                    warn!(port = %port_name, "Error setting DTR (ignored)");
                 };
 
                 let (rd, tx) = io::split(serial_str);
-                let socket = InputSocket::Serial{port_name: port_name.clone(), baudrate: Some(baudrate), rd: Some(rd), tx: Some(tx)};
+                stats.record_connection();
+                let socket = InputSocket::Serial{port_name: port_name.clone(), baudrate: Some(baudrate), rd: Some(rd), tx: Some(tx), stats};
 
-                println!("Opened Serial listener on port {} at {} baud.", port_name, baudrate);
+                info!(port = %port_name, baudrate, "Opened serial listener");
 
                 Ok(socket)
+            },
+            #[cfg(unix)]
+            InputSocket::UnixSocket {path, stats, ..} => {
+                let socket = UnixStream::connect(&path).await.map_err(|e| Error::connection(&path, e))?;
+                let (rd, tx) = io::split(socket);
+                stats.record_connection();
+                info!(%path, "Open Unix socket listener");
+                Ok(InputSocket::UnixSocket{path, rd: Some(rd), tx: Some(tx), stats})
+            },
+            #[cfg(unix)]
+            InputSocket::UnixServer {path, permissions, stats, ..} => {
+                let server = UnixSocketListener::bind(&path, &permissions).await.map_err(|e| Error::connection(&path, e))?;
+                info!(%path, "Input Unix socket server listening");
+                Ok(InputSocket::UnixServer{path, permissions, server: Some(server), stream: None, stats})
+            },
+            #[cfg(unix)]
+            InputSocket::Pty {link, permissions, stats, ..} => {
+                let device = PtyDevice::create(&link, &permissions).map_err(|e| Error::connection(&link, e))?;
+                Ok(InputSocket::Pty{link, permissions, device: Some(device), stats})
+            },
+            InputSocket::Ntrip {settings, stats, ..} => {
+                let stream = NtripStream::connect(&settings).await.map_err(|e| Error::connection(settings.describe(), e))?;
+                stats.record_connection();
+                Ok(InputSocket::Ntrip{settings, stream: Some(stream), stats})
             }
         }
     }
-    
-    
-    /// This function allows you to read from the different port types asynchornously.
-    /// This follows the convention of the AsyncRead function, returning Ok(0) if the port is closed.
-    /// This will also return and error if the reader is uninitialized (with new)
-    ///
-    /// This function is only used internally by the tokio process spawned by run.
-    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+
+
+    /// Returns the statistics handle of this input. The handle is carried over by `connect`, so it can be taken
+    /// before connecting.
+    pub fn stats(&self) -> InputStats {
+        match self {
+            InputSocket::TcpSocket {stats, ..} |
+            InputSocket::TcpServer {stats, ..} |
+            InputSocket::UdpSocket {stats, ..} |
+            InputSocket::Serial {stats, ..} |
+            InputSocket::Ntrip {stats, ..} => stats.clone(),
+            #[cfg(unix)]
+            InputSocket::UnixSocket {stats, ..} |
+            InputSocket::UnixServer {stats, ..} |
+            InputSocket::Pty {stats, ..} => stats.clone()
+        }
+    }
+
+    /// The main run loop, see `run_input`.
+    pub async fn run_loop (&mut self, tx_channel: broadcast::Sender<Bytes>, rx_channel: mpsc::Receiver<Bytes>, control: InputControl, shutdown: CancellationToken) -> Result<()> {
+        run_input(self, tx_channel, rx_channel, control, shutdown).await
+    }
+
+}
+
+impl Input for InputSocket {
+    async fn connect(self) -> Result<InputSocket> {
+        InputSocket::connect(self).await
+    }
+
+    /// Reads from the different port types, appending to the spare capacity of `buf` (a UDP datagram is truncated to
+    /// that capacity). The TCP and Unix socket server inputs return Ok(0) when their client connects or disconnects,
+    /// and the PTY input when its program closes the PTY.
+    async fn read(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
         match self {
             InputSocket::TcpSocket {rd, ..} => {
                 let rd = match rd {
                     Some(val) => val,
-                    None => {return Err(io::Error::new(io::ErrorKind::Other, "Uninitialized TCP reciever."));}
+                    None => {return Err(io::Error::other("Uninitialized TCP reciever."));}
                 }; 
-                Ok(rd.read(buf).await?)
+                match rd.read_buf(buf).await? {
+                    0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "TCP input connection closed.")),
+                    n => Ok(n),
+                }
             },
-            InputSocket::TcpServer{server, stream, ..} => {
+            InputSocket::TcpServer{server, stream, stats, ..} => {
                 // Try to read from existing stream if available
                 if let Some(ref mut tcp_stream) = stream {
-                    match tcp_stream.read(buf).await {
+                    match tcp_stream.read_buf(buf).await {
                         Ok(0) => {
                             // Socket closed, clear it
                             *stream = None;
-                            println!("Input client disconnected, waiting for new connection.");
+                            info!("Input client disconnected, waiting for new connection");
                             return Ok(0);
                         },
                         Ok(n) => {
                             return Ok(n);
                         },
                         Err(e) => {
-                            eprintln!("Error reading from tcp server stream: {:?}", e);
-                            // Clear the stream on error
+                            // Only this client is lost, wait for the next one.
+                            warn!(error = %e, kind = ?e.kind(), "Error reading from TCP server stream");
                             *stream = None;
-                            return Err(e);
+                            return Ok(0);
                         }
                     }
                 }
 
                 // No client connected, accept a new one
                 let listener = server.as_ref()
-                    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Uninitialized TCP Server."))?;
+                    .ok_or_else(|| io::Error::other("Uninitialized TCP Server."))?;
 
-                let (new_stream, _addr) = listener.accept().await?;
-                println!("TCP Server: New client connected from {}", _addr);
+                let (new_stream, addr) = listener.accept().await?;
+                info!(peer = %addr, "Input TCP server client connected");
+                stats.record_connection();
                 *stream = Some(new_stream);
                 Ok(0)
             },
             InputSocket::UdpSocket {rd, ..} => {
                 let rd = match rd {
                     Some(val) => val,
-                    None => {return Err(io::Error::new(io::ErrorKind::Other, "Uninitialized UDP reciever."));}
+                    None => {return Err(io::Error::other("Uninitialized UDP reciever."));}
                 };
-                Ok(rd.recv(buf).await?)
+                Ok(rd.recv_buf(buf).await?)
             },
             InputSocket::Serial {rd, ..} => {
                 let rd = match rd {
                     Some(val) => val,
-                    None => {return Err(io::Error::new(io::ErrorKind::Other, "Uninitialized Serial reciever."));}
+                    None => {return Err(io::Error::other("Uninitialized Serial reciever."));}
                 };
-                Ok(rd.read(buf).await?)
+                match rd.read_buf(buf).await? {
+                    0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Serial port closed.")),
+                    n => Ok(n),
+                }
+            },
+            #[cfg(unix)]
+            InputSocket::UnixSocket {rd, ..} => {
+                let rd = rd.as_mut().ok_or_else(|| io::Error::other("Uninitialized Unix socket reciever."))?;
+                match rd.read_buf(buf).await? {
+                    0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unix socket input connection closed.")),
+                    n => Ok(n),
+                }
+            },
+            #[cfg(unix)]
+            InputSocket::UnixServer{server, stream, stats, ..} => {
+                if let Some(ref mut unix_stream) = stream {
+                    match unix_stream.read_buf(buf).await {
+                        Ok(0) => {
+                            *stream = None;
+                            info!("Input client disconnected, waiting for new connection");
+                            return Ok(0);
+                        },
+                        Ok(n) => return Ok(n),
+                        Err(e) => {
+                            // Only this client is lost, wait for the next one.
+                            warn!(error = %e, kind = ?e.kind(), "Error reading from Unix socket server stream");
+                            *stream = None;
+                            return Ok(0);
+                        }
+                    }
+                }
+
+                let listener = server.as_ref()
+                    .ok_or_else(|| io::Error::other("Uninitialized Unix socket server."))?;
+                *stream = Some(listener.accept().await?);
+                info!(path = %listener.path().display(), "Input Unix socket server client connected");
+                stats.record_connection();
+                Ok(0)
+            },
+            #[cfg(unix)]
+            InputSocket::Pty {device, stats, ..} => {
+                let device = device.as_mut().ok_or_else(|| io::Error::other("Uninitialized PTY."))?;
+                let before = buf.len();
+                if device.read_event(buf).await? == PtyEvent::Opened {
+                    stats.record_connection();
+                }
+                Ok(buf.len() - before)
+            },
+            InputSocket::Ntrip {stream, ..} => {
+                let stream = stream.as_mut().ok_or_else(|| io::Error::other("Uninitialized NTRIP reciever."))?;
+                match stream.read_buf(buf).await? {
+                    0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "NTRIP stream closed.")),
+                    n => Ok(n),
+                }
             }
         }
     }
 
-    /// This function sends data recieved on an MPSC socket.
+    /// Writes the data to the remote end. The UDP input, the TCP server input while no client is connected, and the PTY
+    /// input while no program has it open, return Ok(0).
     async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self {
             InputSocket::TcpSocket {rd: _, tx, ..} => {
                 let tx = match tx {
                     Some(val) => val,
-                    None => {return Err(io::Error::new(io::ErrorKind::Other, "Uninitialized TCP transmitter."));}
+                    None => {return Err(io::Error::other("Uninitialized TCP transmitter."));}
                 }; 
                 let length = buf.len();
-                tx.write_all(&buf).await?;
+                tx.write_all(buf).await?;
                 Ok(length)
             },
             InputSocket::TcpServer{stream, ..} => {
                 if let Some(ref mut tcp_stream) = stream {
-                    let written = buf.len();
-                    tcp_stream.write_all(&buf).await?;
-                    Ok(written)
+                    match tcp_stream.write_all(buf).await {
+                        Ok(()) => Ok(buf.len()),
+                        Err(e) => {
+                            // Only this client is lost, wait for the next one.
+                            warn!(error = %e, kind = ?e.kind(), "Error writing to TCP server stream");
+                            *stream = None;
+                            Ok(0)
+                        }
+                    }
                 } else {
                     // No client connected, can't write
                     Ok(0)
@@ -215,73 +861,381 @@ impl InputSocket {
             InputSocket::Serial {rd: _, tx, ..} => {
                 let tx = match tx {
                     Some(val) => val,
-                    None => {return Err(io::Error::new(io::ErrorKind::Other, "Uninitialized Serial transmitter."));}
+                    None => {return Err(io::Error::other("Uninitialized Serial transmitter."));}
                 };
                 let length = buf.len();
-                tx.write_all(&buf).await?;
+                tx.write_all(buf).await?;
                 Ok(length)
+            },
+            #[cfg(unix)]
+            InputSocket::UnixSocket {tx, ..} => {
+                let tx = tx.as_mut().ok_or_else(|| io::Error::other("Uninitialized Unix socket transmitter."))?;
+                tx.write_all(buf).await?;
+                Ok(buf.len())
+            },
+            #[cfg(unix)]
+            InputSocket::UnixServer{stream, ..} => {
+                let Some(unix_stream) = stream else {
+                    return Ok(0);
+                };
+                match unix_stream.write_all(buf).await {
+                    Ok(()) => Ok(buf.len()),
+                    Err(e) => {
+                        // Only this client is lost, wait for the next one.
+                        warn!(error = %e, kind = ?e.kind(), "Error writing to Unix socket server stream");
+                        *stream = None;
+                        Ok(0)
+                    }
+                }
+            },
+            #[cfg(unix)]
+            InputSocket::Pty {device, ..} => {
+                let device = device.as_mut().ok_or_else(|| io::Error::other("Uninitialized PTY."))?;
+                if !device.is_open() {
+                    return Ok(0);
+                }
+                match device.write_all(buf).await {
+                    Ok(()) => Ok(buf.len()),
+                    Err(e) => {
+                        // The program closed the PTY, the next read replaces it.
+                        warn!(error = %e, kind = ?e.kind(), "Error writing to PTY");
+                        Ok(0)
+                    }
+                }
+            },
+            InputSocket::Ntrip {stream, ..} => {
+                let stream = stream.as_mut().ok_or_else(|| io::Error::other("Uninitialized NTRIP transmitter."))?;
+                stream.write_all(buf).await?;
+                Ok(buf.len())
             }
         }
     }
 
-    pub async fn run_loop (&mut self, tx_channel: broadcast::Sender<Vec<u8>>, mut rx_channel: mpsc::Receiver<Vec<u8>>) {
-        // Statistics tracking
-        static DROPPED_MESSAGES: AtomicU64 = AtomicU64::new(0);
-        static BACKPRESSURE_EVENTS: AtomicU64 = AtomicU64::new(0);
+    /// Shuts down the TCP and Unix socket connections and stops listening. A serial port has DTR dropped before it is closed, so the
+    /// device sees the host go away. A PTY is closed and its symlink removed.
+    async fn close(&mut self) -> io::Result<()> {
+        match self {
+            InputSocket::TcpSocket {rd, tx, ..} => {
+                *rd = None;
+                if let Some(mut tx) = tx.take() {
+                    tx.shutdown().await?;
+                }
+            },
+            InputSocket::TcpServer {server, stream, ..} => {
+                *server = None;
+                if let Some(mut tcp_stream) = stream.take() {
+                    tcp_stream.shutdown().await?;
+                }
+            },
+            InputSocket::UdpSocket {rd, ..} => {
+                *rd = None;
+            },
+            InputSocket::Serial {port_name, rd, tx, ..} => {
+                if let (Some(rd), Some(tx)) = (rd.take(), tx.take()) {
+                    let mut serial_str = rd.unsplit(tx);
+                    if serial_str.write_data_terminal_ready(false).is_ok() {
+                        debug!(port = %port_name, "DTR cleared");
+                    } else {
+                        warn!(port = %port_name, "Error clearing DTR (ignored)");
+                    }
+                }
+            },
+            #[cfg(unix)]
+            InputSocket::UnixSocket {rd, tx, ..} => {
+                *rd = None;
+                if let Some(mut tx) = tx.take() {
+                    tx.shutdown().await?;
+                }
+            },
+            #[cfg(unix)]
+            InputSocket::UnixServer {server, stream, ..} => {
+                *server = None;
+                if let Some(mut unix_stream) = stream.take() {
+                    unix_stream.shutdown().await?;
+                }
+            },
+            #[cfg(unix)]
+            InputSocket::Pty {device, ..} => {
+                *device = None;
+            },
+            InputSocket::Ntrip {stream, ..} => {
+                if let Some(mut stream) = stream.take() {
+                    stream.shutdown().await?;
+                }
+            }
+        }
+        info!(input = %self.describe(), "Input closed");
+        Ok(())
+    }
 
-        loop {
-            let mut buf = vec![0; 8192];
-
-            tokio::select!{
-                Some(val) = rx_channel.recv() => {
-                    self.write(&val).await.expect("Unexpected MPSC write error");
-                },
-
-                Ok(n) = self.read(&mut buf) => {
-                    buf.truncate(n);
-
-                    // Implement backpressure with exponential backoff
-                    let mut retry_count = 0;
-                    const MAX_RETRIES: u32 = 10;
-                    const BASE_DELAY_MS: u64 = 1;
-
-                    loop {
-                        match tx_channel.send(buf.clone()) {
-                            Ok(_) => {
-                                // Successfully sent
-                                if retry_count > 0 {
-                                    println!("Backpressure resolved after {} retries", retry_count);
-                                }
-                                break;
+    /// Only the serial and PTY inputs have line settings. Their DTR, RTS and break state cannot be read back, so they are
+    /// only returned when changed. Settings refused by the port are logged and left out.
+    ///
+    /// The settings of a PTY are those of its terminal, which the program using it can change as well.
+    fn set_line_settings(&mut self, settings: &LineSettings) -> io::Result<LineSettings> {
+        #[cfg(unix)]
+        if let InputSocket::Pty {device, ..} = self {
+            let device = device.as_mut().ok_or_else(|| not_connected("PTY"))?;
+            return apply_line_settings(device.master_mut(), settings);
+        }
+        let InputSocket::Serial {rd, tx, ..} = self else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Only serial inputs have line settings."));
+        };
+        let (Some(serial_rd), Some(serial_tx)) = (rd.take(), tx.take()) else {
+            return Err(not_connected("Serial port"));
+        };
+        let mut serial_str = serial_rd.unsplit(serial_tx);
+        let result = apply_line_settings(&mut serial_str, settings);
+        let (serial_rd, serial_tx) = io::split(serial_str);
+        *rd = Some(serial_rd);
+        *tx = Some(serial_tx);
+        result
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            InputSocket::TcpSocket { ip, port: Some(port), .. } => format!("tcp:{}:{}", ip, port),
+            InputSocket::TcpSocket { ip, port: None, .. } => format!("tcp:{}", ip),
+            InputSocket::TcpServer { port, .. } => format!("tcps:{}", port),
+            InputSocket::UdpSocket { port, .. } => format!("udp:{}", port),
+            InputSocket::Serial { port_name, .. } => format!("serial:{}", port_name),
+            #[cfg(unix)]
+            InputSocket::UnixSocket { path, .. } => format!("unix:{}", path),
+            #[cfg(unix)]
+            InputSocket::UnixServer { path, .. } => format!("unixs:{}", path),
+            #[cfg(unix)]
+            InputSocket::Pty { link, .. } => format!("pty:{}", link),
+            InputSocket::Ntrip { settings, .. } => format!("ntrip:{}", settings.describe()),
+        }
+    }
+
+    fn stats(&self) -> InputStats {
+        InputSocket::stats(self)
+    }
+}
+
+/// Apply each requested setting on its own, so one the port refuses does not prevent the others. Refused settings are
+/// logged, and this only returns an error if the settings cannot be read back.
+fn apply_line_settings(serial_str: &mut SerialStream, settings: &LineSettings) -> io::Result<LineSettings> {
+    let applied = |setting: &str, result: tokio_serial::Result<()>| match result {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(setting, error = %e, "Serial port refused line setting");
+            false
+        }
+    };
+    if let Some(baudrate) = settings.baudrate {
+        applied("baudrate", serial_str.set_baud_rate(baudrate));
+    }
+    if let Some(data_bits) = settings.data_bits {
+        applied("data_bits", serial_str.set_data_bits(data_bits));
+    }
+    if let Some(parity) = settings.parity {
+        applied("parity", serial_str.set_parity(parity));
+    }
+    if let Some(stop_bits) = settings.stop_bits {
+        applied("stop_bits", serial_str.set_stop_bits(stop_bits));
+    }
+    if let Some(flow_control) = settings.flow_control {
+        applied("flow_control", serial_str.set_flow_control(flow_control));
+    }
+    let dtr = settings.dtr.filter(|dtr| applied("dtr", serial_str.write_data_terminal_ready(*dtr)));
+    let rts = settings.rts.filter(|rts| applied("rts", serial_str.write_request_to_send(*rts)));
+    let break_signal = settings.break_signal.filter(|on| {
+        applied("break", if *on { serial_str.set_break() } else { serial_str.clear_break() })
+    });
+    Ok(LineSettings {
+        baudrate: Some(serial_str.baud_rate()?),
+        data_bits: Some(serial_str.data_bits()?),
+        parity: Some(serial_str.parity()?),
+        stop_bits: Some(serial_str.stop_bits()?),
+        flow_control: Some(serial_str.flow_control()?),
+        dtr,
+        rts,
+        break_signal,
+    })
+}
+
+fn not_connected(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, format!("Uninitialized {}, call InputSocket::connect first.", what))
+}
+
+/// A connected input can be read directly, so it composes with `tokio::io` utilities and codecs instead of going
+/// through `run_loop`. Apart from the connections, the statistics are only updated by `run_loop`.
+///
+/// The TCP and Unix socket server inputs never report end of file: when their client disconnects or fails, the read
+/// waits for the next client. Likewise, the PTY input waits for the next program.
+///
+/// ```rust,no_run
+/// # use port_redirector::input_stream::InputSocket;
+/// use tokio::io::{AsyncBufReadExt, BufReader};
+/// # async fn example() -> port_redirector::error::Result<()> {
+/// let socket = InputSocket::connect( InputSocket::Serial {port_name: "/dev/ttyUSB0".to_string(), baudrate: Some(115200), rd: None, tx: None, stats: Default::default()} ).await?;
+/// let mut lines = BufReader::new(socket).lines();
+/// while let Some(line) = lines.next_line().await? {
+///     println!("{}", line);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+impl AsyncRead for InputSocket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InputSocket::TcpSocket {rd, ..} => {
+                Pin::new(rd.as_mut().ok_or_else(|| not_connected("TCP reciever"))?).poll_read(cx, buf)
+            },
+            InputSocket::TcpServer {server, stream, stats, ..} => {
+                if buf.remaining() == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                loop {
+                    if let Some(tcp_stream) = stream {
+                        let filled = buf.filled().len();
+                        match ready!(Pin::new(tcp_stream).poll_read(cx, buf)) {
+                            Ok(()) if buf.filled().len() == filled => {
+                                *stream = None;
+                                info!("Input client disconnected, waiting for new connection");
                             },
-                            Err(broadcast::error::SendError(_)) => {
-                                if retry_count == 0 {
-                                    // First backpressure event
-                                    let bp_count = BACKPRESSURE_EVENTS.fetch_add(1, Ordering::Relaxed) + 1;
-                                    eprintln!("WARNING: Broadcast channel full, applying backpressure (event #{})", bp_count);
-                                }
-
-                                if retry_count >= MAX_RETRIES {
-                                    // Max retries exceeded, drop the message
-                                    let dropped = DROPPED_MESSAGES.fetch_add(1, Ordering::Relaxed) + 1;
-                                    eprintln!("ERROR: Message dropped after {} retries (total dropped: {})", MAX_RETRIES, dropped);
-                                    break;
-                                }
-
-                                // Exponential backoff: 1ms, 2ms, 4ms, 8ms, 16ms, 32ms, 64ms, 128ms, 256ms, 512ms
-                                let delay_ms = BASE_DELAY_MS * (1 << retry_count);
-                                if retry_count % 3 == 0 {
-                                    eprintln!("Backpressure: retry {} after {}ms delay", retry_count + 1, delay_ms);
-                                }
-                                sleep(Duration::from_millis(delay_ms)).await;
-                                retry_count += 1;
+                            Ok(()) => return Poll::Ready(Ok(())),
+                            Err(e) => {
+                                // Only this client is lost, wait for the next one as `Input::read` does.
+                                warn!(error = %e, kind = ?e.kind(), "Error reading from input client, waiting for new connection");
+                                *stream = None;
                             }
                         }
                     }
-                },
-            };
+
+                    let listener = server.as_ref().ok_or_else(|| not_connected("TCP server"))?;
+                    let (new_stream, addr) = ready!(listener.poll_accept(cx))?;
+                    info!(peer = %addr, "Input TCP server client connected");
+                    stats.record_connection();
+                    *stream = Some(new_stream);
+                }
+            },
+            InputSocket::UdpSocket {rd, ..} => {
+                rd.as_ref().ok_or_else(|| not_connected("UDP reciever"))?.poll_recv(cx, buf)
+            },
+            InputSocket::Serial {rd, ..} => {
+                Pin::new(rd.as_mut().ok_or_else(|| not_connected("Serial reciever"))?).poll_read(cx, buf)
+            },
+            #[cfg(unix)]
+            InputSocket::UnixSocket {rd, ..} => {
+                Pin::new(rd.as_mut().ok_or_else(|| not_connected("Unix socket reciever"))?).poll_read(cx, buf)
+            },
+            #[cfg(unix)]
+            InputSocket::UnixServer {server, stream, stats, ..} => {
+                if buf.remaining() == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                loop {
+                    if let Some(unix_stream) = stream {
+                        let filled = buf.filled().len();
+                        match ready!(Pin::new(unix_stream).poll_read(cx, buf)) {
+                            Ok(()) if buf.filled().len() == filled => {
+                                *stream = None;
+                                info!("Input client disconnected, waiting for new connection");
+                            },
+                            Ok(()) => return Poll::Ready(Ok(())),
+                            Err(e) => {
+                                // Only this client is lost, wait for the next one as `Input::read` does.
+                                warn!(error = %e, kind = ?e.kind(), "Error reading from input client, waiting for new connection");
+                                *stream = None;
+                            }
+                        }
+                    }
+
+                    let listener = server.as_ref().ok_or_else(|| not_connected("Unix socket server"))?;
+                    *stream = Some(ready!(listener.poll_accept(cx))?);
+                    info!(path = %listener.path().display(), "Input Unix socket server client connected");
+                    stats.record_connection();
+                }
+            },
+            #[cfg(unix)]
+            InputSocket::Pty {device, stats, ..} => {
+                if buf.remaining() == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                let device = device.as_mut().ok_or_else(|| not_connected("PTY"))?;
+                let filled = buf.filled().len();
+                loop {
+                    if ready!(device.poll_event(cx, buf))? == PtyEvent::Opened {
+                        stats.record_connection();
+                    }
+                    if buf.filled().len() > filled {
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+            },
+            InputSocket::Ntrip {stream, ..} => {
+                Pin::new(stream.as_mut().ok_or_else(|| not_connected("NTRIP reciever"))?).poll_read(cx, buf)
+            }
         }
     }
-
 }
 
+/// Writing to a connected input sends the data to the remote end, like the data written back by output clients.
+///
+/// The TCP and Unix socket server inputs fail with `NotConnected` while no client is connected. The UDP input, and the
+/// PTY input while no program has it open, have nowhere to send the data: it is discarded and reported as written.
+impl AsyncWrite for InputSocket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            InputSocket::TcpSocket {tx, ..} => {
+                Pin::new(tx.as_mut().ok_or_else(|| not_connected("TCP transmitter"))?).poll_write(cx, buf)
+            },
+            InputSocket::TcpServer {stream: Some(tcp_stream), ..} => Pin::new(tcp_stream).poll_write(cx, buf),
+            InputSocket::TcpServer {stream: None, ..} => Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected, "No input client connected."))),
+            InputSocket::UdpSocket {..} => Poll::Ready(Ok(buf.len())),
+            InputSocket::Serial {tx, ..} => {
+                Pin::new(tx.as_mut().ok_or_else(|| not_connected("Serial transmitter"))?).poll_write(cx, buf)
+            },
+            #[cfg(unix)]
+            InputSocket::UnixSocket {tx, ..} => {
+                Pin::new(tx.as_mut().ok_or_else(|| not_connected("Unix socket transmitter"))?).poll_write(cx, buf)
+            },
+            #[cfg(unix)]
+            InputSocket::UnixServer {stream: Some(unix_stream), ..} => Pin::new(unix_stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            InputSocket::UnixServer {stream: None, ..} => Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected, "No input client connected."))),
+            #[cfg(unix)]
+            InputSocket::Pty {device, ..} => {
+                Pin::new(device.as_mut().ok_or_else(|| not_connected("PTY"))?).poll_write(cx, buf)
+            },
+            InputSocket::Ntrip {stream, ..} => {
+                Pin::new(stream.as_mut().ok_or_else(|| not_connected("NTRIP transmitter"))?).poll_write(cx, buf)
+            },
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InputSocket::TcpSocket {tx: Some(tx), ..} => Pin::new(tx).poll_flush(cx),
+            InputSocket::TcpServer {stream: Some(tcp_stream), ..} => Pin::new(tcp_stream).poll_flush(cx),
+            InputSocket::Serial {tx: Some(tx), ..} => Pin::new(tx).poll_flush(cx),
+            #[cfg(unix)]
+            InputSocket::UnixSocket {tx: Some(tx), ..} => Pin::new(tx).poll_flush(cx),
+            #[cfg(unix)]
+            InputSocket::UnixServer {stream: Some(unix_stream), ..} => Pin::new(unix_stream).poll_flush(cx),
+            #[cfg(unix)]
+            InputSocket::Pty {device: Some(device), ..} => Pin::new(device).poll_flush(cx),
+            InputSocket::Ntrip {stream: Some(stream), ..} => Pin::new(stream).poll_flush(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InputSocket::TcpSocket {tx: Some(tx), ..} => Pin::new(tx).poll_shutdown(cx),
+            InputSocket::TcpServer {stream: Some(tcp_stream), ..} => Pin::new(tcp_stream).poll_shutdown(cx),
+            InputSocket::Serial {tx: Some(tx), ..} => Pin::new(tx).poll_shutdown(cx),
+            #[cfg(unix)]
+            InputSocket::UnixSocket {tx: Some(tx), ..} => Pin::new(tx).poll_shutdown(cx),
+            #[cfg(unix)]
+            InputSocket::UnixServer {stream: Some(unix_stream), ..} => Pin::new(unix_stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            InputSocket::Pty {device: Some(device), ..} => Pin::new(device).poll_shutdown(cx),
+            InputSocket::Ntrip {stream: Some(stream), ..} => Pin::new(stream).poll_shutdown(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
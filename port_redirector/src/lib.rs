@@ -3,5 +3,25 @@
 //! data from a sensor to multiple endpoints.
 
 
+pub mod admin;
+pub mod config;
+pub mod error;
+pub mod gpsd;
 pub mod input_stream;
-pub mod retransmit_server;
\ No newline at end of file
+pub mod logging;
+pub mod metrics;
+pub mod modbus;
+pub mod mqtt;
+pub mod ntrip;
+#[cfg(unix)]
+pub mod pty;
+pub mod responses;
+pub mod retransmit_server;
+pub mod rfc2217;
+pub mod routes;
+pub mod rtcm;
+pub mod stats;
+pub mod supervisor;
+pub mod systemd;
+#[cfg(unix)]
+pub mod unix_socket;
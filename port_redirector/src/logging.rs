@@ -0,0 +1,194 @@
+//! This module sets up the structured logging used throughout the crate. All diagnostics are emitted through `tracing`,
+//! this module only decides where they end up (console, file or syslog) and in what format (plain text or JSON).
+
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+use std::sync::Mutex;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+/// Default verbosity when neither the command line nor `RUST_LOG` specify one.
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Output format of the log lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable single line output.
+    Text,
+    /// One JSON object per line, suitable for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<LogFormat> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid log format: {}", s))),
+        }
+    }
+}
+
+/// Logging configuration, usually built from the command line.
+///
+/// ```rust,no_run
+/// use port_redirector::logging::{LogConfig, LogFormat};
+///
+/// let config = LogConfig { level: Some("debug".to_string()), format: LogFormat::Json, ..LogConfig::default() };
+/// port_redirector::logging::init(&config).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    /// Filter directive (e.g. `info` or `port_redirector=debug`). If `None`, `RUST_LOG` is used, falling back to `info`.
+    pub level: Option<String>,
+    pub format: LogFormat,
+    /// Also append the logs to this file.
+    pub log_file: Option<String>,
+    /// Also send the logs to the syslog socket at this path (usually `/dev/log`).
+    pub syslog: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig { level: None, format: LogFormat::Text, log_file: None, syslog: None }
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> fmt::MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer().json().with_current_span(true).with_span_list(false).with_writer(writer).boxed(),
+    }
+}
+
+/// Install the global tracing subscriber described by the configuration.
+///
+/// This will return an error if the filter is invalid, the log file or syslog socket cannot be opened, or if a
+/// subscriber was already installed.
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let filter = match &config.level {
+        Some(level) => EnvFilter::try_new(level),
+        None => EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(DEFAULT_LOG_LEVEL)),
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid log level: {}", e)))?;
+
+    // Span fields are formatted once and shared between the layers, so colours are only safe on a console-only setup.
+    let ansi = config.log_file.is_none() && config.syslog.is_none() && io::stdout().is_terminal();
+    let mut layers: Vec<BoxedLayer> = vec![format_layer(config.format, io::stdout, ansi)];
+
+    if let Some(path) = &config.log_file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        layers.push(format_layer(config.format, Mutex::new(file), false));
+    }
+
+    if let Some(path) = &config.syslog {
+        layers.push(syslog_layer(path, config.format)?);
+    }
+
+    tracing_subscriber::registry()
+        .with(layers.with_filter(filter))
+        .try_init()
+        .map_err(io::Error::other)
+}
+
+#[cfg(unix)]
+fn syslog_layer(path: &str, format: LogFormat) -> io::Result<BoxedLayer> {
+    let writer = syslog::SyslogMakeWriter::connect(path)?;
+    Ok(match format {
+        // syslog stamps the messages itself.
+        LogFormat::Text => fmt::layer().with_writer(writer).with_ansi(false).without_time().boxed(),
+        LogFormat::Json => format_layer(format, writer, false),
+    })
+}
+
+#[cfg(not(unix))]
+fn syslog_layer(_path: &str, _format: LogFormat) -> io::Result<BoxedLayer> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Syslog output is only supported on unix platforms."))
+}
+
+#[cfg(unix)]
+mod syslog {
+    //! Minimal RFC 3164 writer for the local syslog datagram socket.
+
+    use std::io::{self, Write};
+    use std::os::unix::net::UnixDatagram;
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// Facility `daemon` (3), shifted as required by the PRI field.
+    const FACILITY_DAEMON: u8 = 3 << 3;
+
+    pub struct SyslogMakeWriter {
+        socket: UnixDatagram,
+        tag: String,
+    }
+
+    impl SyslogMakeWriter {
+        pub fn connect(path: &str) -> io::Result<SyslogMakeWriter> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            let tag = format!("port_redirector[{}]", std::process::id());
+            Ok(SyslogMakeWriter { socket, tag })
+        }
+    }
+
+    /// Collects a single formatted event and sends it as one datagram when dropped.
+    pub struct SyslogWriter<'a> {
+        socket: &'a UnixDatagram,
+        buf: Vec<u8>,
+    }
+
+    impl Write for SyslogWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for SyslogWriter<'_> {
+        fn drop(&mut self) {
+            while self.buf.last() == Some(&b'\n') {
+                self.buf.pop();
+            }
+            // There is nowhere left to report a failure to log.
+            let _ = self.socket.send(&self.buf);
+        }
+    }
+
+    impl SyslogMakeWriter {
+        fn writer(&self, level: Level) -> SyslogWriter<'_> {
+            let severity = match level {
+                Level::ERROR => 3,
+                Level::WARN => 4,
+                Level::INFO => 6,
+                Level::DEBUG | Level::TRACE => 7,
+            };
+            let buf = format!("<{}>{}: ", FACILITY_DAEMON | severity, self.tag).into_bytes();
+            SyslogWriter { socket: &self.socket, buf }
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for SyslogMakeWriter {
+        type Writer = SyslogWriter<'a>;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.writer(Level::INFO)
+        }
+
+        fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+            self.writer(*meta.level())
+        }
+    }
+}
@@ -1,12 +1,22 @@
 //! This port_redirector_tool is the console application that opens a single port and retransmits it's data to multiple TCP sockets./
-use port_redirector::input_stream::InputSocket;
-use port_redirector::retransmit_server::RetransmitServer;
+use port_redirector::config::{self, RouteConfig, ROUTE_KEYS};
+use port_redirector::logging::{self, LogConfig, LogFormat};
+use port_redirector::metrics::MetricsServer;
+use port_redirector::admin::AdminServer;
+use port_redirector::routes::RouteRegistry;
+use port_redirector::supervisor::{ReloadRequest, ReloadSummary, Supervisor};
+use port_redirector::error::{Error, Result};
+use port_redirector::systemd;
 
-use tokio::io;
 use tokio::signal;
-use tokio::sync::{mpsc, broadcast};
-use clap::{Arg, Command};
-use std::fmt::Write;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Interval};
+use clap::{Arg, ArgMatches, Command};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::process::ExitCode;
+use std::str::FromStr;
+use tracing::{error, info, warn};
 
 /// This program opens the provided port (either TCP, UDP or Serial), starts a TCP server, and retransmits any data 
 /// given to that port to any client connected to that server. It will also read in data from the server and retransmit on the single port.
@@ -14,11 +24,35 @@ use std::fmt::Write;
 /// To get a help message, run with the -h flag.
 /// 
 /// To exit the program, type Ctrl-C
-#[tokio::main]
-async fn main() -> io::Result<()> {
-   
-    //Parse the input arguments.
-    let matches = Command::new ("port_redirector_tool")
+fn main() -> ExitCode {
+    let matches = cli().get_matches();
+    // Until the logging is set up, errors can only be printed.
+    if let Err(e) = init_logging(&matches) {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    // Taking the listeners changes the environment, which is only safe before the runtime starts its threads.
+    let listeners = systemd::take_listeners();
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!(error = %e, "Unable to start the runtime");
+            return ExitCode::FAILURE;
+        }
+    };
+    match runtime.block_on(run(matches, listeners)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!(error = %e, "Stopping after a fatal error");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+/// The command line, see `config` for the route arguments.
+fn cli() -> Command {
+    Command::new ("port_redirector_tool")
         .about(
 "This application takes input from a UDP, Serial or TCP client or TCP server and redirects out on a TCP server that multiple clients can connect to.
 \tUsage: TCP client input:
@@ -32,18 +66,30 @@ async fn main() -> io::Result<()> {
  The above command will open up the local port 5001 with UDP and retransmit any UDP data sent to it through to clients that connect to localhost 8001. \n
 \tSerial Input:
 \t\t port_redirector_tool -t serial -e COM6 -b 115200 -o 8001\n
-The above command will open the serial port on COM6 at 115200 baud and retransmit any data recieved to clients connected to the TCP server at localhost 8001. \n" )
+The above command will open the serial port on COM6 at 115200 baud and retransmit any data recieved to clients connected to the TCP server at localhost 8001. \n
+\tUnix socket input and output:
+\t\t port_redirector_tool -t unix -e /run/imu.sock --output-path /run/imu-shared.sock --socket-mode 660\n
+The above command will connect to the Unix socket /run/imu.sock and retransmit its data to clients connected to the Unix socket /run/imu-shared.sock, which only its owner and group can use. \n
+\tPTY input:
+\t\t port_redirector_tool -t pty -e /tmp/sim0 -o 8001\n
+The above command will create a virtual serial port at /tmp/sim0 and retransmit whatever a program writes to it to clients connected to the TCP server at localhost 8001. \n" )
+        .arg(Arg::new("config")
+                    .short('c')
+                    .long("config")
+                    .value_name("FILE")
+                    .conflicts_with_all(["type", "output_port", "output_path", "output_pty", "output_mqtt"])
+                    .help("Run the routes listed in this file instead of the one given by the arguments, reloaded on SIGHUP"))
         .arg(Arg::new("type")
                     .short('t')
                     .long("type")
                     .value_name("TYPE")
-                    .required(true)
-                    .help("What type of input: 'Serial', 'TCP', 'TCPS', 'UDP'"))
+                    .required_unless_present("config")
+                    .help("What type of input: 'Serial', 'TCP', 'TCPS', 'UDP', 'Unix', 'UnixS' (Unix socket server), 'PTY' (virtual serial port) or 'NTRIP' (NTRIP client)"))
         .arg(Arg::new("endpoint")
                     .short('e')
                     .long("endpoint")
                     .value_name("ENDPOINT")
-                    .help("What endpoint to listen too (<ip> for TCP or <com port> for Serial"))
+                    .help("What endpoint to listen too (<ip> for TCP, <com port> for Serial, <socket path> for Unix, <symlink path> for PTY or [user[:password]@]host[:port]/mountpoint for NTRIP"))
         .arg(Arg::new("port")
                     .short('p')
                     .long("port")
@@ -54,91 +100,421 @@ The above command will open the serial port on COM6 at 115200 baud and retransmi
                     .long("baudrate")
                     .value_name("BAUDRATE")
                     .help("Baudrate for the serial port (default 9600)"))
+        .arg(Arg::new("ntrip_version")
+                    .long("ntrip-version")
+                    .value_name("VERSION")
+                    .default_value(config::default_value("ntrip_version"))
+                    .help("NTRIP version spoken to the caster, 1 or 2"))
+        .arg(Arg::new("gga")
+                    .long("gga")
+                    .value_name("SENTENCE")
+                    .help("GGA sentence sent to the NTRIP caster when connecting and every --gga-interval-secs, for mountpoints that need the position of the rover"))
+        .arg(Arg::new("gga_interval")
+                    .long("gga-interval-secs")
+                    .value_name("SECONDS")
+                    .default_value(config::default_value("gga_interval"))
+                    .help("How often the --gga sentence is sent to the NTRIP caster"))
+        .arg(Arg::new("framing")
+                    .long("framing")
+                    .value_name("FRAMING")
+                    .default_value(config::default_value("framing"))
+                    .help("How the input data is split before being retransmitted: 'raw' as read, 'lines' to send whole lines, or 'rtcm3' to send whole RTCM 3 frames with a valid CRC, discarding anything else"))
+        .arg(Arg::new("rtcm_messages")
+                    .long("rtcm-messages")
+                    .value_name("TYPES")
+                    .help("RTCM 3 message types sent to the output clients with --framing rtcm3, e.g. 1005,1074-1077,1230 (default: all)"))
         .arg(Arg::new("output_port")
                     .short('o')
                     .long("output_port")
                     .value_name("OUTPUT_PORT")
-                    .required(true)
+                    .required_unless_present_any(["config", "output_path", "output_pty", "output_mqtt"])
                     .help("What port to listen on for the TCP redirector server."))
-        .get_matches();
-
-
-    let output_port = matches.get_one::<String>("output_port")
-        .expect("output_port is required")
-        .parse::<u16>()
-        .expect("output_port must be a valid u16");
-    let socket_type_name = matches.get_one::<String>("type")
-        .expect("type is required")
-        .to_ascii_lowercase();
-
-
-    let socket_type = match socket_type_name.as_str() {
-        "missing" => {return Err(io::Error::new(io::ErrorKind::Other, "Missing parameter socket type name."));}
-        "tcp" => {
-            let ip = matches.get_one::<String>("endpoint")
-                .expect("Endpoint IP address required for TCP")
-                .to_string();
-            let port = matches.get_one::<String>("port")
-                .expect("Port required for TCP")
-                .parse::<u16>()
-                .expect("Port must be a valid u16");
-            InputSocket::TcpSocket { ip: ip.to_string(), port: Some(port), rd: None, tx: None }
-        },
-        "tcps" => {
-            let port = matches.get_one::<String>("port")
-                .expect("Listen port required for TCP Server")
-                .parse::<u16>()
-                .expect("Port must be a valid u16");
-            InputSocket::TcpServer { port, server: None, stream: None }
-        }
-        "udp" => {
-            let port = matches.get_one::<String>("port")
-                .expect("Port required for UDP")
-                .parse::<u16>()
-                .expect("Port must be a valid u16");
-            InputSocket::UdpSocket {port: port, rd: None}
+        .arg(Arg::new("output_path")
+                    .long("output-path")
+                    .value_name("SOCKET_PATH")
+                    .conflicts_with("output_port")
+                    .help("Serve the output on a Unix socket at this path instead of a TCP port"))
+        .arg(Arg::new("output_pty")
+                    .long("output-pty")
+                    .value_name("LINK_PATH")
+                    .conflicts_with_all(["output_port", "output_path"])
+                    .help("Serve the output on a virtual serial port (PTY) published as a symlink at this path, e.g. /tmp/gps0"))
+        .arg(Arg::new("output_mqtt")
+                    .long("output-mqtt")
+                    .value_name("BROKER")
+                    .conflicts_with_all(["output_port", "output_path", "output_pty"])
+                    .requires("mqtt_topic")
+                    .help("Publish the output to the MQTT broker at [user[:password]@]host[:port] instead of serving it (default port 1883)"))
+        .arg(Arg::new("mqtt_topic")
+                    .long("mqtt-topic")
+                    .value_name("TOPIC")
+                    .help("Topic every chunk of input data is published to with --output-mqtt"))
+        .arg(Arg::new("mqtt_qos")
+                    .long("mqtt-qos")
+                    .value_name("QOS")
+                    .default_value(config::default_value("mqtt_qos"))
+                    .help("QoS of the published messages and the command subscription, 0, 1 or 2"))
+        .arg(Arg::new("mqtt_retain")
+                    .long("mqtt-retain")
+                    .value_name("BOOL")
+                    .default_value(config::default_value("mqtt_retain"))
+                    .help("Whether the broker keeps the last published message for new subscribers, true or false"))
+        .arg(Arg::new("mqtt_client_id")
+                    .long("mqtt-client-id")
+                    .value_name("ID")
+                    .help("Client identifier sent to the MQTT broker (default: port_redirector- followed by the route name)"))
+        .arg(Arg::new("mqtt_keep_alive")
+                    .long("mqtt-keep-alive-secs")
+                    .value_name("SECONDS")
+                    .default_value(config::default_value("mqtt_keep_alive"))
+                    .help("Interval of the MQTT keep alive pings, the connection being considered lost after two without an answer"))
+        .arg(Arg::new("mqtt_reconnect")
+                    .long("mqtt-reconnect-ms")
+                    .value_name("MS")
+                    .default_value(config::default_value("mqtt_reconnect"))
+                    .help("Delay before connecting to the MQTT broker again after the connection failed or was lost"))
+        .arg(Arg::new("mqtt_command_topic")
+                    .long("mqtt-command-topic")
+                    .value_name("TOPIC")
+                    .help("Topic whose messages are written to the input with --output-mqtt"))
+        .arg(Arg::new("output_protocol")
+                    .long("output-protocol")
+                    .value_name("PROTOCOL")
+                    .default_value(config::default_value("output_protocol"))
+                    .help("Protocol spoken to the output clients: 'raw', 'rfc2217' to let them change the serial port settings (serial and PTY inputs only), 'modbus' for a Modbus TCP to Modbus RTU gateway, 'ntrip' to serve the input as an NTRIP mountpoint, or 'gpsd' for gpsd JSON reports of NMEA input"))
+        .arg(Arg::new("modbus_timeout")
+                    .long("modbus-timeout-ms")
+                    .value_name("MS")
+                    .default_value(config::default_value("modbus_timeout"))
+                    .help("How long a Modbus device is given to answer a request with --output-protocol modbus"))
+        .arg(Arg::new("ntrip_mountpoint")
+                    .long("ntrip-mountpoint")
+                    .value_name("MOUNTPOINT")
+                    .required_if_eq("output_protocol", "ntrip")
+                    .help("Mountpoint the rovers request with --output-protocol ntrip"))
+        .arg(Arg::new("ntrip_credentials")
+                    .long("ntrip-credentials")
+                    .value_name("USER:PASSWORD")
+                    .help("Credentials the rovers must send with --output-protocol ntrip (default: none required)"))
+        .arg(Arg::new("response_routing")
+                    .long("response-routing")
+                    .value_name("ROUTING")
+                    .default_value(config::default_value("response_routing"))
+                    .help("Where the input's response to a client command goes: 'broadcast' to every client, or only to the 'requester'"))
+        .arg(Arg::new("response_terminator")
+                    .long("response-terminator")
+                    .value_name("TERMINATOR")
+                    .default_value(config::default_value("response_terminator"))
+                    .help("End of a response with --response-routing requester, \\n, \\r, \\t are unescaped and an empty terminator leaves the timeout to end it"))
+        .arg(Arg::new("response_timeout")
+                    .long("response-timeout-ms")
+                    .value_name("MS")
+                    .default_value(config::default_value("response_timeout"))
+                    .help("How long a response may take with --response-routing requester, what was received by then being the response"))
+        .arg(Arg::new("socket_mode")
+                    .long("socket-mode")
+                    .value_name("MODE")
+                    .help("Octal permissions of the Unix socket files and PTY devices created, e.g. 660 (default from the umask)"))
+        .arg(Arg::new("socket_owner")
+                    .long("socket-owner")
+                    .value_name("USER")
+                    .help("Owner of the Unix socket files and PTY devices created, as a name or id"))
+        .arg(Arg::new("socket_group")
+                    .long("socket-group")
+                    .value_name("GROUP")
+                    .help("Group of the Unix socket files and PTY devices created, as a name or id"))
+        .arg(Arg::new("channel_capacity")
+                    .long("channel-capacity")
+                    .value_name("MESSAGES")
+                    .default_value(config::default_value("channel_capacity"))
+                    .help("How many input chunks output clients can fall behind before they lag"))
+        .arg(Arg::new("lag_policy")
+                    .long("lag-policy")
+                    .value_name("POLICY")
+                    .default_value(config::default_value("lag_policy"))
+                    .help("What to do when an output client lags: 'skip' the missed data, 'disconnect' the client, or send a gap 'marker'"))
+        .arg(Arg::new("gap_marker")
+                    .long("gap-marker")
+                    .value_name("MARKER")
+                    .help("Marker sent to lagging clients with --lag-policy marker, '{skipped}' is replaced by the number of missed chunks and \\n, \\r, \\t are unescaped (default '[{skipped} messages skipped]\\n')"))
+        .arg(Arg::new("slow_client_policy")
+                    .long("slow-client-policy")
+                    .value_name("POLICY")
+                    .default_value(config::default_value("slow_client_policy"))
+                    .help("What to do when an output client's queue goes over the limits: 'disconnect', 'drop-oldest', 'drop-newest' or 'coalesce'"))
+        .arg(Arg::new("max_pending_messages")
+                    .long("max-pending-messages")
+                    .value_name("CHUNKS")
+                    .default_value(config::default_value("max_pending_messages"))
+                    .help("Most chunks queued for a single output client"))
+        .arg(Arg::new("max_pending_bytes")
+                    .long("max-pending-bytes")
+                    .value_name("BYTES")
+                    .default_value(config::default_value("max_pending_bytes"))
+                    .help("Most bytes queued for a single output client"))
+        .arg(Arg::new("write_timeout")
+                    .long("write-timeout-ms")
+                    .value_name("MS")
+                    .default_value(config::default_value("write_timeout"))
+                    .help("How long a write to an output client may make no progress before it counts as a timeout"))
+        .arg(Arg::new("max_write_timeouts")
+                    .long("max-write-timeouts")
+                    .value_name("COUNT")
+                    .default_value(config::default_value("max_write_timeouts"))
+                    .help("Consecutive write timeouts after which an output client is disconnected"))
+        .arg(Arg::new("drain_timeout")
+                    .long("drain-timeout-ms")
+                    .value_name("MILLISECONDS")
+                    .default_value(config::default_value("drain_timeout"))
+                    .help("Time given on shutdown to send the data already queued for the output clients"))
+        .arg(Arg::new("no_subscriber_policy")
+                    .long("no-subscriber-policy")
+                    .value_name("POLICY")
+                    .default_value(config::default_value("no_subscriber_policy"))
+                    .help("What to do with input data while no output client is connected: 'discard' it, 'buffer' the latest --history-size chunks for the next client, or 'block' reading"))
+        .arg(Arg::new("history_size")
+                    .long("history-size")
+                    .value_name("CHUNKS")
+                    .default_value(config::default_value("history_size"))
+                    .help("How many chunks --no-subscriber-policy buffer keeps"))
+        .arg(Arg::new("name")
+                    .short('n')
+                    .long("name")
+                    .value_name("NAME")
+                    .help("Route name used in the logs (default is derived from the input type and port)"))
+        .arg(Arg::new("log_level")
+                    .long("log-level")
+                    .value_name("LEVEL")
+                    .help("Log verbosity or filter directive, e.g. 'debug' (default RUST_LOG, or 'info')"))
+        .arg(Arg::new("log_format")
+                    .long("log-format")
+                    .value_name("FORMAT")
+                    .default_value("text")
+                    .help("Log output format: 'text' or 'json'"))
+        .arg(Arg::new("log_file")
+                    .long("log-file")
+                    .value_name("PATH")
+                    .help("Also append the logs to this file"))
+        .arg(Arg::new("syslog")
+                    .long("syslog")
+                    .value_name("SOCKET")
+                    .num_args(0..=1)
+                    .default_missing_value("/dev/log")
+                    .help("Also send the logs to syslog (default socket /dev/log)"))
+        .arg(Arg::new("metrics_port")
+                    .long("metrics-port")
+                    .value_name("PORT")
+                    .help("Serve Prometheus metrics on http://0.0.0.0:<PORT>/metrics"))
+        .arg(Arg::new("admin")
+                    .long("admin")
+                    .value_name("ADDRESS")
+                    .help("Listen for admin commands on this address, e.g. 127.0.0.1:9000 (unauthenticated, keep it local)"))
+        .arg(Arg::new("watchdog_idle")
+                    .long("watchdog-idle-secs")
+                    .value_name("SECONDS")
+                    .help("Stop the systemd watchdog pings while an input received nothing for this long (default: only failed routes stop them)"))
+}
+
+/// Set up the logging described by the command line.
+fn init_logging(matches: &ArgMatches) -> Result<()> {
+    let log_config = LogConfig {
+        level: matches.get_one::<String>("log_level").cloned(),
+        format: arg(matches, "log_format")?
+            .parse::<LogFormat>()
+            .map_err(|e| Error::Config(e.to_string()))?,
+        log_file: matches.get_one::<String>("log_file").cloned(),
+        syslog: matches.get_one::<String>("syslog").cloned(),
+    };
+    logging::init(&log_config).map_err(|e| Error::Config(e.to_string()))
+}
+
+async fn run(matches: ArgMatches, listeners: Vec<std::net::TcpListener>) -> Result<()> {
+    let config_path = matches.get_one::<String>("config").cloned();
+    let configs = match &config_path {
+        Some(path) => load_routes(path).await?,
+        None => vec![cli_route(&matches)?],
+    };
+
+    let watchdog_idle = match matches.get_one::<String>("watchdog_idle") {
+        Some(secs) => Some(Duration::from_secs(parse_value::<u64>("watchdog_idle", secs)?)),
+        None => None,
+    };
+
+    let routes = RouteRegistry::new();
+    let mut supervisor = Supervisor::new(routes.clone());
+    supervisor.add_listeners(listeners);
+    let summary = supervisor.apply(configs).await;
+    if config_path.is_none() {
+        // Without a configuration file, there is nothing to retry on reload.
+        if let Some((_, e)) = summary.failed.into_iter().next() {
+            return Err(e);
         }
-        "serial" => {
-            let port_name = matches.get_one::<String>("endpoint")
-                .expect("Serial port name required")
-                .to_string();
-            let baudrate = matches.get_one::<String>("baudrate")
-                .expect("Baudrate required for Serial")
-                .parse::<u32>()
-                .expect("Baudrate must be a valid u32");
-            InputSocket::Serial {port_name: port_name, baudrate: Some(baudrate), rd: None, tx: None}
+    }
+
+    if let Some(metrics_port) = matches.get_one::<String>("metrics_port") {
+        let metrics_port = parse_value::<u16>("metrics_port", metrics_port)?;
+        let mut metrics_server = MetricsServer::new(metrics_port, routes.clone()).await?;
+        tokio::spawn( async move { metrics_server.run_loop().await; });
+    }
+
+    let (reload_tx, mut reload_rx) = mpsc::channel::<ReloadRequest>(1);
+    if let Some(admin_endpoint) = matches.get_one::<String>("admin") {
+        let mut admin_server = AdminServer::new(admin_endpoint, routes.clone()).await?;
+        if config_path.is_some() {
+            admin_server.set_reload(reload_tx);
         }
-        _ =>  { 
-            let mut err_str = String::new();
-            writeln! (err_str, "Invalid parameter socket type name: {}", socket_type_name).unwrap();
-            return Err(io::Error::new(io::ErrorKind::Other, err_str ));
+        tokio::spawn( async move { admin_server.run_loop().await; });
+    }
+
+    notify(&format!("READY=1\nSTATUS={}", status(&supervisor)));
+    let mut watchdog = systemd::watchdog_interval().map(tokio::time::interval);
+
+    let mut hangup = Hangup::new()?;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let result = loop {
+        tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break Ok(());
+            },
+            _ = hangup.recv() => match &config_path {
+                Some(path) => {
+                    info!(%path, "Reloading configuration");
+                    notify("RELOADING=1");
+                    let result = reload(path, &mut supervisor).await;
+                    notify(&format!("READY=1\nSTATUS={}", status(&supervisor)));
+                    if let Err(e) = result {
+                        error!(error = %e, "Unable to reload configuration, keeping the running routes");
+                    }
+                },
+                None => warn!("Ignoring SIGHUP, no configuration file to reload"),
+            },
+            Some(reply) = reload_rx.recv() => {
+                if let Some(path) = &config_path {
+                    notify("RELOADING=1");
+                    let result = reload(path, &mut supervisor).await;
+                    notify(&format!("READY=1\nSTATUS={}", status(&supervisor)));
+                    // The admin connection may be gone by now.
+                    let _ = reply.send(result);
+                }
+            },
+            (_, e) = supervisor.next_failure() => {
+                // With a configuration file the process keeps running, the route is retried on the next reload.
+                if config_path.is_none() {
+                    break Err(e);
+                }
+                notify(&format!("STATUS={}", status(&supervisor)));
+            },
+            _ = tick(&mut watchdog) => {
+                let unhealthy = supervisor.unhealthy_routes(watchdog_idle);
+                if unhealthy.is_empty() {
+                    notify("WATCHDOG=1");
+                } else {
+                    // Letting the watchdog expire gets the service restarted.
+                    warn!(routes = ?unhealthy, "Routes are unhealthy, skipping the watchdog ping");
+                }
+            },
         }
     };
 
+    notify("STOPPING=1");
+    supervisor.shutdown().await;
+    result
+}
+
+/// The single route described by the command line arguments.
+fn cli_route(matches: &ArgMatches) -> Result<RouteConfig> {
+    let settings: HashMap<String, String> = ROUTE_KEYS.iter()
+        .filter_map(|key| Some((key.to_string(), matches.get_one::<String>(key)?.clone())))
+        .collect();
+    RouteConfig::from_settings(&settings)
+}
+
+async fn load_routes(path: &str) -> Result<Vec<RouteConfig>> {
+    let text = tokio::fs::read_to_string(path).await.map_err(|e| Error::Config(format!("{}: {}", path, e)))?;
+    config::parse_routes(&text).map_err(|e| Error::Config(format!("{}: {}", path, e)))
+}
+
+/// Read the configuration file again and apply it to the running routes.
+async fn reload(path: &str, supervisor: &mut Supervisor) -> Result<ReloadSummary> {
+    let configs = load_routes(path).await?;
+    let summary = supervisor.apply(configs).await;
+    info!(started = ?summary.started, stopped = ?summary.stopped, restarted = ?summary.restarted, updated = ?summary.updated, failed = summary.failed.len(), "Configuration reloaded");
+    Ok(summary)
+}
+
+/// Tell the service manager about a state change, if started by one. Failing to do so is not fatal.
+fn notify(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        warn!(error = %e, state, "Unable to notify the service manager");
+    }
+}
 
-    //Broadcast port for reading in data on the input port and outputting it on all broadcast channels
-    // Increased buffer size from 256 to 4096 to handle temporary network slowdowns
-    let (broadcast_from_input_tx, broadcast_from_input_rx) = broadcast::channel(4096);
+/// One line summary of the routes, shown by `systemctl status`.
+fn status(supervisor: &Supervisor) -> String {
+    let failed = supervisor.unhealthy_routes(None).len();
+    format!("{} routes running, {} failed", supervisor.len(), failed)
+}
 
-    //Mutiple producers to read data in from the server ports and output on the single output port.
-    // Increased buffer size from 256 to 4096 to handle temporary network slowdowns
-    let (tx_to_input, rx_to_input) = mpsc::channel(4096);
+/// Wait for the next watchdog ping, forever if the watchdog is not enabled.
+async fn tick(watchdog: &mut Option<Interval>) {
+    match watchdog {
+        Some(interval) => { interval.tick().await; },
+        None => std::future::pending::<()>().await,
+    }
+}
 
-    //open the socket and start the reading process.
-    let mut socket_reader = InputSocket::connect(socket_type).await?;
-    tokio::spawn( async move { socket_reader.run_loop(broadcast_from_input_tx, rx_to_input).await; });
+/// Listens for SIGHUP, the usual request to reload the configuration. Never fires on other platforms.
+struct Hangup(#[cfg(unix)] signal::unix::Signal);
 
-    // Set up server.
-    let mut retransmit_server = RetransmitServer::new(output_port, tx_to_input, broadcast_from_input_rx).await?;
-    tokio::spawn( async move { retransmit_server.run_loop().await; });
+impl Hangup {
+    fn new() -> Result<Hangup> {
+        #[cfg(unix)]
+        return Ok(Hangup(signal::unix::signal(signal::unix::SignalKind::hangup())?));
+        #[cfg(not(unix))]
+        Ok(Hangup())
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.0.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
 
-    match signal::ctrl_c().await {
-        Ok(()) => {},
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
-            // we also shut down in case of error
+/// Wait for Ctrl-C, or for SIGTERM (sent by systemd to stop the service) on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => { sigterm.recv().await; },
+            Err(err) => {
+                error!(error = %err, "Unable to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = signal::ctrl_c() => {
+            if let Err(err) = result {
+                error!(error = %err, "Unable to listen for shutdown signal");
+                // we also shut down in case of error
+            }
         },
+        _ = terminate => {},
     }
+}
+
+/// The value of an argument. Required arguments and arguments with a default are always present.
+fn arg<'a>(matches: &'a ArgMatches, name: &str) -> Result<&'a String> {
+    matches.get_one::<String>(name).ok_or_else(|| Error::Config(format!("Missing argument {}", name)))
+}
 
-    Ok(())
-}
\ No newline at end of file
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T> where T::Err: Display {
+    value.parse::<T>().map_err(|e| Error::Config(format!("Invalid {} '{}': {}", name, value, e)))
+}
@@ -0,0 +1,145 @@
+//! This module exposes the statistics of each route through a small HTTP server in the Prometheus text format.
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{debug, info, warn};
+use crate::error::{Error, Result};
+use crate::routes::RouteRegistry;
+use crate::stats::{InputStatsSnapshot, ServerStatsSnapshot};
+
+/// Wait before accepting again after an error not caused by a single connection, such as running out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Time given to a client to send its request headers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn seconds(time: Option<SystemTime>) -> f64 {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Render every running route in the Prometheus text exposition format.
+pub fn render(routes: &RouteRegistry) -> String {
+    type Getter = fn(&InputStatsSnapshot, &ServerStatsSnapshot) -> f64;
+    const SERIES: [(&str, &str, &str, Getter); 14] = [
+        ("input_bytes_total", "counter", "Bytes read from the input.", |i, _| i.bytes_in as f64),
+        ("input_messages_total", "counter", "Chunks read from the input.", |i, _| i.messages_in as f64),
+        ("output_bytes_total", "counter", "Bytes written to output clients.", |_, o| o.bytes_out as f64),
+        ("output_messages_total", "counter", "Chunks written to output clients.", |_, o| o.messages_out as f64),
+        ("connected_clients", "gauge", "Output clients currently connected.", |_, o| o.connected_clients as f64),
+        ("dropped_messages_total", "counter", "Input chunks that could not be broadcast.", |i, _| i.dropped_messages as f64),
+        ("lagged_messages_total", "counter", "Chunks skipped by lagging output clients.", |_, o| o.lagged_messages as f64),
+        ("input_reconnects_total", "counter", "Times the input connection was re-established.", |i, _| i.reconnects as f64),
+        ("input_crc_failures_total", "counter", "RTCM 3 frames discarded for an invalid CRC.", |i, _| i.crc_failures as f64),
+        ("write_back_bytes_total", "counter", "Bytes written from output clients to the input.", |i, _| i.write_back_bytes as f64),
+        ("slow_client_disconnects_total", "counter", "Output clients disconnected for being too slow.", |_, o| o.slow_client_disconnects as f64),
+        ("slow_client_dropped_messages_total", "counter", "Chunks dropped by the slow client policy.", |_, o| o.slow_client_dropped_messages as f64),
+        ("last_input_timestamp_seconds", "gauge", "Unix time of the last chunk read from the input.", |i, _| seconds(i.last_data)),
+        ("last_output_timestamp_seconds", "gauge", "Unix time of the last chunk written to an output client.", |_, o| seconds(o.last_data)),
+    ];
+
+    let snapshots: Vec<(String, InputStatsSnapshot, ServerStatsSnapshot)> = routes.list().iter()
+        .map(|r| (escape_label(&r.name), r.input_stats.snapshot(), r.server_stats.snapshot()))
+        .collect();
+    let mut out = String::new();
+    for (name, kind, help, getter) in SERIES.iter() {
+        writeln!(out, "# HELP port_redirector_{} {}", name, help).unwrap();
+        writeln!(out, "# TYPE port_redirector_{} {}", name, kind).unwrap();
+        for (route, input, output) in snapshots.iter() {
+            writeln!(out, "port_redirector_{}{{route=\"{}\"}} {}", name, route, getter(input, output)).unwrap();
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// MetricsServer
+///
+/// A minimal HTTP server answering `GET /metrics` with the statistics of every route in a `RouteRegistry`.
+///
+/// ```rust,no_run
+/// # use port_redirector::metrics::MetricsServer;
+/// # use port_redirector::routes::RouteRegistry;
+/// # async fn example() -> port_redirector::error::Result<()> {
+/// let routes = RouteRegistry::new();
+///
+/// let mut metrics_server = MetricsServer::new(9100, routes.clone()).await?;
+/// tokio::spawn( async move { metrics_server.run_loop().await; });
+/// # Ok(())
+/// # }
+/// ```
+pub struct MetricsServer {
+    server: TcpListener,
+    routes: Arc<RouteRegistry>,
+}
+
+impl MetricsServer {
+    /// Start listening for scrapes on the given port.
+    pub async fn new(port: u16, routes: Arc<RouteRegistry>) -> Result<MetricsServer> {
+        let endpoint = "0.0.0.0:".to_owned() + &port.to_string();
+        let server = TcpListener::bind(&endpoint).await.map_err(|e| Error::connection(&endpoint, e))?;
+        info!(port, "Starting metrics server");
+        Ok(MetricsServer { server, routes })
+    }
+
+    /// The main run loop, every scrape is answered on its own task.
+    ///
+    /// Errors affecting a single incoming connection are logged and skipped. After any other error accepting
+    /// connections, such as running out of file descriptors, the loop waits `ACCEPT_RETRY_DELAY` before trying again.
+    pub async fn run_loop(&mut self) {
+        loop {
+            let (socket, socket_address) = match self.server.accept().await {
+                Ok(val) => val,
+                Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted) => {
+                    warn!(error = %e, "Error accepting metrics connection");
+                    continue;
+                },
+                Err(e) => {
+                    warn!(error = %e, "Error accepting metrics connections, retrying");
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let routes = self.routes.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_request(socket, &routes).await {
+                    debug!(peer = %socket_address, error = %e, "Metrics request failed");
+                }
+            });
+        }
+    }
+}
+
+async fn serve_request(mut socket: TcpStream, routes: &RouteRegistry) -> io::Result<()> {
+    // Only the request line matters, wait for the end of the headers so the client sees a clean close. A client that
+    // does not send them in time is dropped, so idle connections do not pile up.
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    timeout(REQUEST_TIMEOUT, async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 || request.len() > 8192 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<(), io::Error>(())
+    }).await.map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(routes);
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
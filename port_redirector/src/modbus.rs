@@ -0,0 +1,385 @@
+//! This module implements a Modbus TCP to Modbus RTU gateway, so Modbus TCP clients of the output can query the devices
+//! on a serial bus.
+//!
+//! Requests are converted from MBAP framing to RTU framing with a CRC, and sent to the input one at a time, as an RTU
+//! bus has a single master. The response is checked and returned only to the client that sent the request, with its
+//! transaction identifier. A device that does not answer in time is reported to the client with a gateway exception.
+use std::io;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// Length of the MBAP header: transaction identifier, protocol identifier, length and unit identifier.
+const MBAP_HEADER_LENGTH: usize = 7;
+
+/// Longest PDU, the RTU frame being limited to 256 bytes with the address and CRC.
+const MAX_PDU_LENGTH: usize = 253;
+
+/// Requests waiting for the bus, over all clients.
+const MAX_QUEUED_REQUESTS: usize = 64;
+
+/// Time left to the devices after a broadcast request, which they do not answer.
+const TURNAROUND_DELAY: Duration = Duration::from_millis(100);
+
+/// Silence marking the end of a response whose length cannot be known from its function code.
+const RESPONSE_GAP: Duration = Duration::from_millis(20);
+
+/// Exception returned when the bus cannot be reached.
+const GATEWAY_PATH_UNAVAILABLE: u8 = 0x0A;
+
+/// Exception returned when the device does not answer before the response timeout.
+const GATEWAY_TARGET_FAILED: u8 = 0x0B;
+
+/// Exception returned to read-only clients for requests that change the device.
+const ILLEGAL_FUNCTION: u8 = 0x01;
+
+/// Function codes that only read from the device, the only ones read-only clients can send.
+const READ_FUNCTIONS: [u8; 10] = [0x01, 0x02, 0x03, 0x04, 0x07, 0x0B, 0x0C, 0x11, 0x14, 0x18];
+
+/// The Modbus CRC-16 of `data`, sent least significant byte first at the end of an RTU frame.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for &byte in data {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Whether `frame` ends with a valid CRC.
+fn crc_valid(frame: &[u8]) -> bool {
+    frame.len() > 2 && crc16(&frame[..frame.len() - 2]).to_le_bytes() == frame[frame.len() - 2..]
+}
+
+/// A Modbus TCP request received from a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModbusRequest {
+    pub transaction_id: u16,
+    pub unit_id: u8,
+    /// The function code and its data.
+    pub pdu: Bytes,
+}
+
+impl ModbusRequest {
+    /// Take the next complete request out of the data received from a client, or return `None` if more data is needed.
+    ///
+    /// This will return an error if the data is not Modbus TCP, in which case the client should be disconnected.
+    pub fn decode(buf: &mut BytesMut) -> io::Result<Option<ModbusRequest>> {
+        if buf.len() < MBAP_HEADER_LENGTH {
+            return Ok(None);
+        }
+        let protocol_id = u16::from_be_bytes([buf[2], buf[3]]);
+        let length = usize::from(u16::from_be_bytes([buf[4], buf[5]]));
+        if protocol_id != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Modbus protocol identifier {}", protocol_id)));
+        }
+        if !(2..=MAX_PDU_LENGTH + 1).contains(&length) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Modbus request length {}", length)));
+        }
+        if buf.len() < MBAP_HEADER_LENGTH - 1 + length {
+            return Ok(None);
+        }
+        let transaction_id = buf.get_u16();
+        buf.advance(4);
+        let unit_id = buf.get_u8();
+        let pdu = buf.split_to(length - 1).freeze();
+        Ok(Some(ModbusRequest { transaction_id, unit_id, pdu }))
+    }
+
+    /// The function code of the request.
+    pub fn function(&self) -> u8 {
+        self.pdu[0]
+    }
+
+    /// Whether the request only reads from the device.
+    pub fn is_read(&self) -> bool {
+        READ_FUNCTIONS.contains(&self.function())
+    }
+
+    /// The request as an RTU frame: the unit identifier as the device address, the PDU and the CRC.
+    pub fn to_rtu(&self) -> Bytes {
+        let mut frame = BytesMut::with_capacity(self.pdu.len() + 3);
+        frame.put_u8(self.unit_id);
+        frame.put_slice(&self.pdu);
+        frame.put_u16_le(crc16(&frame));
+        frame.freeze()
+    }
+
+    /// The Modbus TCP response to this request carrying `pdu`.
+    pub fn response(&self, pdu: &[u8]) -> Bytes {
+        let mut frame = BytesMut::with_capacity(MBAP_HEADER_LENGTH + pdu.len());
+        frame.put_u16(self.transaction_id);
+        frame.put_u16(0);
+        frame.put_u16(pdu.len() as u16 + 1);
+        frame.put_u8(self.unit_id);
+        frame.put_slice(pdu);
+        frame.freeze()
+    }
+
+    /// The Modbus TCP exception response to this request.
+    pub fn exception(&self, code: u8) -> Bytes {
+        self.response(&[self.function() | 0x80, code])
+    }
+}
+
+/// Length of the RTU response starting `frame`, or `None` if it is not known yet or cannot be known from the function
+/// code.
+fn response_length(frame: &[u8]) -> Option<usize> {
+    let function = *frame.get(1)?;
+    match function {
+        // Address, exception function, exception code and CRC.
+        f if f & 0x80 != 0 => Some(5),
+        // A byte count follows the function code.
+        0x01 | 0x02 | 0x03 | 0x04 | 0x0C | 0x11 | 0x14 | 0x15 | 0x17 => Some(3 + usize::from(*frame.get(2)?) + 2),
+        0x07 => Some(5),
+        0x05 | 0x06 | 0x08 | 0x0B | 0x0F | 0x10 => Some(8),
+        0x16 => Some(10),
+        // Two byte count.
+        0x18 => Some(4 + usize::from(u16::from_be_bytes([*frame.get(2)?, *frame.get(3)?])) + 2),
+        _ => None,
+    }
+}
+
+/// A request waiting for the bus, and where to send its response.
+#[derive(Debug)]
+struct Transaction {
+    request: ModbusRequest,
+    reply: mpsc::Sender<Bytes>,
+}
+
+/// Handle used by the client tasks to queue requests on a `ModbusBus`.
+#[derive(Clone, Debug)]
+pub struct ModbusHandle {
+    requests: mpsc::Sender<Transaction>,
+}
+
+impl ModbusHandle {
+    /// Queue `request`, its Modbus TCP response being sent to `reply`. Returns false if the bus is stopped.
+    pub async fn submit(&self, request: ModbusRequest, reply: mpsc::Sender<Bytes>) -> bool {
+        self.requests.send(Transaction { request, reply }).await.is_ok()
+    }
+}
+
+/// The RTU master side of the gateway, sending the queued requests to the input one at a time.
+pub struct ModbusBus {
+    requests_rx: mpsc::Receiver<Transaction>,
+    requests_tx: mpsc::Sender<Transaction>,
+    tx_to_input: mpsc::Sender<Bytes>,
+    broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    response_timeout: Duration,
+}
+
+impl ModbusBus {
+    /// Create a bus writing the requests to `tx_to_input` and reading the responses from `broadcast_from_input_tx`.
+    pub fn new(tx_to_input: mpsc::Sender<Bytes>, broadcast_from_input_tx: broadcast::Sender<Bytes>, response_timeout: Duration) -> ModbusBus {
+        let (requests_tx, requests_rx) = mpsc::channel(MAX_QUEUED_REQUESTS);
+        ModbusBus { requests_rx, requests_tx, tx_to_input, broadcast_from_input_tx, response_timeout }
+    }
+
+    /// Returns the handle used to queue requests.
+    pub fn handle(&self) -> ModbusHandle {
+        ModbusHandle { requests: self.requests_tx.clone() }
+    }
+
+    /// The main run loop, handling the queued requests in order until `shutdown` is cancelled.
+    pub async fn run_loop(mut self, shutdown: CancellationToken) {
+        loop {
+            let transaction = tokio::select! {
+                Some(transaction) = self.requests_rx.recv() => transaction,
+                _ = shutdown.cancelled() => break,
+            };
+            if transaction.reply.is_closed() {
+                continue;
+            }
+            let response = tokio::select! {
+                response = self.transact(&transaction.request) => response,
+                _ = shutdown.cancelled() => break,
+            };
+            if let Some(response) = response {
+                // Waiting for a client that stopped reading would hold up the other clients. The client may also have
+                // disconnected in the meantime.
+                if let Err(mpsc::error::TrySendError::Full(_)) = transaction.reply.try_send(response) {
+                    warn!(transaction = transaction.request.transaction_id, "Modbus client not reading its responses, dropping one");
+                }
+            }
+        }
+    }
+
+    /// Send a request on the bus and wait for its response, returning the Modbus TCP response to send to the client.
+    async fn transact(&self, request: &ModbusRequest) -> Option<Bytes> {
+        // Subscribing now leaves out whatever the input received before the request.
+        let mut rx_from_input = self.broadcast_from_input_tx.subscribe();
+        debug!(transaction = request.transaction_id, unit = request.unit_id, function = request.function(), "Sending Modbus request");
+        if self.tx_to_input.send(request.to_rtu()).await.is_err() {
+            return Some(request.exception(GATEWAY_PATH_UNAVAILABLE));
+        }
+        if request.unit_id == 0 {
+            sleep(TURNAROUND_DELAY).await;
+            return None;
+        }
+
+        let deadline = Instant::now() + self.response_timeout;
+        let mut frame = BytesMut::new();
+        loop {
+            // A response of unknown length is complete once its CRC is valid and the device stops sending.
+            let candidate = frame.len() >= 4 && response_length(&frame).is_none() && crc_valid(&frame);
+            let wait_until = if candidate { deadline.min(Instant::now() + RESPONSE_GAP) } else { deadline };
+            match timeout_at(wait_until, rx_from_input.recv()).await {
+                Ok(Ok(data)) => frame.extend_from_slice(&data),
+                Ok(Err(broadcast::error::RecvError::Lagged(skipped))) => {
+                    warn!(skipped, "Modbus gateway lagged behind the input, discarding the partial response");
+                    frame.clear();
+                    continue;
+                },
+                Ok(Err(broadcast::error::RecvError::Closed)) => return Some(request.exception(GATEWAY_PATH_UNAVAILABLE)),
+                Err(_) if candidate => {
+                    if let Some(response) = self.accept(request, &frame) {
+                        return Some(response);
+                    }
+                    frame.clear();
+                    continue;
+                },
+                Err(_) => {
+                    warn!(transaction = request.transaction_id, unit = request.unit_id, received = frame.len(), "Modbus device did not answer in time");
+                    return Some(request.exception(GATEWAY_TARGET_FAILED));
+                },
+            }
+
+            while let Some(length) = response_length(&frame).filter(|length| frame.len() >= *length) {
+                let response = frame.split_to(length);
+                if let Some(response) = self.accept(request, &response) {
+                    return Some(response);
+                }
+            }
+        }
+    }
+
+    /// Check that `frame` is a valid response to `request`, converting it to Modbus TCP.
+    fn accept(&self, request: &ModbusRequest, frame: &[u8]) -> Option<Bytes> {
+        if !crc_valid(frame) {
+            warn!(bytes = frame.len(), "Discarding Modbus response with an invalid CRC");
+            return None;
+        }
+        if frame[0] != request.unit_id || frame[1] & 0x7F != request.function() {
+            warn!(unit = frame[0], function = frame[1], "Discarding Modbus response to another request");
+            return None;
+        }
+        Some(request.response(&frame[1..frame.len() - 2]))
+    }
+}
+
+/// Answer to a request a read-only client is not allowed to send, or `None` if it is allowed.
+pub fn refuse(request: &ModbusRequest, write_allowed: bool) -> Option<Bytes> {
+    (!write_allowed && !request.is_read()).then(|| request.exception(ILLEGAL_FUNCTION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(transaction_id: u16, unit_id: u8, pdu: &'static [u8]) -> ModbusRequest {
+        ModbusRequest { transaction_id, unit_id, pdu: Bytes::from_static(pdu) }
+    }
+
+    #[test]
+    fn crc16_matches_known_frames() {
+        // Examples of the Modbus over serial line specification and of common device manuals.
+        assert_eq!(crc16(&[0x02, 0x07]).to_le_bytes(), [0x41, 0x12]);
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]).to_le_bytes(), [0xC5, 0xCD]);
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]).to_le_bytes(), [0x84, 0x0A]);
+        assert!(crc_valid(&[0x01, 0x83, 0x02, 0xC0, 0xF1]));
+        assert!(!crc_valid(&[0x01, 0x83, 0x02, 0xC0, 0xF2]));
+    }
+
+    #[test]
+    fn decode_waits_for_a_whole_request() {
+        let frame = [0x00, 0x2A, 0x00, 0x00, 0x00, 0x06, 0x01, 0x03, 0x00, 0x00, 0x00, 0x0A];
+        let mut buf = BytesMut::from(&frame[..5]);
+        assert_eq!(ModbusRequest::decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&frame[5..11]);
+        assert_eq!(ModbusRequest::decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&frame[11..]);
+        buf.extend_from_slice(&[0x00]);
+        assert_eq!(ModbusRequest::decode(&mut buf).unwrap(), Some(request(0x2A, 1, &[0x03, 0x00, 0x00, 0x00, 0x0A])));
+        assert_eq!(&buf[..], &[0x00]);
+    }
+
+    #[test]
+    fn decode_rejects_other_protocols_and_lengths() {
+        let mut buf = BytesMut::from(&[0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x01][..]);
+        assert!(ModbusRequest::decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&[0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x01][..]);
+        assert!(ModbusRequest::decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01][..]);
+        assert!(ModbusRequest::decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn requests_convert_between_mbap_and_rtu() {
+        let read = request(7, 1, &[0x03, 0x00, 0x00, 0x00, 0x0A]);
+        assert_eq!(&read.to_rtu()[..], &[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]);
+        assert_eq!(&read.response(&[0x03, 0x02, 0x12, 0x34])[..], &[0x00, 0x07, 0x00, 0x00, 0x00, 0x05, 0x01, 0x03, 0x02, 0x12, 0x34]);
+        assert_eq!(&read.exception(GATEWAY_TARGET_FAILED)[..], &[0x00, 0x07, 0x00, 0x00, 0x00, 0x03, 0x01, 0x83, 0x0B]);
+    }
+
+    #[test]
+    fn response_length_follows_the_function_code() {
+        assert_eq!(response_length(&[0x01]), None);
+        assert_eq!(response_length(&[0x01, 0x83]), Some(5));
+        assert_eq!(response_length(&[0x01, 0x03]), None);
+        assert_eq!(response_length(&[0x01, 0x03, 0x04]), Some(9));
+        assert_eq!(response_length(&[0x01, 0x06]), Some(8));
+        assert_eq!(response_length(&[0x01, 0x10]), Some(8));
+        assert_eq!(response_length(&[0x01, 0x16]), Some(10));
+        assert_eq!(response_length(&[0x01, 0x18, 0x00, 0x06]), Some(12));
+        assert_eq!(response_length(&[0x01, 0x2B]), None);
+    }
+
+    #[test]
+    fn read_only_clients_can_only_read() {
+        let read = request(1, 1, &[0x03, 0x00, 0x00, 0x00, 0x01]);
+        let write = request(2, 1, &[0x06, 0x00, 0x01, 0x00, 0x03]);
+        assert_eq!(refuse(&read, false), None);
+        assert_eq!(refuse(&write, true), None);
+        assert_eq!(refuse(&write, false).as_deref(), Some(&[0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x01, 0x86, 0x01][..]));
+    }
+
+    #[tokio::test]
+    async fn bus_returns_the_device_response_or_an_exception() {
+        let (tx_to_input, mut rx_to_input) = mpsc::channel(4);
+        let (input_tx, _) = broadcast::channel(16);
+        let bus = ModbusBus::new(tx_to_input, input_tx.clone(), Duration::from_millis(200));
+        let handle = bus.handle();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(bus.run_loop(shutdown.clone()));
+        let (reply_tx, mut reply_rx) = mpsc::channel(4);
+
+        // The response arrives in pieces, behind an exception from the device.
+        let read = request(9, 1, &[0x03, 0x00, 0x00, 0x00, 0x01]);
+        assert!(handle.submit(read, reply_tx.clone()).await);
+        assert_eq!(&rx_to_input.recv().await.unwrap()[..], &[0x01, 0x03, 0x00, 0x00, 0x00, 0x01, 0x84, 0x0A]);
+        let mut response = vec![0x01, 0x03, 0x02, 0x12, 0x34];
+        response.extend_from_slice(&crc16(&response).to_le_bytes());
+        input_tx.send(Bytes::copy_from_slice(&response[..3])).unwrap();
+        input_tx.send(Bytes::copy_from_slice(&response[3..])).unwrap();
+        assert_eq!(&reply_rx.recv().await.unwrap()[..], &[0x00, 0x09, 0x00, 0x00, 0x00, 0x05, 0x01, 0x03, 0x02, 0x12, 0x34]);
+
+        // The device answers with an exception.
+        let write = request(10, 1, &[0x06, 0x00, 0x01, 0x00, 0x03]);
+        assert!(handle.submit(write, reply_tx.clone()).await);
+        rx_to_input.recv().await.unwrap();
+        input_tx.send(Bytes::from_static(&[0x01, 0x86, 0x02, 0xC3, 0xA1])).unwrap();
+        assert_eq!(&reply_rx.recv().await.unwrap()[..], &[0x00, 0x0A, 0x00, 0x00, 0x00, 0x03, 0x01, 0x86, 0x02]);
+
+        // The device does not answer.
+        let silent = request(11, 2, &[0x03, 0x00, 0x00, 0x00, 0x01]);
+        assert!(handle.submit(silent, reply_tx).await);
+        rx_to_input.recv().await.unwrap();
+        assert_eq!(&reply_rx.recv().await.unwrap()[..], &[0x00, 0x0B, 0x00, 0x00, 0x00, 0x03, 0x02, 0x83, GATEWAY_TARGET_FAILED]);
+        shutdown.cancel();
+    }
+}
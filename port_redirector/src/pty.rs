@@ -0,0 +1,423 @@
+//! This module creates pseudo-terminals (PTYs), so programs that only know how to open a serial device can read from and
+//! write to a route. The slave side of each PTY is published at a stable path through a symlink, as the `/dev/pts`
+//! device changes every time a PTY is created.
+use std::ffi::OsString;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, timeout, Duration, Instant, Sleep};
+use tokio_serial::{SerialPort, SerialStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+use crate::error::{Error, Result};
+use crate::retransmit_server::{ClientControl, ServerControl, DEFAULT_DRAIN_TIMEOUT};
+use crate::rtcm::RtcmFilter;
+use crate::stats::{ClientStats, ServerStats};
+use crate::unix_socket::{apply_permissions, SocketPermissions};
+
+/// How often a PTY is checked for a program opening it.
+const OPEN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Space reserved for every read from the PTY.
+const READ_CAPACITY: usize = 4096;
+
+/// Create a PTY, returning its master side and the path of its slave device. The slave device is left closed for
+/// another program to open, in raw mode and with the given permissions.
+pub fn open(permissions: &SocketPermissions) -> io::Result<(SerialStream, String)> {
+    let (master, slave) = SerialStream::pair()?;
+    let path = slave.name().ok_or_else(|| io::Error::other("The PTY has no slave device path."))?;
+    drop(slave);
+    apply_permissions(Path::new(&path), permissions)?;
+    Ok((master, path))
+}
+
+/// Point the symlink `link` to `target`, atomically replacing the previous symlink. Anything else found at `link` is
+/// left alone and reported as an error.
+pub fn publish(link: &Path, target: &str) -> io::Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(link) {
+        if !metadata.file_type().is_symlink() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the path exists and is not a symlink"));
+        }
+    }
+    let mut temporary = OsString::from(link.as_os_str());
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    let temporary = PathBuf::from(temporary);
+    let _ = fs::remove_file(&temporary);
+    std::os::unix::fs::symlink(target, &temporary)?;
+    fs::rename(&temporary, link)
+}
+
+/// Remove the symlink `link` if it still points to `target`.
+pub fn unpublish(link: &Path, target: &str) {
+    if fs::read_link(link).is_ok_and(|current| current == Path::new(target)) {
+        if let Err(e) = fs::remove_file(link) {
+            warn!(link = %link.display(), error = %e, "Unable to remove PTY symlink");
+        }
+    }
+}
+
+/// Wait for a program to open the slave side of the PTY, returning what it already wrote.
+///
+/// Reading the master side fails while the slave is not open, so it is polled until the read blocks or returns data.
+pub async fn wait_for_open(master: &mut SerialStream) -> io::Result<Bytes> {
+    let mut buf = [0; READ_CAPACITY];
+    loop {
+        match master.try_read(&mut buf) {
+            Ok(n) => return Ok(Bytes::copy_from_slice(&buf[..n])),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Bytes::new()),
+            Err(_) => sleep(OPEN_POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// What happened on a `PtyDevice`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PtyEvent {
+    /// A program opened the PTY. What it already wrote was read.
+    Opened,
+    /// The program wrote data.
+    Data,
+    /// The program closed the PTY, which was replaced by a new one.
+    Closed,
+}
+
+/// A PTY published at a symlink path, for a program to open as a serial device.
+///
+/// When the program closes the PTY, it is replaced by a new one and the symlink updated, so the next program starts
+/// from a clean terminal. The symlink is removed when the device is dropped.
+pub struct PtyDevice {
+    link: PathBuf,
+    permissions: SocketPermissions,
+    master: SerialStream,
+    slave: String,
+    opened: bool,
+    /// When the PTY is next checked for a program opening it.
+    retry: Pin<Box<Sleep>>,
+}
+
+impl PtyDevice {
+    /// Create a PTY and publish it at `link`, so it can be opened as soon as this returns.
+    pub fn create(link: impl AsRef<Path>, permissions: &SocketPermissions) -> io::Result<PtyDevice> {
+        let link = link.as_ref().to_path_buf();
+        let (master, slave) = open(permissions)?;
+        publish(&link, &slave)?;
+        info!(link = %link.display(), pty = %slave, "PTY input ready");
+        Ok(PtyDevice {
+            link,
+            permissions: permissions.clone(),
+            master,
+            slave,
+            opened: false,
+            retry: Box::pin(sleep(Duration::ZERO)),
+        })
+    }
+
+    /// The slave device the symlink points to.
+    pub fn slave(&self) -> &str {
+        &self.slave
+    }
+
+    /// Whether a program has the PTY open.
+    pub fn is_open(&self) -> bool {
+        self.opened
+    }
+
+    /// The master side of the PTY, where the line settings of the terminal can be changed.
+    pub fn master_mut(&mut self) -> &mut SerialStream {
+        &mut self.master
+    }
+
+    /// Replace the PTY by a new one and point the symlink to it.
+    fn replace(&mut self) -> io::Result<()> {
+        let (master, slave) = open(&self.permissions)?;
+        publish(&self.link, &slave)?;
+        info!(link = %self.link.display(), pty = %slave, "PTY input replaced");
+        self.master = master;
+        self.slave = slave;
+        self.opened = false;
+        Ok(())
+    }
+
+    /// Read what the program writes to the PTY into `buf`, waiting for a program to open it first.
+    pub fn poll_event(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<PtyEvent>> {
+        while !self.opened {
+            // Reading the master side fails while the slave is not open, see `wait_for_open`.
+            match self.master.try_read(buf.initialize_unfilled()) {
+                Ok(n) => buf.advance(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(_) => {
+                    ready!(self.retry.as_mut().poll(cx));
+                    self.retry.as_mut().reset(Instant::now() + OPEN_POLL_INTERVAL);
+                    continue;
+                },
+            }
+            self.opened = true;
+            info!(pty = %self.slave, "Program opened the PTY input");
+            return Poll::Ready(Ok(PtyEvent::Opened));
+        }
+
+        let filled = buf.filled().len();
+        match ready!(Pin::new(&mut self.master).poll_read(cx, buf)) {
+            Ok(()) if buf.filled().len() > filled => Poll::Ready(Ok(PtyEvent::Data)),
+            // Reading fails once the program closed the PTY.
+            _ => {
+                info!(pty = %self.slave, "Program closed the PTY input");
+                self.replace()?;
+                Poll::Ready(Ok(PtyEvent::Closed))
+            },
+        }
+    }
+
+    /// Wait for the next event, reading what the program writes into `buf`.
+    pub async fn read_event(&mut self, buf: &mut BytesMut) -> io::Result<PtyEvent> {
+        let mut chunk = [0; READ_CAPACITY];
+        let mut chunk = ReadBuf::new(&mut chunk);
+        let event = std::future::poll_fn(|cx| self.poll_event(cx, &mut chunk)).await?;
+        buf.extend_from_slice(chunk.filled());
+        Ok(event)
+    }
+}
+
+/// Data written while no program has the PTY open is discarded.
+impl AsyncWrite for PtyDevice {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let device = self.get_mut();
+        if !device.opened {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        Pin::new(&mut device.master).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().master).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().master).poll_shutdown(cx)
+    }
+}
+
+impl Drop for PtyDevice {
+    fn drop(&mut self) {
+        unpublish(&self.link, &self.slave);
+    }
+}
+
+/// How serving a program ended.
+enum Served {
+    /// The program closed the PTY, or was kicked.
+    Closed,
+    Shutdown,
+}
+
+/// Output that retransmits the input to a program through a PTY.
+///
+/// The PTY is published at the symlink path given to `new`. Whatever the program writes to it goes back to the input.
+/// When the program closes the PTY, it is replaced by a new one so the next program starts from a clean terminal.
+///
+/// The program shows up as a client in the statistics and can be kicked through the `ServerControl` handle, which
+/// also replaces the PTY. Data is written to the PTY as fast as the program reads it, and skipped when it falls too far
+/// behind the broadcast channel.
+pub struct PtyOutput {
+    link: PathBuf,
+    permissions: SocketPermissions,
+    /// The PTY waiting for a program, and its slave device path.
+    pty: Option<(SerialStream, String)>,
+    /// The slave device the symlink points to.
+    published: Option<String>,
+    tx_to_input: mpsc::Sender<Bytes>,
+    broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    stats: ServerStats,
+    control: ServerControl,
+    drain_timeout: Duration,
+    rtcm_filter: Option<RtcmFilter>,
+}
+
+impl PtyOutput {
+    /// Create the first PTY and publish it at `link`, so it can be opened as soon as this returns.
+    pub fn new(
+        link: impl AsRef<Path>,
+        permissions: &SocketPermissions,
+        tx_to_input: mpsc::Sender<Bytes>,
+        broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    ) -> Result<PtyOutput> {
+        let mut output = PtyOutput {
+            link: link.as_ref().to_path_buf(),
+            permissions: permissions.clone(),
+            pty: None,
+            published: None,
+            tx_to_input,
+            broadcast_from_input_tx,
+            stats: ServerStats::new(),
+            control: ServerControl::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            rtcm_filter: None,
+        };
+        output.pty = Some(output.create()?);
+        Ok(output)
+    }
+
+    /// Returns the statistics handle of this output, the program using the PTY counting as a client.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// Returns the handle used to kick the program using the PTY or change its write permission.
+    pub fn control(&self) -> ServerControl {
+        self.control.clone()
+    }
+
+    /// Set how long the program is given on shutdown to read the data already broadcast. The default is
+    /// `DEFAULT_DRAIN_TIMEOUT`.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Only send the program the RTCM 3 frames with the message types of `filter`, the input being framed with
+    /// `Framing::Rtcm3`.
+    pub fn set_rtcm_filter(&mut self, filter: RtcmFilter) {
+        self.rtcm_filter = Some(filter);
+    }
+
+    /// Whether the input data is sent to the program at all.
+    fn wants(&self, data: &[u8]) -> bool {
+        self.rtcm_filter.as_ref().is_none_or(|filter| filter.allows(data))
+    }
+
+    /// Create a PTY and point the symlink to it.
+    fn create(&mut self) -> Result<(SerialStream, String)> {
+        let endpoint = self.link.display().to_string();
+        let (master, slave) = open(&self.permissions).map_err(|e| Error::connection(&endpoint, e))?;
+        publish(&self.link, &slave).map_err(|e| Error::connection(&endpoint, e))?;
+        info!(link = %endpoint, pty = %slave, "PTY output ready");
+        self.published = Some(slave.clone());
+        Ok((master, slave))
+    }
+
+    /// The main run loop.
+    ///
+    /// Waits for a program to open the PTY and serves it until it closes the PTY, then replaces the PTY and waits for
+    /// the next program. Returns an error if a new PTY cannot be created.
+    ///
+    /// Once `shutdown` is cancelled, the program is given the drain timeout to read the data already broadcast, and
+    /// the symlink is removed.
+    pub async fn run_loop(&mut self, shutdown: CancellationToken) -> Result<()> {
+        loop {
+            let (mut master, slave) = match self.pty.take() {
+                Some(pty) => pty,
+                None => self.create()?,
+            };
+            let initial = tokio::select! {
+                result = wait_for_open(&mut master) => result?,
+                _ = shutdown.cancelled() => break,
+            };
+
+            let client_stats = self.stats.connect_client(format!("pty:{}", slave));
+            let client_control = self.control.connect_client(client_stats.id());
+            info!(pty = %slave, client = client_stats.id(), "Program opened the PTY output");
+            let served = self.serve(master, initial, &client_stats, &client_control, &shutdown).await;
+            self.control.disconnect_client(client_stats.id());
+            self.stats.disconnect_client(&client_stats);
+
+            match served {
+                Served::Closed => info!(pty = %slave, "PTY output closed, replacing it"),
+                Served::Shutdown => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Retransmit the input to the program using the PTY, and its writes to the input.
+    async fn serve(&self, master: SerialStream, initial: Bytes, client: &ClientStats, control: &ClientControl, shutdown: &CancellationToken) -> Served {
+        let (mut pty_rd, mut pty_tx) = tokio::io::split(master);
+        let mut rx_from_input = self.broadcast_from_input_tx.subscribe();
+        let mut buf = BytesMut::new();
+        let mut received = initial;
+
+        loop {
+            if !received.is_empty() {
+                self.stats.record_client_input(client, received.len());
+                if !control.write_allowed.load(std::sync::atomic::Ordering::Relaxed) {
+                    debug!(bytes = received.len(), "Discarding data from read-only PTY program");
+                } else if self.tx_to_input.send(received).await.is_err() {
+                    error!("Failed to send data from PTY program to input socket");
+                    return Served::Closed;
+                }
+                received = Bytes::new();
+            }
+            buf.reserve(READ_CAPACITY);
+
+            tokio::select! {
+                result = rx_from_input.recv() => {
+                    let data = match result {
+                        Ok(data) if self.wants(&data) => data,
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            self.stats.record_lagged(client, skipped);
+                            warn!(skipped, "PTY program lagged behind the input");
+                            continue;
+                        },
+                        Err(broadcast::error::RecvError::Closed) => {
+                            // Nothing more to send, the route is stopping.
+                            shutdown.cancelled().await;
+                            return Served::Shutdown;
+                        }
+                    };
+                    let written = tokio::select! {
+                        result = pty_tx.write_all(&data) => result,
+                        _ = control.kick.notified() => return Served::Closed,
+                        _ = shutdown.cancelled() => return Served::Shutdown,
+                    };
+                    match written {
+                        Ok(()) => self.stats.record_output(client, data.len()),
+                        Err(e) => {
+                            info!(error = %e, "PTY output closed (write error)");
+                            return Served::Closed;
+                        }
+                    }
+                },
+                result = pty_rd.read_buf(&mut buf) => {
+                    match result {
+                        Ok(n) if n > 0 => received = buf.split().freeze(),
+                        // Reading fails once the program closed the PTY.
+                        _ => return Served::Closed,
+                    }
+                },
+                _ = control.kick.notified() => {
+                    info!("PTY program kicked");
+                    return Served::Closed;
+                },
+                _ = shutdown.cancelled() => {
+                    let flushed = timeout(self.drain_timeout, async {
+                        while let Ok(data) = rx_from_input.try_recv() {
+                            if !self.wants(&data) {
+                                continue;
+                            }
+                            pty_tx.write_all(&data).await?;
+                            self.stats.record_output(client, data.len());
+                        }
+                        pty_tx.flush().await
+                    }).await;
+                    if !matches!(flushed, Ok(Ok(()))) {
+                        warn!("PTY program not flushed before the shutdown deadline");
+                    }
+                    return Served::Shutdown;
+                },
+            }
+        }
+    }
+}
+
+impl Drop for PtyOutput {
+    fn drop(&mut self) {
+        if let Some(slave) = &self.published {
+            unpublish(&self.link, slave);
+        }
+    }
+}
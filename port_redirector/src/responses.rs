@@ -0,0 +1,310 @@
+//! This module routes the responses of query-style devices to the output client that sent the query.
+//!
+//! The commands of the clients are sent to the input one at a time. After each command, the next response read from the
+//! input, up to a terminator or until a timeout, goes only to the client that sent the command. Anything else the input
+//! sends is unsolicited and broadcast to every client as usual.
+//!
+//! The data of a client is cut into commands by a `CommandSplitter`, each command ending at a line feed.
+use bytes::{Bytes, BytesMut};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep_until, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// Commands waiting for the input, over all clients.
+const MAX_QUEUED_COMMANDS: usize = 64;
+
+/// Pause of a client after which the data it sent without a line feed is taken as a whole command.
+pub const COMMAND_IDLE: Duration = Duration::from_millis(100);
+
+/// How responses are told apart from the rest of the input data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseRouting {
+    /// End of a response, included in it. When empty, a response is everything received until the timeout.
+    pub terminator: Vec<u8>,
+    /// How long the input is given to complete a response. What was received by then is the response.
+    pub timeout: Duration,
+}
+
+/// A command waiting for the input, and where to send its response.
+#[derive(Debug)]
+struct Command {
+    data: Bytes,
+    reply: mpsc::Sender<Bytes>,
+}
+
+/// Handle used by the client tasks to queue their commands on a `ResponseRouter`.
+#[derive(Clone, Debug)]
+pub struct ResponseHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl ResponseHandle {
+    /// Queue the command `data`, its response being sent to `reply`. Returns false if the router is stopped.
+    pub async fn submit(&self, data: Bytes, reply: mpsc::Sender<Bytes>) -> bool {
+        self.commands.send(Command { data, reply }).await.is_ok()
+    }
+}
+
+/// Cuts the data of a client into commands, each ending at a line feed, whatever the reads it was received in. Data
+/// without a line feed waits for the rest of its command, and is a command on its own once the client paused for
+/// `COMMAND_IDLE`, for devices whose commands are not lines.
+#[derive(Debug)]
+pub struct CommandSplitter {
+    partial: BytesMut,
+    deadline: Instant,
+}
+
+impl Default for CommandSplitter {
+    fn default() -> CommandSplitter {
+        CommandSplitter { partial: BytesMut::new(), deadline: Instant::now() }
+    }
+}
+
+impl CommandSplitter {
+    /// Add data received from the client, returning the commands it completes.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Bytes> {
+        self.partial.extend_from_slice(data);
+        self.deadline = Instant::now() + COMMAND_IDLE;
+        let mut commands = Vec::new();
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            commands.push(self.partial.split_to(end + 1).freeze());
+        }
+        commands
+    }
+
+    /// When the data waiting for a line feed is taken as a command, if there is any.
+    pub fn deadline(&self) -> Option<Instant> {
+        (!self.partial.is_empty()).then_some(self.deadline)
+    }
+
+    /// Take the data waiting for a line feed as a command.
+    pub fn flush(&mut self) -> Bytes {
+        self.partial.split().freeze()
+    }
+}
+
+/// The command currently waiting for its response.
+struct Outstanding {
+    reply: mpsc::Sender<Bytes>,
+    response: BytesMut,
+    deadline: Instant,
+}
+
+/// Sits between the input and the output clients, sending the commands to the input and splitting what it reads into
+/// responses and unsolicited data.
+pub struct ResponseRouter {
+    routing: ResponseRouting,
+    commands_rx: mpsc::Receiver<Command>,
+    commands_tx: mpsc::Sender<Command>,
+    tx_to_input: mpsc::Sender<Bytes>,
+    rx_from_input: broadcast::Receiver<Bytes>,
+    unsolicited_tx: broadcast::Sender<Bytes>,
+}
+
+impl ResponseRouter {
+    /// Create a router between the input channels and the clients, which subscribe to `unsolicited` for the data that
+    /// is not a response. The router subscribes to the input right away, so the input always has a subscriber and its
+    /// no-subscriber policy never applies.
+    pub fn new(
+        routing: ResponseRouting,
+        tx_to_input: mpsc::Sender<Bytes>,
+        broadcast_from_input_tx: &broadcast::Sender<Bytes>,
+        unsolicited_tx: broadcast::Sender<Bytes>,
+    ) -> ResponseRouter {
+        let (commands_tx, commands_rx) = mpsc::channel(MAX_QUEUED_COMMANDS);
+        ResponseRouter {
+            routing,
+            commands_rx,
+            commands_tx,
+            tx_to_input,
+            rx_from_input: broadcast_from_input_tx.subscribe(),
+            unsolicited_tx,
+        }
+    }
+
+    /// Returns the handle used to queue commands.
+    pub fn handle(&self) -> ResponseHandle {
+        ResponseHandle { commands: self.commands_tx.clone() }
+    }
+
+    /// The main run loop, until `shutdown` is cancelled or the input stops.
+    pub async fn run_loop(mut self, shutdown: CancellationToken) {
+        let mut outstanding: Option<Outstanding> = None;
+        loop {
+            let deadline = outstanding.as_ref().map_or_else(Instant::now, |o| o.deadline);
+            tokio::select! {
+                Some(command) = self.commands_rx.recv(), if outstanding.is_none() => {
+                    if command.reply.is_closed() {
+                        continue;
+                    }
+                    debug!(bytes = command.data.len(), "Sending command, routing its response to the client");
+                    if self.tx_to_input.send(command.data).await.is_err() {
+                        break;
+                    }
+                    outstanding = Some(Outstanding {
+                        reply: command.reply,
+                        response: BytesMut::new(),
+                        deadline: Instant::now() + self.routing.timeout,
+                    });
+                },
+                result = self.rx_from_input.recv() => {
+                    let data = match result {
+                        Ok(data) => data,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!(skipped, "Response router lagged behind the input");
+                            continue;
+                        },
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    let Some(current) = &mut outstanding else {
+                        // No subscribed client is not an error.
+                        let _ = self.unsolicited_tx.send(data);
+                        continue;
+                    };
+                    current.response.extend_from_slice(&data);
+                    if let Some(end) = self.terminator_end(&current.response) {
+                        let mut current = outstanding.take().unwrap();
+                        let response = current.response.split_to(end).freeze();
+                        respond(&current.reply, response);
+                        if !current.response.is_empty() {
+                            let _ = self.unsolicited_tx.send(current.response.freeze());
+                        }
+                    }
+                },
+                _ = sleep_until(deadline), if outstanding.is_some() => {
+                    let current = outstanding.take().unwrap();
+                    if current.response.is_empty() {
+                        warn!("No response to the command before the timeout");
+                    } else {
+                        if !self.routing.terminator.is_empty() {
+                            debug!(bytes = current.response.len(), "Response not terminated before the timeout");
+                        }
+                        respond(&current.reply, current.response.freeze());
+                    }
+                },
+                _ = shutdown.cancelled() => break,
+            }
+        }
+    }
+
+    /// The end of the response at the first terminator of `response`, if any.
+    fn terminator_end(&self, response: &[u8]) -> Option<usize> {
+        let terminator = &self.routing.terminator;
+        if terminator.is_empty() {
+            return None;
+        }
+        response.windows(terminator.len()).position(|window| window == terminator.as_slice()).map(|start| start + terminator.len())
+    }
+}
+
+/// Send a response to its client, without waiting for a client that stopped reading. The client may also have
+/// disconnected in the meantime.
+fn respond(reply: &mpsc::Sender<Bytes>, response: Bytes) {
+    if let Err(mpsc::error::TrySendError::Full(_)) = reply.try_send(response) {
+        warn!("Output client not reading its responses, dropping one");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    /// A running router, with the channels of the input and of the unsolicited data.
+    struct Harness {
+        handle: ResponseHandle,
+        input_tx: broadcast::Sender<Bytes>,
+        rx_to_input: mpsc::Receiver<Bytes>,
+        unsolicited_rx: broadcast::Receiver<Bytes>,
+        shutdown: CancellationToken,
+    }
+
+    fn start(terminator: &[u8], response_timeout: Duration) -> Harness {
+        let (input_tx, _) = broadcast::channel(16);
+        let (tx_to_input, rx_to_input) = mpsc::channel(16);
+        let (unsolicited_tx, unsolicited_rx) = broadcast::channel(16);
+        let routing = ResponseRouting { terminator: terminator.to_vec(), timeout: response_timeout };
+        let router = ResponseRouter::new(routing, tx_to_input, &input_tx, unsolicited_tx);
+        let handle = router.handle();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(router.run_loop(shutdown.clone()));
+        Harness { handle, input_tx, rx_to_input, unsolicited_rx, shutdown }
+    }
+
+    impl Harness {
+        /// Submit a command and wait for the router to send it to the input.
+        async fn command(&mut self, data: &'static [u8]) -> mpsc::Receiver<Bytes> {
+            let (reply_tx, reply_rx) = mpsc::channel(4);
+            assert!(self.handle.submit(Bytes::from_static(data), reply_tx).await);
+            let sent = timeout(Duration::from_secs(1), self.rx_to_input.recv()).await.unwrap().unwrap();
+            assert_eq!(&sent[..], data);
+            reply_rx
+        }
+
+        fn input(&self, data: &'static [u8]) {
+            self.input_tx.send(Bytes::from_static(data)).unwrap();
+        }
+    }
+
+    async fn next(rx: &mut mpsc::Receiver<Bytes>) -> Bytes {
+        timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn response_goes_to_the_client_that_sent_the_command() {
+        let mut harness = start(b"\n", Duration::from_secs(5));
+        let (other_tx, mut other_rx) = mpsc::channel(4);
+        let mut reply = harness.command(b"*IDN?\n").await;
+        // A second command waits for the response to the first.
+        assert!(harness.handle.submit(Bytes::from_static(b"MEAS?\n"), other_tx).await);
+        harness.input(b"ACME,");
+        harness.input(b"1.0\nNOISE");
+        assert_eq!(&next(&mut reply).await[..], b"ACME,1.0\n");
+        // What follows the terminator is unsolicited, then the second command is sent.
+        let unsolicited = timeout(Duration::from_secs(1), harness.unsolicited_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(&unsolicited[..], b"NOISE");
+        assert_eq!(&timeout(Duration::from_secs(1), harness.rx_to_input.recv()).await.unwrap().unwrap()[..], b"MEAS?\n");
+        harness.input(b"42\n");
+        assert_eq!(&next(&mut other_rx).await[..], b"42\n");
+        assert!(reply.try_recv().is_err());
+        harness.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn response_ends_at_the_timeout() {
+        let mut harness = start(b"\n", Duration::from_millis(50));
+        let mut reply = harness.command(b"DUMP\n").await;
+        harness.input(b"unterminated");
+        assert_eq!(&next(&mut reply).await[..], b"unterminated");
+
+        // Without a terminator, everything received until the timeout is the response.
+        let mut harness = start(b"", Duration::from_millis(50));
+        let mut reply = harness.command(b"DUMP\n").await;
+        harness.input(b"line 1\n");
+        harness.input(b"line 2\n");
+        assert_eq!(&next(&mut reply).await[..], b"line 1\nline 2\n");
+        harness.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn data_without_a_command_is_broadcast() {
+        let mut harness = start(b"\n", Duration::from_secs(5));
+        harness.input(b"$GPGGA\r\n");
+        let unsolicited = timeout(Duration::from_secs(1), harness.unsolicited_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(&unsolicited[..], b"$GPGGA\r\n");
+        harness.shutdown.cancel();
+    }
+
+    #[test]
+    fn splitter_cuts_commands_at_line_feeds() {
+        let mut splitter = CommandSplitter::default();
+        assert_eq!(splitter.push(b"A?\r\nB?\nC"), [Bytes::from_static(b"A?\r\n"), Bytes::from_static(b"B?\n")]);
+        assert!(splitter.deadline().is_some());
+        assert_eq!(splitter.push(b"?\n"), [Bytes::from_static(b"C?\n")]);
+        assert!(splitter.deadline().is_none());
+        assert!(splitter.push(b"\x01\x03").is_empty());
+        assert_eq!(&splitter.flush()[..], b"\x01\x03");
+        assert!(splitter.deadline().is_none());
+    }
+}
@@ -1,157 +1,1022 @@
-//! This server listens on a given port and retransmits any data to any connected clients recieved from the broadcast queue.
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+//! This server listens on a given port, or Unix socket path, and retransmits any data to any connected clients recieved
+//! from the broadcast queue.
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::{broadcast, mpsc};
-use tokio::time::{timeout, Duration};
-use std::collections::VecDeque;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, timeout, timeout_at, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use std::collections::{HashMap, VecDeque};
+use bytes::{Bytes, BytesMut};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error, info, warn, Instrument};
+use crate::error::{Error, Result};
+use crate::input_stream::InputControl;
+use crate::modbus::{self, ModbusBus, ModbusHandle, ModbusRequest};
+use crate::gpsd::{self, GpsdFeed, GpsdHandle, Watch};
+use crate::ntrip::NtripCaster;
+use crate::rtcm::RtcmFilter;
+use crate::responses::{CommandSplitter, ResponseHandle, ResponseRouter, ResponseRouting};
+use crate::rfc2217::{self, Rfc2217Session};
+use crate::stats::{ClientStats, ServerStats};
+#[cfg(unix)]
+use crate::unix_socket::{SocketPermissions, UnixSocketListener};
+
+/// What a client task does when it falls behind the broadcast channel and misses messages.
+///
+/// The number of messages a client can fall behind is the capacity of the broadcast channel given to the server.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Carry on with the oldest message still available. The skipped messages are counted and logged.
+    #[default]
+    Skip,
+    /// Disconnect the client.
+    Disconnect,
+    /// Send this marker to the client in place of the skipped messages, then carry on. `{skipped}` in the marker is
+    /// replaced by the number of skipped messages.
+    GapMarker(String),
+}
+
+/// What a client task does when its queue of data waiting to be written goes over the limits of its
+/// `SlowClientPolicy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlowClientAction {
+    /// Disconnect the client.
+    #[default]
+    Disconnect,
+    /// Drop the oldest queued chunks until the queue is back within the limits.
+    DropOldest,
+    /// Drop the newest queued chunks until the queue is back within the limits.
+    DropNewest,
+    /// Merge the queued chunks into a single one, so a client only limited by the message count catches up with fewer
+    /// writes. A client over the byte limit is disconnected.
+    Coalesce,
+}
+
+/// Limits on the data queued for a single client, and what happens when they are exceeded.
+///
+/// The chunk currently being written always counts towards the limits but is never dropped or merged, so the stream
+/// seen by the client is never cut mid-chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowClientPolicy {
+    pub action: SlowClientAction,
+    /// Most chunks queued for the client.
+    pub max_pending_messages: usize,
+    /// Most bytes queued for the client.
+    pub max_pending_bytes: usize,
+    /// How long a write may make no progress before it counts as a timeout.
+    pub write_timeout: Duration,
+    /// Consecutive write timeouts after which the client is disconnected, whatever the action.
+    pub max_write_timeouts: u32,
+}
+
+impl Default for SlowClientPolicy {
+    fn default() -> SlowClientPolicy {
+        SlowClientPolicy {
+            action: SlowClientAction::Disconnect,
+            max_pending_messages: 100,
+            max_pending_bytes: 1024 * 1024,
+            write_timeout: Duration::from_millis(5000),
+            max_write_timeouts: 10,
+        }
+    }
+}
+
+/// Per-client switches, shared between the client's task and the `ServerControl` handle.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientControl {
+    pub(crate) kick: Arc<Notify>,
+    pub(crate) write_allowed: Arc<AtomicBool>,
+}
+
+/// Handle for managing the clients connected to a `RetransmitServer` while it runs.
+///
+/// Clients are identified by the ids reported in `ServerStats`.
+#[derive(Clone, Debug, Default)]
+pub struct ServerControl {
+    clients: Arc<Mutex<HashMap<u64, ClientControl>>>,
+}
+
+impl ServerControl {
+    /// Disconnect a client. Returns false if no client has this id.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.clients.lock().unwrap().get(&id) {
+            Some(client) => {
+                client.kick.notify_one();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Allow or deny a client writing back to the input. Data sent by a client that is not allowed to write is
+    /// discarded. Returns false if no client has this id.
+    pub fn set_write_allowed(&self, id: u64, allowed: bool) -> bool {
+        match self.clients.lock().unwrap().get(&id) {
+            Some(client) => {
+                client.write_allowed.store(allowed, Ordering::Relaxed);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Whether a client may write back to the input, or `None` if no client has this id.
+    pub fn write_allowed(&self, id: u64) -> Option<bool> {
+        self.clients.lock().unwrap().get(&id).map(|client| client.write_allowed.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn connect_client(&self, id: u64) -> ClientControl {
+        let client = ClientControl { kick: Arc::new(Notify::new()), write_allowed: Arc::new(AtomicBool::new(true)) };
+        self.clients.lock().unwrap().insert(id, client.clone());
+        client
+    }
+
+    pub(crate) fn disconnect_client(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+}
 
 /// RetransmitServer
 ///
 /// This server runs a TCP server asynchronously and every client will retransmit any data sent to the
 /// tx channel and any data recieved on any socket will be sent on the rx channel.
 ///
-/// ```rust
+/// ```rust,no_run
+/// # use port_redirector::input_stream::{InputControl, InputSocket};
+/// # use port_redirector::retransmit_server::RetransmitServer;
+/// # use tokio::sync::{broadcast, mpsc, Notify};
+/// # use tokio_util::sync::CancellationToken;
+/// # async fn example(socket_type: InputSocket, output_port: u16) -> port_redirector::error::Result<()> {
+/// let shutdown = CancellationToken::new();
+///
 /// //Broadcast port for reading in data on the input port and outputting it on all broadcast channels
-/// let (broadcast_from_input_tx, broadcast_from_input_rx) = broadcast::channel(32);
+/// let (broadcast_from_input_tx, _) = broadcast::channel(32);
 ///
 /// //Mutiple producers to read data in from the server ports and output on the single output port.
 /// let (tx_to_input, rx_to_input) = mpsc::channel(32);
 ///
 /// //open the socket and start the reading process.
 /// let mut socket_reader = InputSocket::connect(socket_type).await?;
-/// tokio::spawn( async move { socket_reader.run_loop(broadcast_from_input_tx, rx_to_input).await; });
+/// let input_tx = broadcast_from_input_tx.clone();
+/// let input_shutdown = shutdown.clone();
+/// tokio::spawn( async move { socket_reader.run_loop(input_tx, rx_to_input, InputControl::new(), input_shutdown).await });
 ///
 /// // Set up server.
-/// let mut retransmit_server = RetransmitServer::new(output_port, tx_to_input, broadcast_from_input_rx).await?;
-/// tokio::spawn( async move { retransmit_server.run_loop().await; });
+/// let mut retransmit_server = RetransmitServer::new(output_port, tx_to_input, broadcast_from_input_tx).await?;
+/// let server_shutdown = shutdown.clone();
+/// let server = tokio::spawn( async move { retransmit_server.run_loop(server_shutdown).await });
 ///
+/// // Later, stop both and wait for the clients to be flushed.
+/// shutdown.cancel();
+/// server.await.unwrap()?;
+/// # Ok(())
+/// # }
 /// ```
 pub struct RetransmitServer {
-    server: TcpListener,
-    tx_to_input: mpsc::Sender<Vec<u8>>,
-    broadcast_from_input_rx: broadcast::Receiver<Vec<u8>>,
+    server: Listener,
+    tx_to_input: mpsc::Sender<Bytes>,
+    broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    stats: ServerStats,
+    control: ServerControl,
+    lag_policy: LagPolicy,
+    slow_client_policy: SlowClientPolicy,
+    drain_timeout: Duration,
+    rfc2217: Option<InputControl>,
+    /// The response timeout of the Modbus gateway, if enabled.
+    modbus: Option<Duration>,
+    /// How responses are routed to the client that sent the command, and the capacity of the channel carrying the
+    /// unsolicited data, if enabled.
+    response_routing: Option<(ResponseRouting, usize)>,
+    ntrip: Option<NtripCaster>,
+    rtcm_filter: Option<RtcmFilter>,
+    gpsd: Option<String>,
 }
 
+/// Default time given to the clients to receive their queued data on shutdown.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
 impl RetransmitServer {
     /// Create a new server that listens to messages broadcase through tx.
     /// This method start the server listening on the given port. Any connected clients will retransmit
-    /// any data sent to the tx sender (each instance subscribes to this broadcast sender).
+    /// any data sent to the tx sender (each instance subscribes to this broadcast sender). The server itself does not
+    /// hold a subscription, so the input sees no subscribers while no client is connected.
     pub async fn new(
         port: u16,
-        tx_to_input: mpsc::Sender<Vec<u8>>,
-        broadcast_from_input_rx: broadcast::Receiver<Vec<u8>>,
-    ) -> io::Result<RetransmitServer> {
-        let server = TcpListener::bind("0.0.0.0:".to_owned() + &port.to_string()).await?;
+        tx_to_input: mpsc::Sender<Bytes>,
+        broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    ) -> Result<RetransmitServer> {
+        let endpoint = "0.0.0.0:".to_owned() + &port.to_string();
+        let server = TcpListener::bind(&endpoint).await.map_err(|e| Error::connection(&endpoint, e))?;
+        Ok(RetransmitServer::from_listener(server, tx_to_input, broadcast_from_input_tx))
+    }
+
+    /// Create a server accepting clients on an already bound listener, such as one passed by socket activation.
+    /// Otherwise the same as `new`.
+    pub fn from_listener(
+        server: TcpListener,
+        tx_to_input: mpsc::Sender<Bytes>,
+        broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    ) -> RetransmitServer {
+        match server.local_addr() {
+            Ok(addr) => info!(port = addr.port(), "Starting TCP output retransmission server"),
+            Err(_) => info!("Starting TCP output retransmission server"),
+        }
+        RetransmitServer::with_listener(Listener::Tcp(server), tx_to_input, broadcast_from_input_tx)
+    }
 
-        println!(
-            "Starting TCP output retransmission server at 0.0.0.0:{}",
-            port
-        );
+    /// Create a server listening on a Unix stream socket at `path` instead of a TCP port. The socket file is created
+    /// with the given permissions, replacing a stale one, and removed when the server is dropped. Otherwise the same
+    /// as `new`.
+    #[cfg(unix)]
+    pub async fn new_unix(
+        path: impl AsRef<Path>,
+        permissions: &SocketPermissions,
+        tx_to_input: mpsc::Sender<Bytes>,
+        broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    ) -> Result<RetransmitServer> {
+        let path = path.as_ref();
+        let server = UnixSocketListener::bind(path, permissions).await.map_err(|e| Error::connection(path.display().to_string(), e))?;
+        info!(path = %path.display(), "Starting Unix socket output retransmission server");
+        Ok(RetransmitServer::with_listener(Listener::Unix(server), tx_to_input, broadcast_from_input_tx))
+    }
+
+    fn with_listener(
+        server: Listener,
+        tx_to_input: mpsc::Sender<Bytes>,
+        broadcast_from_input_tx: broadcast::Sender<Bytes>,
+    ) -> RetransmitServer {
+        RetransmitServer {
+            server,
+            tx_to_input,
+            broadcast_from_input_tx,
+            stats: ServerStats::new(),
+            control: ServerControl::default(),
+            lag_policy: LagPolicy::default(),
+            slow_client_policy: SlowClientPolicy::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            rfc2217: None,
+            modbus: None,
+            response_routing: None,
+            ntrip: None,
+            rtcm_filter: None,
+            gpsd: None,
+        }
+    }
+
+    /// Returns the statistics handle of this server, including the statistics of every connected client.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// Returns the handle used to kick clients or change their write permission.
+    pub fn control(&self) -> ServerControl {
+        self.control.clone()
+    }
+
+    /// Set what happens when a client falls so far behind that the broadcast channel overwrites data it has not read
+    /// yet. The default is `LagPolicy::Skip`. Only clients connecting after the change are affected.
+    pub fn set_lag_policy(&mut self, policy: LagPolicy) {
+        self.lag_policy = policy;
+    }
+
+    /// Set how clients that cannot keep up with the input are handled. Only clients connecting after the change are
+    /// affected.
+    pub fn set_slow_client_policy(&mut self, policy: SlowClientPolicy) {
+        self.slow_client_policy = policy;
+    }
 
-        Ok(RetransmitServer {
-            server: server,
-            tx_to_input: tx_to_input,
-            broadcast_from_input_rx: broadcast_from_input_rx,
-        })
+    /// Set how long the clients are given on shutdown to receive the data already queued for them. The default is
+    /// `DEFAULT_DRAIN_TIMEOUT`.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Serve the clients with the RFC 2217 Telnet protocol instead of raw bytes, applying the line settings they
+    /// request to the serial input controlled by `control`. Read-only clients can query the settings but not change
+    /// them. Only clients connecting after the change are affected.
+    pub fn set_rfc2217(&mut self, control: InputControl) {
+        self.rfc2217 = Some(control);
+    }
+
+    /// Run a Modbus TCP to Modbus RTU gateway instead of retransmitting the input: the clients send Modbus TCP
+    /// requests, which are sent to the input one at a time as RTU frames, and each response is returned only to the
+    /// client that sent the request. A device not answering within `response_timeout` is reported to the client with
+    /// a gateway exception. Read-only clients can only send requests that read from the devices.
+    ///
+    /// Must be set before `run_loop` is called.
+    pub fn set_modbus(&mut self, response_timeout: Duration) {
+        self.modbus = Some(response_timeout);
+    }
+
+    /// Route the responses of query-style devices to the client that sent the query. The commands of the clients are
+    /// sent to the input one at a time, and the next response, framed as given by `routing`, only goes to the client
+    /// that sent the command. The rest of the input data is broadcast to every client through a channel of
+    /// `channel_capacity` chunks, normally the capacity of the input broadcast channel.
+    ///
+    /// Must be set before `run_loop` is called.
+    pub fn set_response_routing(&mut self, routing: ResponseRouting, channel_capacity: usize) {
+        self.response_routing = Some((routing, channel_capacity));
+    }
+
+    /// Serve the input as an NTRIP mountpoint. The clients must request the mountpoint, with the credentials if the
+    /// caster has some, before receiving the data. Other requests are answered with the sourcetable. What the clients
+    /// send afterwards is discarded. Only clients connecting after the change are affected.
+    pub fn set_ntrip_caster(&mut self, caster: NtripCaster) {
+        self.ntrip = Some(caster);
+    }
+
+    /// Only send the clients the RTCM 3 frames with the message types of `filter`, the input being framed with
+    /// `Framing::Rtcm3`. Only clients connecting after the change are affected.
+    pub fn set_rtcm_filter(&mut self, filter: RtcmFilter) {
+        self.rtcm_filter = Some(filter);
+    }
+
+    /// Speak the JSON protocol of gpsd to the clients, reporting the NMEA sentences of the input as the device
+    /// `device`. What the clients send is answered as gpsd requests and never reaches the input.
+    pub fn set_gpsd(&mut self, device: String) {
+        self.gpsd = Some(device);
     }
 
     /// The main run loop.
     ///
     /// This loop listens for new connections and spawn a new tokio process with a unique reciever.
     /// The spawned processes will simply retransmit the data recieved until the socket or the reciever is closed.
-    pub async fn run_loop(&mut self) {
+    ///
+    /// Errors affecting a single incoming connection are logged and skipped. Any other error accepting connections,
+    /// such as running out of file descriptors, stops the loop and is returned.
+    ///
+    /// Once `shutdown` is cancelled, no new client is accepted and every client is given the drain timeout to receive
+    /// the data already queued for it. The loop returns when all clients are closed.
+    pub async fn run_loop(&mut self, shutdown: CancellationToken) -> Result<()> {
+        let mut clients = JoinSet::new();
+        let modbus = self.modbus.map(|response_timeout| {
+            let bus = ModbusBus::new(self.tx_to_input.clone(), self.broadcast_from_input_tx.clone(), response_timeout);
+            let handle = bus.handle();
+            clients.spawn(bus.run_loop(shutdown.clone()).in_current_span());
+            handle
+        });
+        let gpsd = self.gpsd.as_ref().map(|device| {
+            let feed = GpsdFeed::new(device, &self.broadcast_from_input_tx);
+            let handle = feed.handle();
+            clients.spawn(feed.run_loop(shutdown.clone()).in_current_span());
+            handle
+        });
+        // With response routing, the clients only receive the data that is not a response. This sender is kept until
+        // the clients are closed, so they drain on shutdown instead of seeing the channel close.
+        let (broadcast_to_clients, responses) = match self.response_routing.clone() {
+            Some((routing, channel_capacity)) => {
+                let (unsolicited_tx, _) = broadcast::channel(channel_capacity);
+                let router = ResponseRouter::new(routing, self.tx_to_input.clone(), &self.broadcast_from_input_tx, unsolicited_tx.clone());
+                let handle = router.handle();
+                clients.spawn(router.run_loop(shutdown.clone()).in_current_span());
+                (unsolicited_tx, Some(handle))
+            },
+            None => (self.broadcast_from_input_tx.clone(), None),
+        };
         loop {
             //second item contains the ip and port of the new connection
-            let (mut client_socket, socket_address) = self.server.accept().await.unwrap();
-            let mut rx_from_input = self.broadcast_from_input_rx.resubscribe();
+            let accepted = tokio::select! {
+                accepted = self.server.accept() => accepted,
+                Some(_) = clients.join_next() => continue,
+                _ = shutdown.cancelled() => break,
+            };
+            let (client_socket, peer) = match accepted {
+                Ok(val) => val,
+                Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted) => {
+                    warn!(error = %e, "Error accepting output client connection");
+                    continue;
+                },
+                Err(e) => return Err(Error::Io(e)),
+            };
+            let stats = self.stats.clone();
+            let client_stats = stats.connect_client(peer.clone());
+            let control = self.control.clone();
+            let client_control = control.connect_client(client_stats.id());
+            info!(%peer, client = client_stats.id(), "Accepted output client connection");
+            let ctx = ClientContext {
+                peer,
+                server_stats: stats,
+                stats: client_stats,
+                control: client_control,
+                lag_policy: self.lag_policy.clone(),
+                slow_client_policy: self.slow_client_policy.clone(),
+                shutdown: shutdown.clone(),
+                drain_timeout: self.drain_timeout,
+                rfc2217: self.rfc2217.clone(),
+                modbus: modbus.clone(),
+                responses: responses.clone(),
+                ntrip: self.ntrip.clone(),
+                rtcm_filter: self.rtcm_filter.clone(),
+                gpsd: gpsd.clone(),
+            };
+            // Modbus clients only receive the responses to their requests, gpsd clients the reports.
+            let rx_from_input = (modbus.is_none() && gpsd.is_none()).then(|| broadcast_to_clients.subscribe());
             let tx_from_client = self.tx_to_input.clone();
-            println!("Accepted output client connection at {}", socket_address);
-
-            tokio::spawn(async move {
-                // Per-client buffer to handle temporary slow writes
-                let mut pending_writes: VecDeque<Vec<u8>> = VecDeque::new();
-                const MAX_PENDING_WRITES: usize = 100;
-                const WRITE_TIMEOUT_MS: u64 = 5000; // 5 second timeout for writes
-                let mut slow_client_warnings = 0;
-
-                loop {
-                    let mut buf = vec![0; 8192];
-
-                    // Try to flush pending writes first
-                    while let Some(data) = pending_writes.front() {
-                        match timeout(Duration::from_millis(WRITE_TIMEOUT_MS), client_socket.write_all(data)).await {
-                            Ok(Ok(())) => {
-                                pending_writes.pop_front();
-                            },
-                            Ok(Err(e)) => {
-                                eprintln!("Client {} disconnected (write error: {})", socket_address, e);
-                                return;
+
+            clients.spawn(async move {
+                match rx_from_input {
+                    Some(rx_from_input) => handle_client(client_socket, rx_from_input, tx_from_client, &ctx).await,
+                    None if ctx.gpsd.is_some() => handle_gpsd_client(client_socket, &ctx).await,
+                    None => handle_modbus_client(client_socket, &ctx).await,
+                }
+                control.disconnect_client(ctx.stats.id());
+                ctx.server_stats.disconnect_client(&ctx.stats);
+            }.in_current_span());
+        }
+
+        info!(clients = clients.len(), "Shutting down output server");
+        while clients.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+/// The socket the output clients connect to.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocketListener),
+}
+
+/// A connected output client, of either listener.
+trait ClientSocket: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ClientSocket for T {}
+
+impl Listener {
+    /// Wait for the next client, returning its socket and a description of its address.
+    async fn accept(&self) -> io::Result<(Box<dyn ClientSocket>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Box::new(socket), addr.to_string()))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                // Unix socket clients are usually unnamed, so they are described by the server path.
+                let socket = listener.accept().await?;
+                Ok((Box::new(socket), format!("unix:{}", listener.path().display())))
+            },
+        }
+    }
+}
+
+/// Everything a client task needs besides its socket and channels.
+struct ClientContext {
+    peer: String,
+    server_stats: ServerStats,
+    stats: ClientStats,
+    control: ClientControl,
+    lag_policy: LagPolicy,
+    slow_client_policy: SlowClientPolicy,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+    rfc2217: Option<InputControl>,
+    modbus: Option<ModbusHandle>,
+    responses: Option<ResponseHandle>,
+    ntrip: Option<NtripCaster>,
+    rtcm_filter: Option<RtcmFilter>,
+    gpsd: Option<GpsdHandle>,
+}
+
+impl ClientContext {
+    /// Whether the input data is sent to the client at all.
+    fn wants(&self, data: &[u8]) -> bool {
+        self.rtcm_filter.as_ref().is_none_or(|filter| filter.allows(data))
+    }
+
+    /// Prepare input data for the client, escaping it for the Telnet protocol.
+    fn encode(&self, data: Bytes) -> Bytes {
+        match self.rfc2217 {
+            Some(_) => rfc2217::escape(data),
+            None => data,
+        }
+    }
+}
+
+/// Space reserved for every read from a client.
+const CLIENT_READ_CAPACITY: usize = 8192;
+
+/// Time given to a client to send its NTRIP request.
+const NTRIP_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Modbus and routed responses waiting to be queued for a single client.
+const CLIENT_RESPONSES: usize = 16;
+
+/// Data queued for a client that has not been written yet. The front chunk may be partially written.
+#[derive(Default)]
+struct PendingWrites {
+    chunks: VecDeque<Bytes>,
+    /// Bytes of the front chunk already written.
+    offset: usize,
+    /// Unwritten bytes over all chunks.
+    bytes: usize,
+}
+
+impl PendingWrites {
+    fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn push(&mut self, data: Bytes) {
+        self.bytes += data.len();
+        self.chunks.push_back(data);
+    }
+
+    /// The unwritten part of the front chunk.
+    fn front(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => &chunk[self.offset..],
+            None => &[],
+        }
+    }
+
+    /// Mark `n` bytes of the front chunk as written. Returns the length of the front chunk if it is now complete.
+    fn advance(&mut self, n: usize) -> Option<usize> {
+        self.offset += n;
+        self.bytes -= n;
+        let len = self.chunks.front()?.len();
+        if self.offset < len {
+            return None;
+        }
+        self.chunks.pop_front();
+        self.offset = 0;
+        Some(len)
+    }
+
+    /// Index of the first chunk that can still be dropped or merged, as a partially written one has to be finished.
+    fn first_unstarted(&self) -> usize {
+        if self.offset > 0 { 1 } else { 0 }
+    }
+
+    /// Drop the oldest chunk not being written. Returns false if there is none.
+    fn drop_oldest(&mut self) -> bool {
+        match self.chunks.remove(self.first_unstarted()) {
+            Some(chunk) => {
+                self.bytes -= chunk.len();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Drop the newest chunk, unless it is being written. Returns false if there is none.
+    fn drop_newest(&mut self) -> bool {
+        if self.chunks.len() <= self.first_unstarted() {
+            return false;
+        }
+        let chunk = self.chunks.pop_back().unwrap();
+        self.bytes -= chunk.len();
+        true
+    }
+
+    /// Merge the chunks not being written into one. Returns false if there was nothing to merge.
+    fn coalesce(&mut self) -> bool {
+        let start = self.first_unstarted();
+        if self.chunks.len() <= start + 1 {
+            return false;
+        }
+        let mut merged = BytesMut::with_capacity(self.chunks.iter().skip(start).map(|chunk| chunk.len()).sum());
+        for chunk in self.chunks.drain(start..) {
+            merged.extend_from_slice(&chunk);
+        }
+        self.chunks.push_back(merged.freeze());
+        true
+    }
+}
+
+/// Apply the slow-client policy once a chunk has been queued. Returns false if the client has to be disconnected.
+fn enforce_limits(pending: &mut PendingWrites, ctx: &ClientContext, slow: &mut bool) -> bool {
+    let policy = &ctx.slow_client_policy;
+    let mut dropped = 0;
+    let mut coalesced = false;
+    while pending.len() > policy.max_pending_messages || pending.bytes > policy.max_pending_bytes {
+        let reduced = match policy.action {
+            SlowClientAction::Disconnect => false,
+            SlowClientAction::DropOldest => pending.drop_oldest(),
+            SlowClientAction::DropNewest => pending.drop_newest(),
+            SlowClientAction::Coalesce => pending.len() > policy.max_pending_messages && pending.coalesce(),
+        };
+        if !reduced {
+            if matches!(policy.action, SlowClientAction::DropOldest | SlowClientAction::DropNewest) {
+                // Only the chunk being written is left, it has to be finished.
+                break;
+            }
+            error!(peer = %ctx.peer, policy = ?policy.action, pending = pending.len(), pending_bytes = pending.bytes, "Output client queue over limit, disconnecting");
+            ctx.server_stats.record_slow_disconnect();
+            return false;
+        }
+        match policy.action {
+            SlowClientAction::Coalesce => coalesced = true,
+            _ => dropped += 1,
+        }
+    }
+
+    if dropped > 0 {
+        ctx.server_stats.record_slow_drop(&ctx.stats, dropped);
+    }
+    if (dropped > 0 || coalesced) && !*slow {
+        // Reported once per episode, until the queue drains.
+        *slow = true;
+        warn!(peer = %ctx.peer, policy = ?policy.action, dropped, pending = pending.len(), pending_bytes = pending.bytes, "Output client queue over limit, applying slow client policy");
+    }
+    true
+}
+
+/// Write the data already queued for, or broadcast to, a shutting down client, giving up after the drain timeout.
+async fn drain<W: AsyncWrite + Unpin>(client_tx: &mut W, rx_from_input: &mut broadcast::Receiver<Bytes>, pending: &mut PendingWrites, ctx: &ClientContext) {
+    let deadline = Instant::now() + ctx.drain_timeout;
+    loop {
+        match rx_from_input.try_recv() {
+            Ok(data) if ctx.wants(&data) => pending.push(ctx.encode(data)),
+            Ok(_) => {},
+            Err(broadcast::error::TryRecvError::Lagged(skipped)) => ctx.server_stats.record_lagged(&ctx.stats, skipped),
+            Err(_) => break,
+        }
+    }
+
+    let flushed = timeout_at(deadline, async {
+        while !pending.is_empty() {
+            let n = client_tx.write(pending.front()).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            if let Some(len) = pending.advance(n) {
+                ctx.server_stats.record_output(&ctx.stats, len);
+            }
+        }
+        client_tx.shutdown().await
+    }).await;
+
+    match flushed {
+        Ok(Ok(())) => info!(peer = %ctx.peer, "Output client flushed, disconnecting for shutdown"),
+        Ok(Err(e)) => info!(peer = %ctx.peer, error = %e, kind = ?e.kind(), "Output client disconnected while flushing for shutdown"),
+        Err(_) => warn!(peer = %ctx.peer, pending = pending.len(), pending_bytes = pending.bytes, "Output client not flushed before the shutdown deadline, dropping its queued data"),
+    }
+    ctx.server_stats.set_pending(&ctx.stats, pending.len(), pending.bytes);
+}
+
+/// Whether the client asked to stop receiving data for now.
+fn suspended(telnet: &Option<Rfc2217Session>) -> bool {
+    telnet.as_ref().is_some_and(Rfc2217Session::is_suspended)
+}
+
+/// Retransmit the broadcast data to a single client and forward anything it sends to the input, until either side closes.
+async fn handle_client(
+    mut client_socket: Box<dyn ClientSocket>,
+    mut rx_from_input: broadcast::Receiver<Bytes>,
+    tx_from_client: mpsc::Sender<Bytes>,
+    ctx: &ClientContext,
+) {
+    if let Some(caster) = &ctx.ntrip {
+        match timeout(NTRIP_REQUEST_TIMEOUT, caster.handshake(&mut client_socket, &ctx.peer)).await {
+            Ok(Ok(true)) => {},
+            Ok(Ok(false)) => return,
+            Ok(Err(e)) => {
+                info!(peer = %ctx.peer, error = %e, "Output client disconnected during the NTRIP request");
+                return;
+            },
+            Err(_) => {
+                info!(peer = %ctx.peer, "Output client did not send its NTRIP request in time");
+                return;
+            },
+        }
+    }
+    let policy = &ctx.slow_client_policy;
+    let (mut client_rd, mut client_tx) = io::split(client_socket);
+    let mut pending = PendingWrites::default();
+    // Whether the slow client policy fired since the queue last drained.
+    let mut slow = false;
+    let mut write_timeouts = 0;
+    let mut write_deadline = Instant::now() + policy.write_timeout;
+    let mut buf = BytesMut::new();
+    let mut telnet = ctx.rfc2217.clone().map(Rfc2217Session::new);
+    if let Some(session) = &telnet {
+        pending.push(session.greeting());
+    }
+    // Responses routed to this client, and its data cut into commands, with response routing.
+    let (responses_tx, mut responses_rx) = mpsc::channel(CLIENT_RESPONSES);
+    let mut commands = CommandSplitter::default();
+
+    loop {
+        buf.reserve(CLIENT_READ_CAPACITY);
+
+        tokio::select! {
+            result = rx_from_input.recv() => {
+                let data = match result {
+                    Ok(data) if ctx.wants(&data) => data,
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        ctx.server_stats.record_lagged(&ctx.stats, skipped);
+                        let total = ctx.stats.snapshot().lagged_messages;
+                        warn!(peer = %ctx.peer, skipped, total_skipped = total, policy = ?ctx.lag_policy, "Output client lagged behind the input");
+                        match &ctx.lag_policy {
+                            LagPolicy::Skip => continue,
+                            LagPolicy::Disconnect => {
+                                info!(peer = %ctx.peer, "Disconnecting lagging output client");
+                                break;
                             },
-                            Err(_) => {
-                                slow_client_warnings += 1;
-                                if slow_client_warnings % 5 == 1 {
-                                    eprintln!("WARNING: Client {} is slow (timeout #{}, {} messages pending)",
-                                             socket_address, slow_client_warnings, pending_writes.len());
-                                }
-                                if slow_client_warnings > 10 {
-                                    eprintln!("ERROR: Client {} too slow, disconnecting", socket_address);
-                                    return;
-                                }
-                                break; // Move on to handle other events
-                            }
+                            LagPolicy::GapMarker(template) => Bytes::from(template.replace("{skipped}", &skipped.to_string())),
                         }
+                    },
+                    Err(broadcast::error::RecvError::Closed) => {
+                        info!(peer = %ctx.peer, "Input closed, disconnecting output client");
+                        break;
                     }
+                };
 
-                    tokio::select! {
-                        Ok(data) = rx_from_input.recv() => {
-                            // Try to write immediately if no pending writes
-                            if pending_writes.is_empty() {
-                                match timeout(Duration::from_millis(WRITE_TIMEOUT_MS), client_socket.write_all(&data)).await {
-                                    Ok(Ok(())) => {
-                                        // Successfully written
-                                    },
-                                    Ok(Err(_)) => {
-                                        println!("Client {} disconnected (write failed)", socket_address);
-                                        break;
-                                    },
-                                    Err(_) => {
-                                        // Timeout, buffer the message
-                                        eprintln!("WARNING: Client {} write timeout, buffering message", socket_address);
-                                        pending_writes.push_back(data);
-                                    }
-                                }
-                            } else {
-                                // Already have pending writes, add to buffer
-                                if pending_writes.len() >= MAX_PENDING_WRITES {
-                                    eprintln!("ERROR: Client {} buffer full ({} messages), disconnecting",
-                                             socket_address, MAX_PENDING_WRITES);
-                                    break;
+                if pending.is_empty() {
+                    write_deadline = Instant::now() + policy.write_timeout;
+                }
+                pending.push(ctx.encode(data));
+                if !enforce_limits(&mut pending, ctx, &mut slow) {
+                    break;
+                }
+                ctx.server_stats.set_pending(&ctx.stats, pending.len(), pending.bytes);
+            },
+            Some(response) = responses_rx.recv() => {
+                if pending.is_empty() {
+                    write_deadline = Instant::now() + policy.write_timeout;
+                }
+                pending.push(ctx.encode(response));
+                if !enforce_limits(&mut pending, ctx, &mut slow) {
+                    break;
+                }
+                ctx.server_stats.set_pending(&ctx.stats, pending.len(), pending.bytes);
+            },
+            result = client_tx.write(pending.front()), if !pending.is_empty() && !suspended(&telnet) => {
+                match result {
+                    Ok(0) => {
+                        info!(peer = %ctx.peer, "Output client disconnected (write closed)");
+                        break;
+                    },
+                    Ok(n) => {
+                        if let Some(len) = pending.advance(n) {
+                            ctx.server_stats.record_output(&ctx.stats, len);
+                        }
+                        write_timeouts = 0;
+                        write_deadline = Instant::now() + policy.write_timeout;
+                        if pending.is_empty() {
+                            slow = false;
+                        }
+                        ctx.server_stats.set_pending(&ctx.stats, pending.len(), pending.bytes);
+                    },
+                    Err(e) => {
+                        info!(peer = %ctx.peer, error = %e, kind = ?e.kind(), "Output client disconnected (write error)");
+                        break;
+                    }
+                }
+            },
+            _ = sleep_until(write_deadline), if !pending.is_empty() && !suspended(&telnet) => {
+                write_timeouts += 1;
+                warn!(peer = %ctx.peer, timeouts = write_timeouts, pending = pending.len(), pending_bytes = pending.bytes, "Output client is slow");
+                if write_timeouts >= policy.max_write_timeouts {
+                    error!(peer = %ctx.peer, timeouts = write_timeouts, "Output client too slow, disconnecting");
+                    ctx.server_stats.record_slow_disconnect();
+                    break;
+                }
+                write_deadline = Instant::now() + policy.write_timeout;
+            },
+            _ = sleep_until(commands.deadline().unwrap_or_else(Instant::now)), if commands.deadline().is_some() => {
+                let Some(router) = &ctx.responses else { continue };
+                if !router.submit(commands.flush(), responses_tx.clone()).await {
+                    error!(peer = %ctx.peer, "Failed to send data from output client to input socket");
+                    break;
+                }
+            },
+            _ = ctx.control.kick.notified() => {
+                info!(peer = %ctx.peer, "Output client kicked");
+                break;
+            },
+            _ = ctx.shutdown.cancelled() => {
+                drain(&mut client_tx, &mut rx_from_input, &mut pending, ctx).await;
+                break;
+            },
+            result = client_rd.read_buf(&mut buf) => {
+                match result {
+                    Ok(0) => {
+                        info!(peer = %ctx.peer, "Output client disconnected (connection closed)");
+                        break;
+                    },
+                    Ok(n) => {
+                        let mut data = buf.split().freeze();
+                        debug!(peer = %ctx.peer, bytes = n, "Received data from output client");
+                        ctx.server_stats.record_client_input(&ctx.stats, n);
+                        let write_allowed = ctx.control.write_allowed.load(Ordering::Relaxed);
+                        if let Some(session) = &mut telnet {
+                            let was_suspended = session.is_suspended();
+                            let (received, replies) = session.receive(&data, write_allowed).await;
+                            if !replies.is_empty() {
+                                if pending.is_empty() {
+                                    write_deadline = Instant::now() + policy.write_timeout;
                                 }
-                                pending_writes.push_back(data);
+                                pending.push(replies);
+                                ctx.server_stats.set_pending(&ctx.stats, pending.len(), pending.bytes);
                             }
-                        },
-                        result = client_socket.read(&mut buf) => {
-                            match result {
-                                Ok(0) => {
-                                    println!("Input Client {} disconnected (connection closed)", socket_address);
-                                    break;
-                                },
-                                Ok(n) => {
-                                    buf.truncate(n);
-                                    if let Err(_) = tx_from_client.send(buf).await {
-                                        println!("Failed to send data from client {} to input socket", socket_address);
-                                        break;
-                                    }
-                                },
-                                Err(_) => {
-                                    println!("Client {} disconnected (read error)", socket_address);
-                                    break;
-                                }
+                            if was_suspended && !session.is_suspended() {
+                                write_deadline = Instant::now() + policy.write_timeout;
+                            }
+                            if received.is_empty() {
+                                continue;
                             }
+                            data = received;
+                        }
+                        if !write_allowed {
+                            debug!(peer = %ctx.peer, bytes = n, "Discarding data from read-only output client");
+                            continue;
+                        }
+                        if ctx.ntrip.is_some() {
+                            debug!(peer = %ctx.peer, bytes = n, "Discarding data from NTRIP client");
+                            continue;
+                        }
+                        let sent = match &ctx.responses {
+                            Some(router) => submit_commands(router, commands.push(&data), &responses_tx).await,
+                            None => tx_from_client.send(data).await.is_ok(),
+                        };
+                        if !sent {
+                            error!(peer = %ctx.peer, "Failed to send data from output client to input socket");
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        info!(peer = %ctx.peer, error = %e, kind = ?e.kind(), "Output client disconnected (read error)");
+                        break;
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// Queue the commands of a client on the response router, returning false if the router is stopped.
+async fn submit_commands(router: &ResponseHandle, commands: Vec<Bytes>, responses_tx: &mpsc::Sender<Bytes>) -> bool {
+    for command in commands {
+        if !router.submit(command, responses_tx.clone()).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Pass the Modbus TCP requests of a client to the gateway and send it the responses, until either side closes.
+async fn handle_modbus_client(client_socket: Box<dyn ClientSocket>, ctx: &ClientContext) {
+    let Some(bus) = &ctx.modbus else { return };
+    let (mut client_rd, mut client_tx) = io::split(client_socket);
+    let (responses_tx, mut responses_rx) = mpsc::channel(CLIENT_RESPONSES);
+    let mut buf = BytesMut::new();
+
+    loop {
+        buf.reserve(CLIENT_READ_CAPACITY);
+
+        let response = tokio::select! {
+            Some(response) = responses_rx.recv() => response,
+            _ = ctx.control.kick.notified() => {
+                info!(peer = %ctx.peer, "Output client kicked");
+                break;
+            },
+            _ = ctx.shutdown.cancelled() => {
+                let _ = timeout(ctx.drain_timeout, client_tx.shutdown()).await;
+                info!(peer = %ctx.peer, "Output client disconnected for shutdown");
+                break;
+            },
+            result = client_rd.read_buf(&mut buf) => {
+                match result {
+                    Ok(0) => {
+                        info!(peer = %ctx.peer, "Output client disconnected (connection closed)");
+                        break;
+                    },
+                    Ok(n) => ctx.server_stats.record_client_input(&ctx.stats, n),
+                    Err(e) => {
+                        info!(peer = %ctx.peer, error = %e, kind = ?e.kind(), "Output client disconnected (read error)");
+                        break;
+                    }
+                }
+                let requests = std::iter::from_fn(|| ModbusRequest::decode(&mut buf).transpose());
+                let mut refused = BytesMut::new();
+                let mut valid = true;
+                for request in requests {
+                    let request = match request {
+                        Ok(request) => request,
+                        Err(e) => {
+                            warn!(peer = %ctx.peer, error = %e, "Disconnecting output client sending invalid Modbus TCP data");
+                            valid = false;
+                            break;
                         }
                     };
+                    if let Some(exception) = modbus::refuse(&request, ctx.control.write_allowed.load(Ordering::Relaxed)) {
+                        debug!(peer = %ctx.peer, function = request.function(), "Refusing Modbus request from read-only output client");
+                        refused.extend_from_slice(&exception);
+                    } else if !bus.submit(request, responses_tx.clone()).await {
+                        valid = false;
+                        break;
+                    }
                 }
-            });
+                if !valid {
+                    break;
+                }
+                if refused.is_empty() {
+                    continue;
+                }
+                refused.freeze()
+            }
+        };
+
+        match timeout(ctx.slow_client_policy.write_timeout, client_tx.write_all(&response)).await {
+            Ok(Ok(())) => ctx.server_stats.record_output(&ctx.stats, response.len()),
+            Ok(Err(e)) => {
+                info!(peer = %ctx.peer, error = %e, kind = ?e.kind(), "Output client disconnected (write error)");
+                break;
+            },
+            Err(_) => {
+                error!(peer = %ctx.peer, "Output client too slow, disconnecting");
+                ctx.server_stats.record_slow_disconnect();
+                break;
+            }
         }
     }
 }
+
+/// Serve the reports of the gpsd feed to a single client, answering its requests, until either side closes.
+async fn handle_gpsd_client(client_socket: Box<dyn ClientSocket>, ctx: &ClientContext) {
+    let Some(gpsd) = &ctx.gpsd else { return };
+    let (mut client_rd, mut client_tx) = io::split(client_socket);
+    let mut reports = gpsd.subscribe();
+    let mut watch = Watch::default();
+    let mut buf = BytesMut::new();
+    let mut output = Bytes::from(gpsd.version() + "\r\n");
+
+    loop {
+        if !output.is_empty() {
+            match timeout(ctx.slow_client_policy.write_timeout, client_tx.write_all(&output)).await {
+                Ok(Ok(())) => ctx.server_stats.record_output(&ctx.stats, output.len()),
+                Ok(Err(e)) => {
+                    info!(peer = %ctx.peer, error = %e, kind = ?e.kind(), "Output client disconnected (write error)");
+                    break;
+                },
+                Err(_) => {
+                    error!(peer = %ctx.peer, "Output client too slow, disconnecting");
+                    ctx.server_stats.record_slow_disconnect();
+                    break;
+                }
+            }
+        }
+        buf.reserve(CLIENT_READ_CAPACITY);
+
+        output = tokio::select! {
+            result = reports.recv() => match result {
+                Ok(report) if watch.wants(&report) => match report {
+                    gpsd::Report::Json(data) | gpsd::Report::Nmea(data) => data,
+                },
+                Ok(_) => Bytes::new(),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    ctx.server_stats.record_lagged(&ctx.stats, skipped);
+                    warn!(peer = %ctx.peer, skipped, "gpsd client lagged behind the reports");
+                    Bytes::new()
+                },
+                Err(broadcast::error::RecvError::Closed) => {
+                    info!(peer = %ctx.peer, "Input closed, disconnecting output client");
+                    break;
+                },
+            },
+            _ = ctx.control.kick.notified() => {
+                info!(peer = %ctx.peer, "Output client kicked");
+                break;
+            },
+            _ = ctx.shutdown.cancelled() => {
+                let _ = timeout(ctx.drain_timeout, client_tx.shutdown()).await;
+                info!(peer = %ctx.peer, "Output client disconnected for shutdown");
+                break;
+            },
+            result = client_rd.read_buf(&mut buf) => {
+                match result {
+                    Ok(0) => {
+                        info!(peer = %ctx.peer, "Output client disconnected (connection closed)");
+                        break;
+                    },
+                    Ok(n) => ctx.server_stats.record_client_input(&ctx.stats, n),
+                    Err(e) => {
+                        info!(peer = %ctx.peer, error = %e, kind = ?e.kind(), "Output client disconnected (read error)");
+                        break;
+                    }
+                }
+                let mut answers = String::new();
+                while let Some(request) = gpsd::next_request(&mut buf) {
+                    debug!(peer = %ctx.peer, %request, "gpsd request");
+                    answers.push_str(&gpsd::answer(&request, &mut watch, gpsd));
+                }
+                if buf.len() > gpsd::MAX_REQUEST_LENGTH {
+                    warn!(peer = %ctx.peer, bytes = buf.len(), "Disconnecting output client sending an overlong gpsd request");
+                    break;
+                }
+                Bytes::from(answers)
+            },
+        };
+    }
+}
@@ -0,0 +1,438 @@
+//! This module implements the server side of RFC 2217, the Telnet COM port control option, so output clients can change
+//! the line settings of a serial input (baud rate, framing, flow control, DTR, RTS and break) while receiving its data.
+//!
+//! Telnet commands are stripped from the data sent by the client, and IAC bytes are escaped in both directions. Change
+//! requests are handed to the input through its `InputControl` and acknowledged with the value in use once the input
+//! tried to apply them.
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::time::Duration;
+use tokio_serial::{DataBits, FlowControl, Parity, StopBits};
+use tracing::{debug, info};
+use crate::input_stream::{InputControl, LineSettings};
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+const SIGNATURE: u8 = 0;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const FLOWCONTROL_SUSPEND: u8 = 8;
+const FLOWCONTROL_RESUME: u8 = 9;
+/// Added to a client command to make the server reply.
+const SERVER_OFFSET: u8 = 100;
+
+/// Longest wait for the input to apply a line settings change before replying with the settings in use.
+const LINE_SETTINGS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most bytes kept from a single subnegotiation, longer ones are truncated.
+const MAX_SUBNEGOTIATION: usize = 256;
+
+const SERVER_SIGNATURE: &[u8] = b"port_redirector";
+
+/// Where the decoder is in the Telnet stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// The Telnet state of a single client connection.
+#[derive(Debug)]
+pub struct Rfc2217Session {
+    control: InputControl,
+    state: State,
+    subnegotiation: Vec<u8>,
+    /// Options enabled on our side, and on the client side.
+    local: [bool; 2],
+    remote: [bool; 3],
+    suspended: bool,
+}
+
+impl Rfc2217Session {
+    /// Start a session applying the requests of the client to the input controlled by `control`.
+    pub fn new(control: InputControl) -> Rfc2217Session {
+        // The options requested by `greeting` count as enabled, so the client agreeing does not trigger another round.
+        Rfc2217Session {
+            control,
+            state: State::Data,
+            subnegotiation: Vec::new(),
+            local: [true; 2],
+            remote: [true; 3],
+            suspended: false,
+        }
+    }
+
+    /// The negotiation sent when the client connects: binary transmission and no go-ahead both ways, and the COM port
+    /// option on the client side.
+    pub fn greeting(&self) -> Bytes {
+        Bytes::from_static(&[
+            IAC, WILL, BINARY, IAC, DO, BINARY,
+            IAC, WILL, SUPPRESS_GO_AHEAD, IAC, DO, SUPPRESS_GO_AHEAD,
+            IAC, DO, COM_PORT_OPTION,
+        ])
+    }
+
+    /// Whether the client asked to stop receiving data, with FLOWCONTROL-SUSPEND.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Process data received from the client, returning the data bytes for the input and the replies for the client.
+    /// Unless `apply` is set, COM port requests are answered with the current settings but not applied.
+    pub async fn receive(&mut self, input: &[u8], apply: bool) -> (Bytes, Bytes) {
+        let mut data = BytesMut::with_capacity(input.len());
+        let mut replies = BytesMut::new();
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, _) => {
+                    data.put_u8(byte);
+                    State::Data
+                },
+                (State::Iac, IAC) => {
+                    data.put_u8(IAC);
+                    State::Data
+                },
+                (State::Iac, WILL | WONT | DO | DONT) => State::Negotiation(byte),
+                (State::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    State::Subnegotiation
+                },
+                // NOP, go ahead, interrupt and the other commands mean nothing for a serial line.
+                (State::Iac, _) => State::Data,
+                (State::Negotiation(command), option) => {
+                    self.negotiate(command, option, &mut replies);
+                    State::Data
+                },
+                (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+                (State::Subnegotiation, _) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(byte);
+                    }
+                    State::Subnegotiation
+                },
+                (State::SubnegotiationIac, SE) => {
+                    let subnegotiation = std::mem::take(&mut self.subnegotiation);
+                    self.subnegotiate(&subnegotiation, apply, &mut replies).await;
+                    State::Data
+                },
+                (State::SubnegotiationIac, _) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(byte);
+                    }
+                    State::Subnegotiation
+                },
+            };
+        }
+        (data.freeze(), replies.freeze())
+    }
+
+    /// Answer an option negotiation, only replying when the state of the option changes so the two sides never loop.
+    fn negotiate(&mut self, command: u8, option: u8, replies: &mut BytesMut) {
+        let local = match option {
+            BINARY => Some(0),
+            SUPPRESS_GO_AHEAD => Some(1),
+            _ => None,
+        };
+        let remote = match option {
+            BINARY => Some(0),
+            SUPPRESS_GO_AHEAD => Some(1),
+            COM_PORT_OPTION => Some(2),
+            _ => None,
+        };
+        let reply = match (command, local, remote) {
+            (DO, Some(i), _) if !self.local[i] => { self.local[i] = true; WILL },
+            (DO, None, _) => WONT,
+            (DONT, Some(i), _) if self.local[i] => { self.local[i] = false; WONT },
+            (WILL, _, Some(i)) if !self.remote[i] => { self.remote[i] = true; DO },
+            (WILL, _, None) => DONT,
+            (WONT, _, Some(i)) if self.remote[i] => { self.remote[i] = false; DONT },
+            _ => return,
+        };
+        replies.put_slice(&[IAC, reply, option]);
+    }
+
+    /// Handle a COM port option request and queue its reply, which reports the settings in use once the input tried
+    /// to apply the request.
+    async fn subnegotiate(&mut self, subnegotiation: &[u8], apply: bool, replies: &mut BytesMut) {
+        let [COM_PORT_OPTION, command, value @ ..] = subnegotiation else {
+            debug!(?subnegotiation, "Ignoring Telnet subnegotiation");
+            return;
+        };
+        let mut request = LineSettings::default();
+        match *command {
+            SIGNATURE if !value.is_empty() => {
+                info!(signature = %String::from_utf8_lossy(value), "RFC 2217 client signature");
+                return;
+            },
+            SET_BAUDRATE => {
+                if let [a, b, c, d] = value {
+                    request.baudrate = Some(u32::from_be_bytes([*a, *b, *c, *d])).filter(|baudrate| *baudrate != 0);
+                }
+            },
+            SET_DATASIZE => {
+                request.data_bits = match value.first() {
+                    Some(5) => Some(DataBits::Five),
+                    Some(6) => Some(DataBits::Six),
+                    Some(7) => Some(DataBits::Seven),
+                    Some(8) => Some(DataBits::Eight),
+                    _ => None,
+                };
+            },
+            SET_PARITY => {
+                // Mark and space parity are not supported by the serial port library.
+                request.parity = match value.first() {
+                    Some(1) => Some(Parity::None),
+                    Some(2) => Some(Parity::Odd),
+                    Some(3) => Some(Parity::Even),
+                    _ => None,
+                };
+            },
+            SET_STOPSIZE => {
+                // 1.5 stop bits are not supported by the serial port library.
+                request.stop_bits = match value.first() {
+                    Some(1) => Some(StopBits::One),
+                    Some(2) => Some(StopBits::Two),
+                    _ => None,
+                };
+            },
+            SET_CONTROL => control_request(value.first().copied().unwrap_or(0), &mut request),
+            FLOWCONTROL_SUSPEND | FLOWCONTROL_RESUME => {
+                self.suspended = *command == FLOWCONTROL_SUSPEND;
+                debug!(suspended = self.suspended, "RFC 2217 client flow control");
+                return;
+            },
+            _ => {},
+        }
+
+        let settings = if request == LineSettings::default() {
+            self.control.line_settings()
+        } else if apply {
+            info!(?request, "RFC 2217 client changed the line settings");
+            self.control.apply_line_settings(request, LINE_SETTINGS_TIMEOUT).await
+        } else {
+            debug!(?request, "Ignoring line settings change from read-only output client");
+            self.control.line_settings()
+        };
+
+        let reply: Vec<u8> = match *command {
+            SIGNATURE => SERVER_SIGNATURE.to_vec(),
+            SET_BAUDRATE => settings.baudrate.unwrap_or(0).to_be_bytes().to_vec(),
+            SET_DATASIZE => vec![match settings.data_bits {
+                Some(DataBits::Five) => 5,
+                Some(DataBits::Six) => 6,
+                Some(DataBits::Seven) => 7,
+                Some(DataBits::Eight) => 8,
+                None => 0,
+            }],
+            SET_PARITY => vec![match settings.parity {
+                Some(Parity::None) => 1,
+                Some(Parity::Odd) => 2,
+                Some(Parity::Even) => 3,
+                None => 0,
+            }],
+            SET_STOPSIZE => vec![match settings.stop_bits {
+                Some(StopBits::One) => 1,
+                Some(StopBits::Two) => 2,
+                None => 0,
+            }],
+            SET_CONTROL => vec![control_reply(value.first().copied().unwrap_or(0), &settings)],
+            // Line and modem state masks, and purge requests, are acknowledged as is. No state notifications are sent.
+            _ => value.to_vec(),
+        };
+        replies.put_slice(&[IAC, SB, COM_PORT_OPTION, command + SERVER_OFFSET]);
+        for byte in reply {
+            if byte == IAC {
+                replies.put_u8(IAC);
+            }
+            replies.put_u8(byte);
+        }
+        replies.put_slice(&[IAC, SE]);
+    }
+}
+
+/// The change requested by a SET-CONTROL value, if any.
+fn control_request(value: u8, request: &mut LineSettings) {
+    match value {
+        1..=3 => request.flow_control = Some([FlowControl::None, FlowControl::Software, FlowControl::Hardware][usize::from(value - 1)]),
+        5 | 6 => request.break_signal = Some(value == 5),
+        8 | 9 => request.dtr = Some(value == 8),
+        11 | 12 => request.rts = Some(value == 11),
+        _ => {},
+    }
+}
+
+/// The reply to a SET-CONTROL value, the state of the setting it reads or changes.
+fn control_reply(value: u8, settings: &LineSettings) -> u8 {
+    match value {
+        0..=3 => match settings.flow_control {
+            Some(FlowControl::None) => 1,
+            Some(FlowControl::Software) => 2,
+            Some(FlowControl::Hardware) => 3,
+            None => 0,
+        },
+        4..=6 => if settings.break_signal.unwrap_or(false) { 5 } else { 6 },
+        // DTR and RTS are raised when the serial port is opened.
+        7..=9 => if settings.dtr.unwrap_or(true) { 8 } else { 9 },
+        10..=12 => if settings.rts.unwrap_or(true) { 11 } else { 12 },
+        // Inbound flow control follows the outbound one.
+        13 => match settings.flow_control {
+            Some(FlowControl::Software) => 15,
+            Some(FlowControl::Hardware) => 16,
+            _ => 14,
+        },
+        other => other,
+    }
+}
+
+/// Escape the IAC bytes of data sent to a client. The data is returned as is when it contains none.
+pub fn escape(data: Bytes) -> Bytes {
+    if !data.contains(&IAC) {
+        return data;
+    }
+    let mut escaped = BytesMut::with_capacity(data.len() + 16);
+    for &byte in data.iter() {
+        if byte == IAC {
+            escaped.put_u8(IAC);
+        }
+        escaped.put_u8(byte);
+    }
+    escaped.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use crate::input_stream::{run_input, Input};
+    use crate::stats::InputStats;
+    use std::io;
+    use tokio::sync::{broadcast, mpsc};
+    use tokio_util::sync::CancellationToken;
+
+    /// A serial line that only accepts the baud rates up to 19200.
+    struct SlowLine {
+        settings: LineSettings,
+    }
+
+    impl Input for SlowLine {
+        async fn connect(self) -> Result<SlowLine> {
+            Ok(self)
+        }
+
+        async fn read(&mut self, _buf: &mut BytesMut) -> io::Result<usize> {
+            std::future::pending().await
+        }
+
+        async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn set_line_settings(&mut self, settings: &LineSettings) -> io::Result<LineSettings> {
+            if let Some(baudrate) = settings.baudrate.filter(|baudrate| *baudrate <= 19200) {
+                self.settings.baudrate = Some(baudrate);
+            }
+            Ok(self.settings)
+        }
+
+        fn describe(&self) -> String {
+            "slow-line".to_string()
+        }
+
+        fn stats(&self) -> InputStats {
+            InputStats::new()
+        }
+    }
+
+    fn set_baudrate(baudrate: u32) -> Vec<u8> {
+        let mut request = vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE];
+        request.extend_from_slice(&baudrate.to_be_bytes());
+        request.extend_from_slice(&[IAC, SE]);
+        request
+    }
+
+    fn baudrate_reply(baudrate: u32) -> Vec<u8> {
+        let mut reply = vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE + SERVER_OFFSET];
+        reply.extend_from_slice(&baudrate.to_be_bytes());
+        reply.extend_from_slice(&[IAC, SE]);
+        reply
+    }
+
+    /// Run a `SlowLine` input at 9600 baud, returning once it reported its settings.
+    async fn start_line(control: &InputControl) -> CancellationToken {
+        let shutdown = CancellationToken::new();
+        let mut input = SlowLine { settings: LineSettings { baudrate: Some(9600), ..LineSettings::default() } };
+        let (input_tx, _) = broadcast::channel(4);
+        let (tx_to_input, rx_to_input) = mpsc::channel(4);
+        let (input_control, token) = (control.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            let _tx_to_input = tx_to_input;
+            run_input(&mut input, input_tx, rx_to_input, input_control, token).await
+        });
+        while control.line_settings().baudrate.is_none() {
+            tokio::task::yield_now().await;
+        }
+        shutdown
+    }
+
+    #[tokio::test]
+    async fn accepted_change_is_acknowledged() {
+        let control = InputControl::new();
+        let shutdown = start_line(&control).await;
+        let mut session = Rfc2217Session::new(control.clone());
+        let (data, replies) = session.receive(&set_baudrate(19200), true).await;
+        assert!(data.is_empty());
+        assert_eq!(&replies[..], &baudrate_reply(19200)[..]);
+        assert_eq!(control.line_settings().baudrate, Some(19200));
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn refused_change_replies_with_the_current_value() {
+        let control = InputControl::new();
+        let shutdown = start_line(&control).await;
+        let mut session = Rfc2217Session::new(control.clone());
+        let (_, replies) = session.receive(&set_baudrate(115200), true).await;
+        assert_eq!(&replies[..], &baudrate_reply(9600)[..]);
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn read_only_client_gets_the_current_value() {
+        let control = InputControl::new();
+        let shutdown = start_line(&control).await;
+        let mut session = Rfc2217Session::new(control.clone());
+        let (_, replies) = session.receive(&set_baudrate(4800), false).await;
+        assert_eq!(&replies[..], &baudrate_reply(9600)[..]);
+        assert_eq!(control.line_settings().baudrate, Some(9600));
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn control_reply_reports_the_line_state() {
+        let mut session = Rfc2217Session::new(InputControl::new());
+        let (_, replies) = session.receive(&[IAC, SB, COM_PORT_OPTION, SET_CONTROL, 9, IAC, SE], false).await;
+        // DTR is still up, the read-only request was not applied.
+        assert_eq!(&replies[..], &[IAC, SB, COM_PORT_OPTION, SET_CONTROL + SERVER_OFFSET, 8, IAC, SE]);
+    }
+
+    #[test]
+    fn escape_doubles_iac() {
+        assert_eq!(&escape(Bytes::from_static(&[1, IAC, 2]))[..], &[1, IAC, IAC, 2]);
+    }
+}
@@ -0,0 +1,67 @@
+//! This module keeps track of the running routes (an input and the server retransmitting it) so the metrics and admin
+//! servers can inspect and manage them.
+use std::sync::{Arc, Mutex};
+use crate::input_stream::InputControl;
+use crate::retransmit_server::ServerControl;
+use crate::stats::{InputStats, ServerStats};
+
+/// The handles of a single running route.
+#[derive(Clone, Debug)]
+pub struct RouteHandle {
+    pub name: String,
+    pub input_stats: InputStats,
+    pub input_control: InputControl,
+    pub server_stats: ServerStats,
+    pub server_control: ServerControl,
+}
+
+/// The set of running routes, shared between the tasks that report on or manage them.
+///
+/// ```rust,no_run
+/// # use port_redirector::input_stream::{InputControl, InputSocket};
+/// # use port_redirector::retransmit_server::RetransmitServer;
+/// # use port_redirector::routes::{RouteHandle, RouteRegistry};
+/// # fn example(socket_reader: &InputSocket, input_control: InputControl, retransmit_server: &RetransmitServer) {
+/// let routes = RouteRegistry::new();
+/// routes.register(RouteHandle {
+///     name: "gps".to_string(),
+///     input_stats: socket_reader.stats(),
+///     input_control,
+///     server_stats: retransmit_server.stats(),
+///     server_control: retransmit_server.control(),
+/// });
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct RouteRegistry {
+    routes: Mutex<Vec<RouteHandle>>,
+}
+
+impl RouteRegistry {
+    pub fn new() -> Arc<RouteRegistry> {
+        Arc::new(RouteRegistry::default())
+    }
+
+    /// Add a route, replacing any route with the same name.
+    pub fn register(&self, route: RouteHandle) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.retain(|r| r.name != route.name);
+        routes.push(route);
+    }
+
+    /// Remove a route by name. Returns the removed route, if any.
+    pub fn remove(&self, name: &str) -> Option<RouteHandle> {
+        let mut routes = self.routes.lock().unwrap();
+        let index = routes.iter().position(|r| r.name == name)?;
+        Some(routes.remove(index))
+    }
+
+    pub fn get(&self, name: &str) -> Option<RouteHandle> {
+        self.routes.lock().unwrap().iter().find(|r| r.name == name).cloned()
+    }
+
+    /// All running routes, in registration order.
+    pub fn list(&self) -> Vec<RouteHandle> {
+        self.routes.lock().unwrap().clone()
+    }
+}
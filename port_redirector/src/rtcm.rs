@@ -0,0 +1,176 @@
+//! This module splits RTCM 3 streams, such as the GNSS corrections of a base station, into whole frames.
+//!
+//! A frame starts with the preamble 0xD3, followed by 6 reserved bits, the 10 bit length of the payload, the payload
+//! and a CRC-24Q. The first 12 bits of the payload are the message type number, e.g. 1005 for the base station
+//! position or 1077 for GPS observations. Some receivers do not cope with a frame split over several writes, so with
+//! RTCM 3 framing the input hands every frame to the output clients as a chunk of its own, which also lets the outputs
+//! select the frames by message type.
+use std::ops::RangeInclusive;
+use bytes::{Buf, Bytes, BytesMut};
+use tracing::debug;
+
+/// First byte of every frame.
+pub const PREAMBLE: u8 = 0xD3;
+
+/// Length of the preamble, reserved bits and payload length.
+const HEADER_LENGTH: usize = 3;
+
+const CRC_LENGTH: usize = 3;
+
+/// The CRC-24Q of `data`, sent most significant byte first at the end of a frame.
+pub fn crc24q(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= u32::from(byte) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x100_0000 != 0 {
+                crc ^= 0x186_4CFB;
+            }
+        }
+    }
+    crc & 0xFF_FFFF
+}
+
+/// Length of the frame starting `data`: `Err` if `data` does not start with a frame header, `Ok(None)` if the header
+/// is not complete yet.
+fn frame_length(data: &[u8]) -> Result<Option<usize>, ()> {
+    match data {
+        [] => Ok(None),
+        [first, ..] if *first != PREAMBLE => Err(()),
+        [_] => Ok(None),
+        [_, high, ..] if high & 0xFC != 0 => Err(()),
+        [_, _] => Ok(None),
+        [_, high, low, ..] => Ok(Some(HEADER_LENGTH + (usize::from(high & 0x03) << 8 | usize::from(*low)) + CRC_LENGTH)),
+    }
+}
+
+/// The message type number of `frame`, or `None` if it is not a single whole frame or its payload is empty.
+pub fn message_type(frame: &[u8]) -> Option<u16> {
+    if frame_length(frame) != Ok(Some(frame.len())) || frame.len() < HEADER_LENGTH + 2 + CRC_LENGTH {
+        return None;
+    }
+    Some(u16::from(frame[3]) << 4 | u16::from(frame[4] >> 4))
+}
+
+/// What `split_frame` found at the start of the buffer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Framed {
+    /// A whole frame with a valid CRC.
+    Frame(Bytes),
+    /// A frame of this length whose CRC does not match. Only its preamble is removed from the buffer, in case it was
+    /// not a frame and a valid one starts further on, so the preamble bytes inside a corrupted frame are reported too
+    /// until the next valid frame.
+    CrcFailure(usize),
+}
+
+/// Take the next frame out of the data read from the input, or return `None` if more data is needed. The data before
+/// the frame, which is not RTCM 3, is discarded.
+pub fn split_frame(buf: &mut BytesMut) -> Option<Framed> {
+    loop {
+        let start = buf.iter().position(|&byte| byte == PREAMBLE).unwrap_or(buf.len());
+        if start > 0 {
+            debug!(bytes = start, "Discarding data between RTCM 3 frames");
+            buf.advance(start);
+        }
+        let length = match frame_length(buf) {
+            Ok(Some(length)) => length,
+            Ok(None) => return None,
+            Err(()) => {
+                buf.advance(1);
+                continue;
+            },
+        };
+        if buf.len() < length {
+            return None;
+        }
+        let crc = u32::from_be_bytes([0, buf[length - 3], buf[length - 2], buf[length - 1]]);
+        if crc24q(&buf[..length - CRC_LENGTH]) != crc {
+            buf.advance(1);
+            return Some(Framed::CrcFailure(length));
+        }
+        return Some(Framed::Frame(buf.split_to(length).freeze()));
+    }
+}
+
+/// The message types an output receives, when framing the input as RTCM 3.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtcmFilter {
+    pub message_types: Vec<RangeInclusive<u16>>,
+}
+
+impl RtcmFilter {
+    /// Whether `frame` has one of the message types of the filter.
+    pub fn allows(&self, frame: &[u8]) -> bool {
+        message_type(frame).is_some_and(|message_type| self.message_types.iter().any(|range| range.contains(&message_type)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message 1005, the antenna position of a reference station.
+    const MESSAGE_1005: [u8; 25] = [
+        0xD3, 0x00, 0x13, 0x3E, 0xD7, 0xD3, 0x02, 0x02, 0x98, 0x0E, 0xDE, 0xEF, 0x34,
+        0xB4, 0xBD, 0x62, 0xAC, 0x09, 0x41, 0x98, 0x6F, 0x33, 0x36, 0x0B, 0x98,
+    ];
+
+    /// A frame without payload, sent by some casters to keep the connection alive.
+    const EMPTY_FRAME: [u8; 6] = [0xD3, 0x00, 0x00, 0x47, 0xEA, 0x4B];
+
+    #[test]
+    fn crc24q_matches_known_frames() {
+        assert_eq!(crc24q(&MESSAGE_1005[..22]), 0x36_0B98);
+        assert_eq!(crc24q(&EMPTY_FRAME[..3]), 0x47_EA4B);
+    }
+
+    #[test]
+    fn message_type_needs_a_whole_frame() {
+        assert_eq!(message_type(&MESSAGE_1005), Some(1005));
+        assert_eq!(message_type(&MESSAGE_1005[..24]), None);
+        assert_eq!(message_type(&EMPTY_FRAME), None);
+    }
+
+    #[test]
+    fn split_frame_waits_for_the_rest_of_a_frame() {
+        let mut buf = BytesMut::from(&MESSAGE_1005[..2]);
+        assert_eq!(split_frame(&mut buf), None);
+        buf.extend_from_slice(&MESSAGE_1005[2..10]);
+        assert_eq!(split_frame(&mut buf), None);
+        assert_eq!(buf.len(), 10);
+        buf.extend_from_slice(&MESSAGE_1005[10..]);
+        buf.extend_from_slice(&EMPTY_FRAME[..1]);
+        assert_eq!(split_frame(&mut buf), Some(Framed::Frame(Bytes::copy_from_slice(&MESSAGE_1005))));
+        assert_eq!(&buf[..], &EMPTY_FRAME[..1]);
+    }
+
+    #[test]
+    fn split_frame_discards_what_is_not_rtcm() {
+        let mut buf = BytesMut::from(&b"$GPGGA,123519,4807.038,N*47\r\n"[..]);
+        buf.extend_from_slice(&[0xD3, 0xFF]);
+        buf.extend_from_slice(&EMPTY_FRAME);
+        assert_eq!(split_frame(&mut buf), Some(Framed::Frame(Bytes::copy_from_slice(&EMPTY_FRAME))));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn split_frame_reports_corrupted_frames() {
+        let mut corrupted = EMPTY_FRAME;
+        corrupted[5] ^= 0x01;
+        let mut buf = BytesMut::from(&corrupted[..]);
+        buf.extend_from_slice(&MESSAGE_1005);
+        assert_eq!(split_frame(&mut buf), Some(Framed::CrcFailure(6)));
+        assert_eq!(split_frame(&mut buf), Some(Framed::Frame(Bytes::copy_from_slice(&MESSAGE_1005))));
+        assert_eq!(split_frame(&mut buf), None);
+    }
+
+    #[test]
+    fn filter_allows_listed_message_types() {
+        let filter = RtcmFilter { message_types: vec![1005..=1005, 1074..=1077] };
+        assert!(filter.allows(&MESSAGE_1005));
+        assert!(!RtcmFilter { message_types: vec![1074..=1077] }.allows(&MESSAGE_1005));
+        assert!(!filter.allows(&EMPTY_FRAME));
+        assert!(!filter.allows(b"not rtcm"));
+    }
+}
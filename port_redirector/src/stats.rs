@@ -0,0 +1,432 @@
+//! This module contains the statistics handles kept by every `InputSocket` and `RetransmitServer`.
+//!
+//! The handles are cheap to clone and can be read from any thread while the run loops update them, so embedding
+//! applications can poll them to display the health of each route.
+//!
+//! ```rust,no_run
+//! # use port_redirector::retransmit_server::RetransmitServer;
+//! # async fn example(retransmit_server: &RetransmitServer) {
+//! let stats = retransmit_server.stats();
+//! let before = stats.snapshot();
+//! tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+//! let after = stats.snapshot();
+//! println!("{} clients, {:.0} B/s", after.connected_clients, after.rate_since(&before).bytes_per_sec);
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Throughput over an interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rate {
+    pub bytes_per_sec: f64,
+    pub messages_per_sec: f64,
+}
+
+impl Rate {
+    fn between(bytes: u64, messages: u64, elapsed: Duration) -> Rate {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return Rate::default();
+        }
+        Rate { bytes_per_sec: bytes as f64 / secs, messages_per_sec: messages as f64 / secs }
+    }
+}
+
+/// Unix time in milliseconds, stored in the atomics as 0 for "never".
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn to_system_time(ms: u64) -> Option<SystemTime> {
+    if ms == 0 {
+        None
+    } else {
+        Some(UNIX_EPOCH + Duration::from_millis(ms))
+    }
+}
+
+#[derive(Debug)]
+struct InputCounters {
+    started: Instant,
+    bytes_in: AtomicU64,
+    messages_in: AtomicU64,
+    write_back_bytes: AtomicU64,
+    dropped_messages: AtomicU64,
+    backpressure_events: AtomicU64,
+    connections: AtomicU64,
+    crc_failures: AtomicU64,
+    last_input_ms: AtomicU64,
+}
+
+/// Statistics of a single input, shared between the input's run loop and any readers.
+#[derive(Clone, Debug)]
+pub struct InputStats {
+    inner: Arc<InputCounters>,
+}
+
+impl Default for InputStats {
+    fn default() -> InputStats {
+        InputStats {
+            inner: Arc::new(InputCounters {
+                started: Instant::now(),
+                bytes_in: AtomicU64::new(0),
+                messages_in: AtomicU64::new(0),
+                write_back_bytes: AtomicU64::new(0),
+                dropped_messages: AtomicU64::new(0),
+                backpressure_events: AtomicU64::new(0),
+                connections: AtomicU64::new(0),
+                crc_failures: AtomicU64::new(0),
+                last_input_ms: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+/// Point in time copy of `InputStats`.
+#[derive(Clone, Debug)]
+pub struct InputStatsSnapshot {
+    /// Time since the statistics were created.
+    pub uptime: Duration,
+    /// Bytes read from the input.
+    pub bytes_in: u64,
+    /// Chunks read from the input.
+    pub messages_in: u64,
+    /// Bytes written from output clients back to the input.
+    pub write_back_bytes: u64,
+    /// Input chunks that could not be broadcast.
+    pub dropped_messages: u64,
+    /// Times the input stopped reading to wait for an output client (`NoSubscriberPolicy::Block`).
+    pub backpressure_events: u64,
+    /// Times the input connection was re-established.
+    pub reconnects: u64,
+    /// RTCM 3 frames discarded for an invalid CRC (`Framing::Rtcm3`), counted once until the next valid frame.
+    pub crc_failures: u64,
+    /// When the last chunk was read from the input.
+    pub last_data: Option<SystemTime>,
+}
+
+impl InputStatsSnapshot {
+    /// Input throughput since an earlier snapshot of the same statistics.
+    pub fn rate_since(&self, earlier: &InputStatsSnapshot) -> Rate {
+        Rate::between(
+            self.bytes_in.saturating_sub(earlier.bytes_in),
+            self.messages_in.saturating_sub(earlier.messages_in),
+            self.uptime.saturating_sub(earlier.uptime),
+        )
+    }
+
+    /// Average input throughput since the statistics were created.
+    pub fn average_rate(&self) -> Rate {
+        Rate::between(self.bytes_in, self.messages_in, self.uptime)
+    }
+}
+
+impl InputStats {
+    pub fn new() -> InputStats {
+        InputStats::default()
+    }
+
+    pub fn snapshot(&self) -> InputStatsSnapshot {
+        let c = &self.inner;
+        InputStatsSnapshot {
+            uptime: c.started.elapsed(),
+            bytes_in: c.bytes_in.load(Ordering::Relaxed),
+            messages_in: c.messages_in.load(Ordering::Relaxed),
+            write_back_bytes: c.write_back_bytes.load(Ordering::Relaxed),
+            dropped_messages: c.dropped_messages.load(Ordering::Relaxed),
+            backpressure_events: c.backpressure_events.load(Ordering::Relaxed),
+            reconnects: c.connections.load(Ordering::Relaxed).saturating_sub(1),
+            crc_failures: c.crc_failures.load(Ordering::Relaxed),
+            last_data: to_system_time(c.last_input_ms.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn record_input(&self, bytes: usize) {
+        self.inner.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.inner.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.inner.last_input_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write_back(&self, bytes: usize) {
+        self.inner.write_back_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the total number of dropped messages, including this one.
+    pub(crate) fn record_dropped(&self) -> u64 {
+        self.inner.dropped_messages.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Returns the total number of backpressure events, including this one.
+    pub(crate) fn record_backpressure(&self) -> u64 {
+        self.inner.backpressure_events.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Returns the total number of CRC failures, including this one.
+    pub(crate) fn record_crc_failure(&self) -> u64 {
+        self.inner.crc_failures.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Record that the input connection was established. Every connection after the first counts as a reconnect.
+    ///
+    /// `Input` implementations call this themselves, as only they know when their connection changes.
+    pub fn record_connection(&self) {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct ClientCounters {
+    id: u64,
+    peer: String,
+    connected_at: SystemTime,
+    started: Instant,
+    bytes_out: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    lagged_messages: AtomicU64,
+    dropped_messages: AtomicU64,
+    pending_writes: AtomicUsize,
+    pending_bytes: AtomicUsize,
+}
+
+/// Statistics of a single client connected to a `RetransmitServer`.
+#[derive(Clone, Debug)]
+pub struct ClientStats {
+    inner: Arc<ClientCounters>,
+}
+
+/// Point in time copy of `ClientStats`.
+#[derive(Clone, Debug)]
+pub struct ClientStatsSnapshot {
+    /// Identifier of the client, unique within its server.
+    pub id: u64,
+    /// Address of the client.
+    pub peer: String,
+    pub connected_at: SystemTime,
+    /// Time since the client connected.
+    pub uptime: Duration,
+    /// Bytes written to the client.
+    pub bytes_out: u64,
+    /// Chunks written to the client.
+    pub messages_out: u64,
+    /// Bytes recieved from the client and forwarded to the input.
+    pub bytes_in: u64,
+    /// Chunks the client skipped because it fell behind the broadcast channel.
+    pub lagged_messages: u64,
+    /// Chunks dropped by the slow client policy.
+    pub dropped_messages: u64,
+    /// Chunks currently queued for the client.
+    pub pending_writes: usize,
+    /// Bytes currently queued for the client.
+    pub pending_bytes: usize,
+}
+
+impl ClientStatsSnapshot {
+    /// Output throughput to this client since an earlier snapshot.
+    pub fn rate_since(&self, earlier: &ClientStatsSnapshot) -> Rate {
+        Rate::between(
+            self.bytes_out.saturating_sub(earlier.bytes_out),
+            self.messages_out.saturating_sub(earlier.messages_out),
+            self.uptime.saturating_sub(earlier.uptime),
+        )
+    }
+
+    /// Average output throughput to this client since it connected.
+    pub fn average_rate(&self) -> Rate {
+        Rate::between(self.bytes_out, self.messages_out, self.uptime)
+    }
+}
+
+impl ClientStats {
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    fn new(id: u64, peer: String) -> ClientStats {
+        ClientStats {
+            inner: Arc::new(ClientCounters {
+                id,
+                peer,
+                connected_at: SystemTime::now(),
+                started: Instant::now(),
+                bytes_out: AtomicU64::new(0),
+                messages_out: AtomicU64::new(0),
+                bytes_in: AtomicU64::new(0),
+                lagged_messages: AtomicU64::new(0),
+                dropped_messages: AtomicU64::new(0),
+                pending_writes: AtomicUsize::new(0),
+                pending_bytes: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn snapshot(&self) -> ClientStatsSnapshot {
+        let c = &self.inner;
+        ClientStatsSnapshot {
+            id: c.id,
+            peer: c.peer.clone(),
+            connected_at: c.connected_at,
+            uptime: c.started.elapsed(),
+            bytes_out: c.bytes_out.load(Ordering::Relaxed),
+            messages_out: c.messages_out.load(Ordering::Relaxed),
+            bytes_in: c.bytes_in.load(Ordering::Relaxed),
+            lagged_messages: c.lagged_messages.load(Ordering::Relaxed),
+            dropped_messages: c.dropped_messages.load(Ordering::Relaxed),
+            pending_writes: c.pending_writes.load(Ordering::Relaxed),
+            pending_bytes: c.pending_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ServerCounters {
+    started: Instant,
+    bytes_out: AtomicU64,
+    messages_out: AtomicU64,
+    lagged_messages: AtomicU64,
+    slow_client_disconnects: AtomicU64,
+    slow_client_dropped_messages: AtomicU64,
+    total_clients: AtomicU64,
+    last_output_ms: AtomicU64,
+    clients: Mutex<HashMap<u64, ClientStats>>,
+}
+
+/// Statistics of a `RetransmitServer`, totalled over all clients, plus the statistics of each connected client.
+#[derive(Clone, Debug)]
+pub struct ServerStats {
+    inner: Arc<ServerCounters>,
+}
+
+impl Default for ServerStats {
+    fn default() -> ServerStats {
+        ServerStats {
+            inner: Arc::new(ServerCounters {
+                started: Instant::now(),
+                bytes_out: AtomicU64::new(0),
+                messages_out: AtomicU64::new(0),
+                lagged_messages: AtomicU64::new(0),
+                slow_client_disconnects: AtomicU64::new(0),
+                slow_client_dropped_messages: AtomicU64::new(0),
+                total_clients: AtomicU64::new(0),
+                last_output_ms: AtomicU64::new(0),
+                clients: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+/// Point in time copy of `ServerStats`.
+#[derive(Clone, Debug)]
+pub struct ServerStatsSnapshot {
+    /// Time since the statistics were created.
+    pub uptime: Duration,
+    /// Bytes written to clients, summed over all clients.
+    pub bytes_out: u64,
+    /// Chunks written to clients, summed over all clients.
+    pub messages_out: u64,
+    /// Chunks skipped by lagging clients.
+    pub lagged_messages: u64,
+    /// Clients disconnected for being too slow.
+    pub slow_client_disconnects: u64,
+    /// Chunks dropped by the slow client policy, summed over all clients.
+    pub slow_client_dropped_messages: u64,
+    /// Clients currently connected.
+    pub connected_clients: usize,
+    /// Clients accepted since the server started.
+    pub total_clients: u64,
+    /// When the last chunk was written to a client.
+    pub last_data: Option<SystemTime>,
+    /// Each connected client.
+    pub clients: Vec<ClientStatsSnapshot>,
+}
+
+impl ServerStatsSnapshot {
+    /// Output throughput, summed over all clients, since an earlier snapshot of the same statistics.
+    pub fn rate_since(&self, earlier: &ServerStatsSnapshot) -> Rate {
+        Rate::between(
+            self.bytes_out.saturating_sub(earlier.bytes_out),
+            self.messages_out.saturating_sub(earlier.messages_out),
+            self.uptime.saturating_sub(earlier.uptime),
+        )
+    }
+
+    /// Average output throughput since the statistics were created.
+    pub fn average_rate(&self) -> Rate {
+        Rate::between(self.bytes_out, self.messages_out, self.uptime)
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats::default()
+    }
+
+    pub fn snapshot(&self) -> ServerStatsSnapshot {
+        let c = &self.inner;
+        let mut clients: Vec<ClientStatsSnapshot> = c.clients.lock().unwrap().values().map(|client| client.snapshot()).collect();
+        clients.sort_by_key(|client| client.id);
+        ServerStatsSnapshot {
+            uptime: c.started.elapsed(),
+            bytes_out: c.bytes_out.load(Ordering::Relaxed),
+            messages_out: c.messages_out.load(Ordering::Relaxed),
+            lagged_messages: c.lagged_messages.load(Ordering::Relaxed),
+            slow_client_disconnects: c.slow_client_disconnects.load(Ordering::Relaxed),
+            slow_client_dropped_messages: c.slow_client_dropped_messages.load(Ordering::Relaxed),
+            connected_clients: clients.len(),
+            total_clients: c.total_clients.load(Ordering::Relaxed),
+            last_data: to_system_time(c.last_output_ms.load(Ordering::Relaxed)),
+            clients,
+        }
+    }
+
+    /// Statistics of a single connected client, if it is still connected.
+    pub fn client(&self, id: u64) -> Option<ClientStats> {
+        self.inner.clients.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Register a new client, assigning it the next client id.
+    pub(crate) fn connect_client(&self, peer: String) -> ClientStats {
+        let id = self.inner.total_clients.fetch_add(1, Ordering::Relaxed) + 1;
+        let client = ClientStats::new(id, peer);
+        self.inner.clients.lock().unwrap().insert(id, client.clone());
+        client
+    }
+
+    pub(crate) fn disconnect_client(&self, client: &ClientStats) {
+        self.inner.clients.lock().unwrap().remove(&client.inner.id);
+    }
+
+    pub(crate) fn record_output(&self, client: &ClientStats, bytes: usize) {
+        client.inner.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        client.inner.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.inner.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.inner.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.inner.last_output_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_client_input(&self, client: &ClientStats, bytes: usize) {
+        client.inner.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_lagged(&self, client: &ClientStats, skipped: u64) {
+        client.inner.lagged_messages.fetch_add(skipped, Ordering::Relaxed);
+        self.inner.lagged_messages.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_slow_disconnect(&self) {
+        self.inner.slow_client_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_slow_drop(&self, client: &ClientStats, dropped: u64) {
+        client.inner.dropped_messages.fetch_add(dropped, Ordering::Relaxed);
+        self.inner.slow_client_dropped_messages.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_pending(&self, client: &ClientStats, messages: usize, bytes: usize) {
+        client.inner.pending_writes.store(messages, Ordering::Relaxed);
+        client.inner.pending_bytes.store(bytes, Ordering::Relaxed);
+    }
+}
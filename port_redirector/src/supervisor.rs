@@ -0,0 +1,445 @@
+//! This module starts and stops routes from their configuration, so the set of running routes can be changed without
+//! restarting the process.
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::{AbortHandle, JoinError, JoinHandle};
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::config::{OutputConfig, OutputProtocol, RouteConfig};
+use crate::error::{Error, Result};
+use crate::input_stream::{Input, InputControl, InputSocket};
+use crate::mqtt::MqttOutput;
+#[cfg(unix)]
+use crate::pty::PtyOutput;
+use crate::retransmit_server::{RetransmitServer, ServerControl};
+use crate::routes::{RouteHandle, RouteRegistry};
+use crate::stats::ServerStats;
+
+/// Extra time, on top of the drain timeout, given to a stopping route before its tasks are abandoned.
+const STOP_MARGIN: Duration = Duration::from_secs(1);
+
+/// A request to reload the configuration, answered with the outcome of the reload.
+pub type ReloadRequest = oneshot::Sender<Result<ReloadSummary>>;
+
+/// What changed when a configuration was applied.
+#[derive(Debug, Default)]
+pub struct ReloadSummary {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    pub restarted: Vec<String>,
+    /// Routes changed without a restart.
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Routes that could not be started, they are not running.
+    pub failed: Vec<(String, Error)>,
+}
+
+impl fmt::Display for ReloadSummary {
+    /// One `key=value` line per kind of change, in the format of the admin interface.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (kind, names) in [("started", &self.started), ("stopped", &self.stopped), ("restarted", &self.restarted), ("updated", &self.updated), ("unchanged", &self.unchanged)] {
+            writeln!(f, "{}={}", kind, names.join(","))?;
+        }
+        for (name, e) in &self.failed {
+            writeln!(f, "failed={} error={}", name, e)?;
+        }
+        Ok(())
+    }
+}
+
+struct RunningRoute {
+    config: RouteConfig,
+    input_control: InputControl,
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+/// Supervisor
+///
+/// Runs a set of routes and registers them in a `RouteRegistry`. Applying a new configuration starts the new routes,
+/// stops the removed ones and restarts only the routes whose settings changed, so the clients of the other routes stay
+/// connected.
+///
+/// ```rust,no_run
+/// # use port_redirector::config::parse_routes;
+/// # use port_redirector::routes::RouteRegistry;
+/// # use port_redirector::supervisor::Supervisor;
+/// # async fn example() -> port_redirector::error::Result<()> {
+/// let mut supervisor = Supervisor::new(RouteRegistry::new());
+/// supervisor.apply(parse_routes("name=gps type=udp port=5001 output_port=8001")?).await;
+///
+/// // Later, with the new contents of the configuration file.
+/// let summary = supervisor.apply(parse_routes("name=gps type=udp port=5002 output_port=8001")?).await;
+/// println!("{}", summary);
+///
+/// supervisor.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Supervisor {
+    routes: Arc<RouteRegistry>,
+    running: HashMap<String, RunningRoute>,
+    /// Configured routes that failed to start or stopped on an error.
+    failed: BTreeSet<String>,
+    /// Listening sockets to use instead of binding the output ports, by port.
+    listeners: HashMap<u16, net::TcpListener>,
+    failures_tx: mpsc::UnboundedSender<(String, Error)>,
+    failures_rx: mpsc::UnboundedReceiver<(String, Error)>,
+}
+
+impl Supervisor {
+    pub fn new(routes: Arc<RouteRegistry>) -> Supervisor {
+        let (failures_tx, failures_rx) = mpsc::unbounded_channel();
+        Supervisor { routes, running: HashMap::new(), failed: BTreeSet::new(), listeners: HashMap::new(), failures_tx, failures_rx }
+    }
+
+    /// Serve the output of the routes on these listening sockets instead of binding their ports, matching them by
+    /// port. The sockets are kept open when their routes restart.
+    pub fn add_listeners(&mut self, listeners: Vec<net::TcpListener>) {
+        for listener in listeners {
+            match listener.local_addr() {
+                Ok(addr) => { self.listeners.insert(addr.port(), listener); },
+                Err(e) => warn!(error = %e, "Ignoring listener without a local address"),
+            }
+        }
+    }
+
+    /// The routes that are not healthy: those that failed, and if `max_idle` is given, those whose input received
+    /// nothing for longer than that.
+    pub fn unhealthy_routes(&self, max_idle: Option<Duration>) -> Vec<String> {
+        let mut unhealthy: Vec<String> = self.failed.iter().cloned().collect();
+        if let Some(max_idle) = max_idle {
+            for route in self.routes.list() {
+                let input = route.input_stats.snapshot();
+                let idle = match input.last_data {
+                    Some(last) => SystemTime::now().duration_since(last).unwrap_or_default(),
+                    None => input.uptime,
+                };
+                if idle > max_idle {
+                    unhealthy.push(route.name);
+                }
+            }
+        }
+        unhealthy
+    }
+
+    /// The number of running routes.
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Whether no route is running.
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Make the running routes match `configs`. Removed and changed routes are stopped first, so a restarted route
+    /// can bind the same ports again.
+    pub async fn apply(&mut self, configs: Vec<RouteConfig>) -> ReloadSummary {
+        let mut summary = ReloadSummary::default();
+        self.failed.retain(|name| configs.iter().any(|c| &c.name == name));
+
+        let removed: Vec<String> = self.running.keys().filter(|name| !configs.iter().any(|c| &c.name == *name)).cloned().collect();
+        for name in removed {
+            self.stop(&name).await;
+            summary.stopped.push(name);
+        }
+
+        let mut to_start = Vec::new();
+        for config in configs {
+            match self.running.get_mut(&config.name) {
+                None => to_start.push((config, false)),
+                Some(route) if route.config.needs_restart(&config) => {
+                    self.stop(&config.name).await;
+                    to_start.push((config, true));
+                },
+                Some(route) if route.config != config => {
+                    route.input_control.set_no_subscriber_policy(config.no_subscriber_policy);
+                    info!(route = %config.name, policy = ?config.no_subscriber_policy, "Route updated");
+                    summary.updated.push(config.name.clone());
+                    route.config = config;
+                },
+                Some(_) => summary.unchanged.push(config.name),
+            }
+        }
+
+        for (config, restart) in to_start {
+            let name = config.name.clone();
+            match self.start(config).await {
+                Ok(()) if restart => summary.restarted.push(name),
+                Ok(()) => summary.started.push(name),
+                Err(e) => {
+                    error!(route = %name, error = %e, "Unable to start route");
+                    self.failed.insert(name.clone());
+                    summary.failed.push((name, e));
+                }
+            }
+        }
+        summary
+    }
+
+    /// Wait for a running route to stop on its own because of an error. The route is removed from the running routes.
+    pub async fn next_failure(&mut self) -> (String, Error) {
+        loop {
+            let (name, e) = self.failures_rx.recv().await.expect("the supervisor holds a sender");
+            // A route stopped or restarted by the supervisor has already been removed or replaced by a new one.
+            if self.running.get(&name).is_some_and(|route| route.shutdown.is_cancelled()) {
+                self.running.remove(&name);
+                self.routes.remove(&name);
+                self.failed.insert(name.clone());
+                return (name, e);
+            }
+        }
+    }
+
+    /// Stop every route, giving their clients the drain timeout to receive the queued data.
+    pub async fn shutdown(&mut self) {
+        let names: Vec<String> = self.running.keys().cloned().collect();
+        for route in self.running.values() {
+            route.shutdown.cancel();
+        }
+        for name in names {
+            self.stop(&name).await;
+        }
+    }
+
+    async fn start(&mut self, config: RouteConfig) -> Result<()> {
+        let span = info_span!("route", route = %config.name);
+
+        //Broadcast port for reading in data on the input port and outputting it on all broadcast channels
+        let (broadcast_from_input_tx, _) = broadcast::channel(config.channel_capacity);
+
+        //Mutiple producers to read data in from the server ports and output on the single output port.
+        let (tx_to_input, rx_to_input) = mpsc::channel(config.channel_capacity);
+
+        let socket_reader = InputSocket::connect(config.input.socket()).instrument(span.clone()).await?;
+        let input_control = InputControl::new();
+        input_control.set_no_subscriber_policy(config.no_subscriber_policy);
+        input_control.set_framing(config.framing);
+
+        let output = match &config.output {
+            OutputConfig::Tcp { port } => {
+                let retransmit_server = match self.listeners.get(port) {
+                    Some(listener) => {
+                        let listener = inherited(listener).map_err(|e| Error::connection(format!("inherited port {}", port), e))?;
+                        span.in_scope(|| RetransmitServer::from_listener(listener, tx_to_input, broadcast_from_input_tx.clone()))
+                    },
+                    None => RetransmitServer::new(*port, tx_to_input, broadcast_from_input_tx.clone()).instrument(span.clone()).await?,
+                };
+                Output::Server(configure_server(retransmit_server, &config, &input_control))
+            },
+            #[cfg(unix)]
+            OutputConfig::Unix { path, permissions } => {
+                let retransmit_server = RetransmitServer::new_unix(path, permissions, tx_to_input, broadcast_from_input_tx.clone()).instrument(span.clone()).await?;
+                Output::Server(configure_server(retransmit_server, &config, &input_control))
+            },
+            #[cfg(unix)]
+            OutputConfig::Pty { link, permissions } => {
+                let mut pty_output = span.in_scope(|| PtyOutput::new(link, permissions, tx_to_input, broadcast_from_input_tx.clone()))?;
+                pty_output.set_drain_timeout(config.drain_timeout);
+                if let Some(filter) = &config.rtcm_filter {
+                    pty_output.set_rtcm_filter(filter.clone());
+                }
+                Output::Pty(pty_output)
+            },
+            OutputConfig::Mqtt(settings) => {
+                let mut mqtt_output = MqttOutput::new(settings.clone(), tx_to_input, broadcast_from_input_tx.clone());
+                mqtt_output.set_drain_timeout(config.drain_timeout);
+                if let Some(filter) = &config.rtcm_filter {
+                    mqtt_output.set_rtcm_filter(filter.clone());
+                }
+                Output::Mqtt(mqtt_output)
+            },
+        };
+
+        self.routes.register(RouteHandle {
+            name: config.name.clone(),
+            input_stats: socket_reader.stats(),
+            input_control: input_control.clone(),
+            server_stats: output.stats(),
+            server_control: output.control(),
+        });
+
+        let shutdown = CancellationToken::new();
+        let route = Route {
+            name: config.name.clone(),
+            socket_reader,
+            broadcast_from_input_tx,
+            rx_to_input,
+            input_control: input_control.clone(),
+            output,
+            drain_timeout: config.drain_timeout,
+        };
+        let task = tokio::spawn(route.run(shutdown.clone(), self.failures_tx.clone()).instrument(span));
+        self.failed.remove(&config.name);
+        self.running.insert(config.name.clone(), RunningRoute { config, input_control, shutdown, task });
+        Ok(())
+    }
+
+    async fn stop(&mut self, name: &str) {
+        let Some(mut route) = self.running.remove(name) else { return };
+        self.routes.remove(name);
+        route.shutdown.cancel();
+        if timeout(route.config.drain_timeout + STOP_MARGIN * 2, &mut route.task).await.is_err() {
+            warn!(route = %name, "Route did not stop in time, aborting it");
+            // Aborting the route aborts its input and server, which release their ports and devices once dropped.
+            route.task.abort();
+            let _ = route.task.await;
+        }
+    }
+}
+
+/// Apply the client settings of a route to its server.
+fn configure_server(mut retransmit_server: RetransmitServer, config: &RouteConfig, input_control: &InputControl) -> RetransmitServer {
+    retransmit_server.set_lag_policy(config.lag_policy.clone());
+    retransmit_server.set_slow_client_policy(config.slow_client_policy.clone());
+    retransmit_server.set_drain_timeout(config.drain_timeout);
+    match config.output_protocol {
+        OutputProtocol::Raw => {},
+        OutputProtocol::Rfc2217 => retransmit_server.set_rfc2217(input_control.clone()),
+        OutputProtocol::Modbus => retransmit_server.set_modbus(config.modbus_timeout),
+        OutputProtocol::Ntrip => {
+            if let Some(caster) = &config.ntrip_caster {
+                retransmit_server.set_ntrip_caster(caster.clone());
+            }
+        },
+        OutputProtocol::Gpsd => retransmit_server.set_gpsd(config.input.socket().describe()),
+    }
+    if let Some(filter) = &config.rtcm_filter {
+        retransmit_server.set_rtcm_filter(filter.clone());
+    }
+    if let Some(routing) = &config.response_routing {
+        retransmit_server.set_response_routing(routing.clone(), config.channel_capacity);
+    }
+    retransmit_server
+}
+
+/// A tokio listener sharing an inherited socket, which stays open in the supervisor when the route stops.
+fn inherited(listener: &net::TcpListener) -> std::io::Result<tokio::net::TcpListener> {
+    let listener = listener.try_clone()?;
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
+/// The output side of a route.
+enum Output {
+    Server(RetransmitServer),
+    #[cfg(unix)]
+    Pty(PtyOutput),
+    Mqtt(MqttOutput),
+}
+
+impl Output {
+    fn stats(&self) -> ServerStats {
+        match self {
+            Output::Server(server) => server.stats(),
+            #[cfg(unix)]
+            Output::Pty(pty) => pty.stats(),
+            Output::Mqtt(mqtt) => mqtt.stats(),
+        }
+    }
+
+    fn control(&self) -> ServerControl {
+        match self {
+            Output::Server(server) => server.control(),
+            #[cfg(unix)]
+            Output::Pty(pty) => pty.control(),
+            Output::Mqtt(mqtt) => mqtt.control(),
+        }
+    }
+
+    async fn run_loop(&mut self, shutdown: CancellationToken) -> Result<()> {
+        match self {
+            Output::Server(server) => server.run_loop(shutdown).await,
+            #[cfg(unix)]
+            Output::Pty(pty) => pty.run_loop(shutdown).await,
+            Output::Mqtt(mqtt) => mqtt.run_loop(shutdown).await,
+        }
+    }
+}
+
+/// Aborts the tasks of a route when dropped, so they do not outlive the route task if it is aborted.
+struct AbortOnDrop([AbortHandle; 2]);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// The parts of a started route, moved into its task.
+struct Route {
+    name: String,
+    socket_reader: InputSocket,
+    broadcast_from_input_tx: broadcast::Sender<bytes::Bytes>,
+    rx_to_input: mpsc::Receiver<bytes::Bytes>,
+    input_control: InputControl,
+    output: Output,
+    drain_timeout: Duration,
+}
+
+impl Route {
+    /// Run the input and the server until `shutdown` is cancelled or one of them fails, then stop the other one.
+    async fn run(self, shutdown: CancellationToken, failures: mpsc::UnboundedSender<(String, Error)>) {
+        let Route { name, mut socket_reader, broadcast_from_input_tx, rx_to_input, input_control, mut output, drain_timeout } = self;
+
+        let input_shutdown = shutdown.clone();
+        let mut input_task = tokio::spawn(async move {
+            socket_reader.run_loop(broadcast_from_input_tx, rx_to_input, input_control, input_shutdown).await
+        }.in_current_span());
+        let server_shutdown = shutdown.clone();
+        let mut server_task = tokio::spawn(async move { output.run_loop(server_shutdown).await }.in_current_span());
+        let _abort = AbortOnDrop([input_task.abort_handle(), server_task.abort_handle()]);
+
+        let result = tokio::select! {
+            _ = shutdown.cancelled() => Ok(()),
+            result = &mut input_task => task_result("input", result, false),
+            result = &mut server_task => task_result("output server", result, false),
+        };
+
+        // Whether asked to or because one of them failed, let the remaining task flush before returning.
+        shutdown.cancel();
+        let drained = timeout(drain_timeout + STOP_MARGIN, async {
+            for (task, handle) in [("input", &mut input_task), ("output server", &mut server_task)] {
+                if handle.is_finished() {
+                    continue;
+                }
+                if let Err(e) = task_result(task, handle.await, true) {
+                    warn!(error = %e, "Error during shutdown");
+                }
+            }
+        }).await;
+        if drained.is_err() {
+            warn!("Shutdown deadline reached, abandoning the route");
+            input_task.abort();
+            server_task.abort();
+            // Wait for them to be dropped, so their ports and devices are free once the route is stopped.
+            let _ = input_task.await;
+            let _ = server_task.await;
+        }
+        info!("Route stopped");
+
+        if let Err(e) = result {
+            error!(error = %e, "Route failed");
+            // Nobody is listening anymore if the supervisor is gone.
+            let _ = failures.send((name, e));
+        }
+    }
+}
+
+/// The outcome of a route task. Before the shutdown, a task is not expected to stop even without an error.
+fn task_result(task: &str, result: std::result::Result<Result<()>, JoinError>, shutting_down: bool) -> Result<()> {
+    match result {
+        Ok(Ok(())) if shutting_down => Ok(()),
+        Ok(Ok(())) => Err(Error::Channel(format!("{} task stopped", task))),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(Error::Channel(format!("{} task failed: {}", task, e))),
+    }
+}
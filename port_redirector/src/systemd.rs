@@ -0,0 +1,168 @@
+//! This module implements the parts of the systemd service protocol used by the tool: readiness and watchdog
+//! notifications (`Type=notify`, `WatchdogSec=`) and listening sockets passed by socket activation (`LISTEN_FDS`).
+//!
+//! Everything is a no-op when the process is not started by systemd, so the functions can be called unconditionally.
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Send a state change, such as `READY=1` or `WATCHDOG=1`, to the service manager.
+///
+/// Returns false without doing anything if `NOTIFY_SOCKET` is not set.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    send(&path, state)?;
+    debug!(state, "Sent service manager notification");
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes() {
+        // A leading '@' names a Linux abstract socket.
+        [b'@', name @ ..] => {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = name;
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "Abstract notify sockets are only supported on Linux."));
+            }
+        },
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        },
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &std::ffi::OsStr, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Service manager notifications are only supported on unix platforms."))
+}
+
+/// How often to send `WATCHDOG=1`, half of the watchdog timeout configured for this process, if any.
+pub fn watchdog_interval() -> Option<Duration> {
+    if !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Whether a variable naming the process a setting is meant for is unset or names this process.
+fn for_this_process(variable: &str) -> bool {
+    match env::var(variable) {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => true,
+    }
+}
+
+/// First file descriptor passed by socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take the TCP listening sockets passed by socket activation. The variables describing them are removed, so the
+/// sockets are only taken once and are not passed on to child processes. As it changes the environment, call it before
+/// starting other threads, such as those of a multi-threaded tokio runtime.
+///
+/// Descriptors that are not listening TCP sockets, such as datagram or Unix sockets, are skipped with a warning.
+#[cfg(unix)]
+pub fn take_listeners() -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let count = match env::var("LISTEN_FDS") {
+        Ok(count) if env::var("LISTEN_PID").is_ok() && for_this_process("LISTEN_PID") => count.parse::<i32>().unwrap_or(0),
+        _ => 0,
+    };
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        match is_stream_listener(fd) {
+            Ok(true) => {},
+            Ok(false) => {
+                warn!(fd, "Ignoring socket from the service manager that is not a listening stream socket");
+                continue;
+            },
+            Err(e) => {
+                warn!(fd, error = %e, "Ignoring descriptor from the service manager that is not a socket");
+                continue;
+            },
+        }
+        // SAFETY: systemd passes ownership of the descriptors from 3 to 3 + LISTEN_FDS, and the variables were removed
+        // above so nothing else takes them.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(addr) => {
+                debug!(fd, %addr, "Received listening socket from the service manager");
+                listeners.push(listener);
+            },
+            Err(e) => {
+                warn!(fd, error = %e, "Ignoring socket from the service manager that is not a TCP listener");
+            }
+        }
+    }
+    listeners
+}
+
+/// Whether `fd` is a stream socket accepting connections.
+#[cfg(unix)]
+fn is_stream_listener(fd: i32) -> io::Result<bool> {
+    Ok(socket_option(fd, libc::SO_TYPE)? == libc::SOCK_STREAM && socket_option(fd, libc::SO_ACCEPTCONN)? != 0)
+}
+
+/// Read an integer `SOL_SOCKET` option of `fd`.
+#[cfg(unix)]
+fn socket_option(fd: i32, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `length` describe a buffer large enough for an integer option.
+    let result = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, option, &mut value as *mut libc::c_int as *mut libc::c_void, &mut length) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(not(unix))]
+pub fn take_listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::net::{TcpStream, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn only_listening_stream_sockets_are_listeners() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(is_stream_listener(listener.as_raw_fd()).unwrap());
+
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(!is_stream_listener(stream.as_raw_fd()).unwrap());
+
+        let datagram = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(!is_stream_listener(datagram.as_raw_fd()).unwrap());
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(is_stream_listener(file.as_raw_fd()).is_err());
+    }
+
+}
@@ -0,0 +1,155 @@
+//! This module contains the Unix domain socket support shared by the inputs and the output server: listening on a
+//! socket path with the requested permissions, and removing the socket file once it is closed.
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::task::{Context, Poll};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, warn};
+
+/// Permissions and ownership given to a socket file, or PTY device, when it is created. Unset fields are left to the
+/// process umask, user and group.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketPermissions {
+    /// File mode, e.g. `0o660`.
+    pub mode: Option<u32>,
+    /// Owning user, as a name or a numeric id.
+    pub owner: Option<String>,
+    /// Owning group, as a name or a numeric id.
+    pub group: Option<String>,
+}
+
+/// A listening Unix stream socket. The socket file is removed when the listener is dropped, unless another listener
+/// replaced it in the meantime.
+#[derive(Debug)]
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+    inode: u64,
+}
+
+impl UnixSocketListener {
+    /// Listen on `path` and apply `permissions` to the socket file.
+    ///
+    /// A socket file left behind by a previous run is replaced. This will return an error if the path exists and is
+    /// not a socket, or if another process is still listening on it.
+    pub async fn bind(path: impl AsRef<Path>, permissions: &SocketPermissions) -> io::Result<UnixSocketListener> {
+        let path = path.as_ref();
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the path exists and is not a socket"));
+            }
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another process is listening on this socket"));
+            }
+            debug!(path = %path.display(), "Removing stale socket file");
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        let inode = fs::symlink_metadata(path)?.ino();
+        // Built before applying the permissions, so the socket file is removed if that fails.
+        let listener = UnixSocketListener { listener, path: path.to_path_buf(), inode };
+        apply_permissions(path, permissions)?;
+        Ok(listener)
+    }
+
+    /// The path of the socket file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Wait for the next connection.
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        Ok(self.listener.accept().await?.0)
+    }
+
+    /// Poll for the next connection, see `tokio::net::UnixListener::poll_accept`.
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<UnixStream>> {
+        self.listener.poll_accept(cx).map_ok(|(stream, _)| stream)
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        match fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.ino() == self.inode => {
+                if let Err(e) = fs::remove_file(&self.path) {
+                    warn!(path = %self.path.display(), error = %e, "Unable to remove socket file");
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Apply `permissions` to a file created by the tool, a socket or a PTY device.
+pub(crate) fn apply_permissions(path: &Path, permissions: &SocketPermissions) -> io::Result<()> {
+    if let Some(mode) = permissions.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    let uid = permissions.owner.as_deref().map(user_id).transpose()?;
+    let gid = permissions.group.as_deref().map(group_id).transpose()?;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// Resolve a user to its numeric id, through the system user database so users from NSS, such as LDAP, are found.
+fn user_id(name: &str) -> io::Result<u32> {
+    lookup_id("user", name, |name, buf| {
+        // SAFETY: `passwd` is plain data, filled by `getpwnam_r` with pointers into `buf`, which outlives it.
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let code = unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+        (code, (!result.is_null()).then_some(entry.pw_uid))
+    })
+}
+
+/// Resolve a group to its numeric id, through the system group database.
+fn group_id(name: &str) -> io::Result<u32> {
+    lookup_id("group", name, |name, buf| {
+        // SAFETY: as for `user_id`, with `getgrnam_r`.
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let code = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+        (code, (!result.is_null()).then_some(entry.gr_gid))
+    })
+}
+
+/// Resolve a name with a reentrant `get*nam_r` function, returning its error code and the id found, growing the buffer
+/// until the entry fits. Numeric ids are taken as they are.
+fn lookup_id(kind: &str, name: &str, get: impl Fn(&CStr, &mut [libc::c_char]) -> (libc::c_int, Option<u32>)) -> io::Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let c_name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid {} name: {}", kind, name)))?;
+    let mut buf = vec![0; 1024];
+    loop {
+        match get(&c_name, &mut buf) {
+            (0, Some(id)) => return Ok(id),
+            // Some systems report a missing entry as an error.
+            (0 | libc::ENOENT | libc::ESRCH, None) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown {}: {}", kind, name))),
+            (libc::ERANGE, _) if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            (code, _) => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_resolved_through_the_system_databases() {
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert_eq!(user_id("1234").unwrap(), 1234);
+        assert_eq!(group_id("0").unwrap(), 0);
+        assert_eq!(user_id("no-such-user-here").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(group_id("no-such-group-here").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(group_id("bad\0name").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
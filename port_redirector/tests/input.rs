@@ -0,0 +1,142 @@
+use bytes::{Bytes, BytesMut};
+use port_redirector::error::Result;
+use port_redirector::input_stream::{run_input, Framing, Input, InputControl, NoSubscriberPolicy};
+use port_redirector::stats::InputStats;
+use std::io;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+/// An input fed and read back through channels.
+struct ChannelInput {
+    reads: mpsc::Receiver<Vec<u8>>,
+    writes: mpsc::UnboundedSender<Vec<u8>>,
+    stats: InputStats,
+}
+
+impl Input for ChannelInput {
+    async fn connect(self) -> Result<ChannelInput> {
+        Ok(self)
+    }
+
+    async fn read(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+        match self.reads.recv().await {
+            Some(data) => {
+                buf.extend_from_slice(&data);
+                Ok(data.len())
+            },
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = self.writes.send(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn describe(&self) -> String {
+        "channel".to_string()
+    }
+
+    fn stats(&self) -> InputStats {
+        self.stats.clone()
+    }
+}
+
+#[tokio::test]
+async fn block_policy_keeps_serving_write_back() {
+    let (feed, reads) = mpsc::channel(16);
+    let (writes, mut written) = mpsc::unbounded_channel();
+    let mut input = ChannelInput { reads, writes, stats: InputStats::new() };
+    let stats = input.stats();
+    let (input_tx, input_rx) = broadcast::channel(16);
+    drop(input_rx);
+    let (tx_to_input, rx_to_input) = mpsc::channel(16);
+    let control = InputControl::new();
+    control.set_no_subscriber_policy(NoSubscriberPolicy::Block);
+    let shutdown = CancellationToken::new();
+    let task = {
+        let input_tx = input_tx.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { run_input(&mut input, input_tx, rx_to_input, control, shutdown).await })
+    };
+
+    feed.send(b"first".to_vec()).await.unwrap();
+    feed.send(b"second".to_vec()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The input is blocked without a subscriber, but still writes back.
+    tx_to_input.send(Bytes::from_static(b"command")).await.unwrap();
+    let echoed = timeout(Duration::from_secs(1), written.recv()).await.unwrap().unwrap();
+    assert_eq!(echoed, b"command");
+    assert_eq!(stats.snapshot().backpressure_events, 1);
+
+    let mut rx = input_tx.subscribe();
+    let first = timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap();
+    let second = timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap();
+    assert_eq!((&first[..], &second[..]), (&b"first"[..], &b"second"[..]));
+
+    shutdown.cancel();
+    drop(tx_to_input);
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn rtcm_crc_failures_are_counted_once_per_resync() {
+    const EMPTY_FRAME: [u8; 6] = [0xD3, 0x00, 0x00, 0x47, 0xEA, 0x4B];
+    let (feed, reads) = mpsc::channel(16);
+    let (writes, _written) = mpsc::unbounded_channel();
+    let mut input = ChannelInput { reads, writes, stats: InputStats::new() };
+    let stats = input.stats();
+    let (input_tx, mut rx) = broadcast::channel(16);
+    let (_tx_to_input, rx_to_input) = mpsc::channel(16);
+    let control = InputControl::new();
+    control.set_framing(Framing::Rtcm3);
+    let shutdown = CancellationToken::new();
+    let task = {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { run_input(&mut input, input_tx, rx_to_input, control, shutdown).await })
+    };
+
+    // Three stray preambles, each heading a 7 byte frame whose CRC does not match, then a valid frame.
+    let mut data = [0xD3, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00].repeat(3);
+    data.extend_from_slice(&EMPTY_FRAME);
+    feed.send(data.clone()).await.unwrap();
+    assert_eq!(&timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap()[..], &EMPTY_FRAME);
+    assert_eq!(stats.snapshot().crc_failures, 1);
+
+    feed.send(data).await.unwrap();
+    assert_eq!(&timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap()[..], &EMPTY_FRAME);
+    assert_eq!(stats.snapshot().crc_failures, 2);
+
+    shutdown.cancel();
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn tcp_server_input_stream_waits_for_a_working_client() {
+    use port_redirector::input_stream::InputSocket;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut input = InputSocket::connect(InputSocket::TcpServer { port, server: None, stream: None, stats: Default::default() }).await.unwrap();
+    assert_eq!(AsyncWriteExt::write_all(&mut input, b"nobody").await.unwrap_err().kind(), io::ErrorKind::NotConnected);
+
+    // The first client resets its connection, the read carries on with the next one.
+    let reset = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    // A zero linger does not block on drop.
+    #[allow(deprecated)]
+    reset.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(reset);
+    let reader = tokio::spawn(async move {
+        let mut buf = [0; 16];
+        let n = AsyncReadExt::read(&mut input, &mut buf).await.unwrap();
+        buf[..n].to_vec()
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    client.write_all(b"hello").await.unwrap();
+    assert_eq!(timeout(Duration::from_secs(2), reader).await.unwrap().unwrap(), b"hello");
+}
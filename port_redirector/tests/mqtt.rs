@@ -0,0 +1,101 @@
+use bytes::Bytes;
+use port_redirector::mqtt::{MqttOutput, MqttSettings, QoS};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+/// Read a packet on the broker side, returning its fixed header byte and its body.
+async fn read_packet(socket: &mut TcpStream) -> (u8, Vec<u8>) {
+    let header = socket.read_u8().await.unwrap();
+    let mut length = 0;
+    for shift in (0..4).map(|index| index * 7) {
+        let byte = socket.read_u8().await.unwrap();
+        length |= usize::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; length];
+    socket.read_exact(&mut body).await.unwrap();
+    (header, body)
+}
+
+/// A length prefixed string, as in the packets.
+fn string(value: &str) -> Vec<u8> {
+    let mut encoded = (value.len() as u16).to_be_bytes().to_vec();
+    encoded.extend_from_slice(value.as_bytes());
+    encoded
+}
+
+#[tokio::test]
+async fn mqtt_output_publishes_to_a_broker() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let broker = format!("gps:secret@127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let settings = MqttSettings {
+        client_id: "test".to_string(),
+        qos: QoS::AtLeastOnce,
+        command_topic: Some("vessel/cmd".to_string()),
+        ..MqttSettings::from_broker(&broker, "vessel/gps").unwrap()
+    };
+    let (input_tx, _) = broadcast::channel(16);
+    let (tx_to_input, mut rx_to_input) = mpsc::channel(16);
+    let mut output = MqttOutput::new(settings, tx_to_input, input_tx.clone());
+    let stats = output.stats();
+    let shutdown = CancellationToken::new();
+    let task = {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { output.run_loop(shutdown).await })
+    };
+
+    let (mut socket, _) = timeout(Duration::from_secs(2), listener.accept()).await.unwrap().unwrap();
+    let (header, body) = read_packet(&mut socket).await;
+    assert_eq!(header, 0x10);
+    let mut expected = string("MQTT");
+    // Protocol level 4, user name and password, a persistent session and a keep alive of 30 s.
+    expected.extend_from_slice(&[4, 0xC0, 0, 30]);
+    for value in ["test", "gps", "secret"] {
+        expected.extend_from_slice(&string(value));
+    }
+    assert_eq!(body, expected);
+    socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+    // The command topic subscription, made once connected.
+    let (header, body) = read_packet(&mut socket).await;
+    assert_eq!(header, 0x82);
+    assert_eq!(&body[2..], [string("vessel/cmd"), vec![1]].concat());
+    socket.write_all(&[&[0x90, 0x03][..], &body[..2], &[0x01]].concat()).await.unwrap();
+
+    // A chunk of input data, published at QoS 1 and acknowledged.
+    input_tx.send(Bytes::from_static(b"$GPGGA\r\n")).unwrap();
+    let (header, body) = read_packet(&mut socket).await;
+    assert_eq!(header, 0x32);
+    let topic = string("vessel/gps");
+    assert_eq!(&body[..topic.len()], &topic[..]);
+    assert_eq!(&body[topic.len() + 2..], b"$GPGGA\r\n");
+    socket.write_all(&[&[0x40, 0x02][..], &body[topic.len()..topic.len() + 2]].concat()).await.unwrap();
+
+    // A command, written to the input and acknowledged.
+    let command = [string("vessel/cmd"), vec![0x00, 0x09], b"reset".to_vec()].concat();
+    socket.write_all(&[&[0x32, command.len() as u8][..], &command].concat()).await.unwrap();
+    let data = timeout(Duration::from_secs(2), rx_to_input.recv()).await.unwrap().unwrap();
+    assert_eq!(&data[..], b"reset");
+    assert_eq!(read_packet(&mut socket).await, (0x40, vec![0x00, 0x09]));
+
+    // On shutdown, the last message is acknowledged before disconnecting.
+    input_tx.send(Bytes::from_static(b"$GPRMC\r\n")).unwrap();
+    shutdown.cancel();
+    let (header, body) = read_packet(&mut socket).await;
+    assert_eq!(header, 0x32);
+    assert_eq!(&body[topic.len() + 2..], b"$GPRMC\r\n");
+    assert!(timeout(Duration::from_millis(200), socket.read_u8()).await.is_err(), "disconnected before the PUBACK");
+    socket.write_all(&[&[0x40, 0x02][..], &body[topic.len()..topic.len() + 2]].concat()).await.unwrap();
+    assert_eq!(read_packet(&mut socket).await, (0xE0, Vec::new()));
+
+    timeout(Duration::from_secs(2), task).await.unwrap().unwrap().unwrap();
+    let snapshot = stats.snapshot();
+    assert_eq!((snapshot.messages_out, snapshot.bytes_out), (2, 16));
+    assert_eq!((snapshot.total_clients, snapshot.connected_clients), (1, 0));
+}
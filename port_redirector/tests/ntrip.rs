@@ -0,0 +1,58 @@
+use port_redirector::input_stream::{InputControl, InputSocket};
+use port_redirector::ntrip::NtripSettings;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+/// Read from the caster side until `suffix` was received.
+async fn read_until(socket: &mut tokio::net::TcpStream, suffix: &[u8]) -> Vec<u8> {
+    let mut received = Vec::new();
+    while !received.ends_with(suffix) {
+        received.push(socket.read_u8().await.unwrap());
+    }
+    received
+}
+
+#[tokio::test]
+async fn ntrip_input_streams_corrections_from_a_caster() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("rover:secret@127.0.0.1:{}/BASE1", listener.local_addr().unwrap().port());
+    let (received_tx, received_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let request = read_until(&mut socket, b"\r\n\r\n").await;
+        socket.write_all(b"HTTP/1.1 200 OK\r\nNtrip-Version: Ntrip/2.0\r\nTransfer-Encoding: chunked\r\n\r\n").await.unwrap();
+        socket.write_all(b"6\r\n\xD3\x00\x00\x47\xEA\x4B\r\n").await.unwrap();
+        // The rover position is uploaded once the stream is read.
+        let gga = read_until(&mut socket, b"\r\n").await;
+        let _ = received_tx.send((String::from_utf8(request).unwrap(), String::from_utf8(gga).unwrap()));
+        let _ = socket.read_u8().await;
+    });
+
+    let settings = NtripSettings {
+        gga: Some("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47".to_string()),
+        ..NtripSettings::from_endpoint(&endpoint).unwrap()
+    };
+    let mut input = InputSocket::connect(InputSocket::Ntrip { settings, stream: None, stats: Default::default() }).await.unwrap();
+    let (input_tx, mut rx) = broadcast::channel(16);
+    let (tx_to_input, rx_to_input) = mpsc::channel(16);
+    let shutdown = CancellationToken::new();
+    let task = {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { input.run_loop(input_tx, rx_to_input, InputControl::new(), shutdown).await })
+    };
+
+    let data = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(&data[..], b"\xD3\x00\x00\x47\xEA\x4B");
+
+    let (request, gga) = timeout(Duration::from_secs(2), received_rx).await.unwrap().unwrap();
+    shutdown.cancel();
+    drop(tx_to_input);
+    timeout(Duration::from_secs(2), task).await.unwrap().unwrap().unwrap();
+    assert!(request.starts_with("GET /BASE1 HTTP/1.1\r\n"), "{}", request);
+    assert!(request.contains("Authorization: Basic cm92ZXI6c2VjcmV0\r\n"), "{}", request);
+    assert_eq!(gga, "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n");
+}
@@ -0,0 +1,52 @@
+#![cfg(unix)]
+
+use bytes::Bytes;
+use port_redirector::input_stream::{InputControl, InputSocket};
+use std::io::{Read, Write};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn pty_input_carries_data_both_ways() {
+    let link = std::env::temp_dir().join(format!("port_redirector-pty-{}", std::process::id()));
+    let link = link.to_str().unwrap().to_string();
+    let mut input = InputSocket::connect(InputSocket::Pty { link: link.clone(), permissions: Default::default(), device: None, stats: Default::default() })
+        .await
+        .unwrap();
+    let stats = input.stats();
+
+    let (input_tx, mut rx) = broadcast::channel(16);
+    let (tx_to_input, rx_to_input) = mpsc::channel(16);
+    let shutdown = CancellationToken::new();
+    let task = {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { input.run_loop(input_tx, rx_to_input, InputControl::new(), shutdown).await })
+    };
+
+    // The program under test opens the published path like any serial device.
+    let mut slave = std::fs::OpenOptions::new().read(true).write(true).open(&link).unwrap();
+    let sentence = b"$GPGGA,123519,4807.038,N\r\n";
+    slave.write_all(sentence).unwrap();
+    let mut received = Vec::new();
+    while received.len() < sentence.len() {
+        let chunk = timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+        received.extend_from_slice(&chunk);
+    }
+    assert_eq!(received, sentence);
+    assert_eq!(stats.snapshot().bytes_in, sentence.len() as u64);
+
+    tx_to_input.send(Bytes::from_static(b"PING\r\n")).await.unwrap();
+    let reply = tokio::task::spawn_blocking(move || {
+        let mut reply = [0; 6];
+        slave.read_exact(&mut reply).unwrap();
+        reply
+    });
+    assert_eq!(&timeout(Duration::from_secs(2), reply).await.unwrap().unwrap(), b"PING\r\n");
+
+    shutdown.cancel();
+    drop(tx_to_input);
+    timeout(Duration::from_secs(2), task).await.unwrap().unwrap().unwrap();
+    assert!(std::fs::symlink_metadata(&link).is_err(), "the symlink is removed on shutdown");
+}
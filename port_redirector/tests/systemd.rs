@@ -0,0 +1,34 @@
+#![cfg(unix)]
+
+use port_redirector::systemd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+// The environment is process wide, so everything touching it runs in this single test.
+#[test]
+fn notifications_reach_the_notify_socket() {
+    let path = std::env::temp_dir().join(format!("port_redirector-notify-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    std::env::remove_var("NOTIFY_SOCKET");
+    assert!(!systemd::notify("READY=1").unwrap());
+
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    let mut buf = [0; 256];
+    for state in ["READY=1\nSTATUS=1 route running", "RELOADING=1", "WATCHDOG=1", "STOPPING=1"] {
+        assert!(systemd::notify(state).unwrap());
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(std::str::from_utf8(&buf[..n]).unwrap(), state);
+    }
+
+    std::env::set_var("WATCHDOG_USEC", "2000000");
+    std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+    assert_eq!(systemd::watchdog_interval(), Some(Duration::from_secs(1)));
+    std::env::set_var("WATCHDOG_PID", "1");
+    assert_eq!(systemd::watchdog_interval(), None);
+
+    std::env::remove_var("NOTIFY_SOCKET");
+    std::fs::remove_file(&path).unwrap();
+}